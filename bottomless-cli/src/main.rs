@@ -1,4 +1,5 @@
 use anyhow::Result;
+use bottomless::replicator::Resolution;
 use clap::{Parser, Subcommand};
 
 mod replicator_extras;
@@ -69,9 +70,38 @@ enum Commands {
             long_help = "Remove generations older than given date"
         )]
         older_than: Option<chrono::NaiveDate>,
+        #[clap(
+            long,
+            requires = "older_than",
+            long_help = "Do not remove labeled generations"
+        )]
+        keep_labeled: bool,
         #[clap(long, short)]
         verbose: bool,
     },
+    #[clap(about = "Label given generation, or remove its label")]
+    Label {
+        #[clap(long, short)]
+        generation: uuid::Uuid,
+        #[clap(long, short, required_unless_present = "remove")]
+        label: Option<String>,
+        #[clap(long, conflicts_with = "label", long_help = "Remove the label")]
+        remove: bool,
+    },
+    #[clap(about = "Compare the local database with its backup, and optionally reconcile them")]
+    Reconcile {
+        #[clap(
+            long,
+            conflicts_with = "keep_remote",
+            long_help = "Snapshot the local database into a new generation"
+        )]
+        keep_local: bool,
+        #[clap(
+            long,
+            long_help = "Restore the backup over the local database, moving the local files aside"
+        )]
+        keep_remote: bool,
+    },
 }
 
 async fn run() -> Result<()> {
@@ -128,15 +158,39 @@ async fn run() -> Result<()> {
         Commands::Rm {
             generation,
             older_than,
+            keep_labeled,
             verbose,
         } => match (generation, older_than) {
-            (None, Some(older_than)) => client.remove_many(older_than, verbose).await?,
+            (None, Some(older_than)) => {
+                client
+                    .remove_many(older_than, keep_labeled, verbose)
+                    .await?
+            }
             (Some(generation), None) => client.remove(generation, verbose).await?,
             (Some(_), Some(_)) => unreachable!(),
             (None, None) => println!(
                 "rm command cannot be run without parameters; see -h or --help for details"
             ),
         },
+        Commands::Label {
+            generation,
+            label,
+            remove,
+        } => match label {
+            Some(label) if !remove => client.label_generation(&generation, &label).await?,
+            _ => client.remove_generation_label(&generation).await?,
+        },
+        Commands::Reconcile {
+            keep_local,
+            keep_remote,
+        } => {
+            let resolution = match (keep_local, keep_remote) {
+                (true, _) => Some(Resolution::KeepLocal),
+                (_, true) => Some(Resolution::KeepRemote),
+                _ => None,
+            };
+            client.reconcile(resolution).await?
+        }
     };
     Ok(())
 }
@@ -1,4 +1,5 @@
 use anyhow::Result;
+use bottomless::replicator::{Resolution, SyncStatus};
 
 pub(crate) struct Replicator {
     inner: bottomless::replicator::Replicator,
@@ -78,9 +79,7 @@ impl Replicator {
         let mut limit = limit.unwrap_or(u64::MAX);
         loop {
             let mut list_request = self
-                .client
                 .list_objects()
-                .bucket(&self.bucket)
                 .set_delimiter(Some("/".to_string()))
                 .prefix(&self.db_name);
 
@@ -112,7 +111,10 @@ impl Replicator {
                     if datetime.date() > older_than.unwrap_or(chrono::NaiveDate::MAX) {
                         continue;
                     }
-                    println!("{uuid}");
+                    match self.get_generation_label(&uuid).await? {
+                        Some(label) => println!("{uuid} [{label}]"),
+                        None => println!("{uuid}"),
+                    }
                     if verbose {
                         let counter = self.get_remote_change_counter(&uuid).await?;
                         let (consistent_frame, checksum) =
@@ -143,9 +145,7 @@ impl Replicator {
         let mut next_marker = None;
         loop {
             let mut list_request = self
-                .client
                 .list_objects()
-                .bucket(&self.bucket)
                 .prefix(format!("{}-{}/", &self.db_name, generation));
 
             if let Some(marker) = next_marker {
@@ -191,15 +191,14 @@ impl Replicator {
     pub(crate) async fn remove_many(
         &self,
         older_than: chrono::NaiveDate,
+        keep_labeled: bool,
         verbose: bool,
     ) -> Result<()> {
         let mut next_marker = None;
         let mut removed_count = 0;
         loop {
             let mut list_request = self
-                .client
                 .list_objects()
-                .bucket(&self.bucket)
                 .set_delimiter(Some("/".to_string()))
                 .prefix(&self.db_name);
 
@@ -226,6 +225,14 @@ impl Replicator {
                     if datetime.date() >= older_than {
                         continue;
                     }
+                    if keep_labeled {
+                        if let Some(label) = self.get_generation_label(&uuid).await? {
+                            if verbose {
+                                println!("Keeping {uuid}, labeled as {label}");
+                            }
+                            continue;
+                        }
+                    }
                     if verbose {
                         println!("Removing {uuid}");
                     }
@@ -246,9 +253,7 @@ impl Replicator {
     }
 
     pub(crate) async fn list_generation(&self, generation: uuid::Uuid) -> Result<()> {
-        self.client
-            .list_objects()
-            .bucket(&self.bucket)
+        self.list_objects()
             .prefix(format!("{}-{}/", &self.db_name, generation))
             .max_keys(1)
             .send()
@@ -262,6 +267,9 @@ impl Replicator {
         let (consistent_frame, checksum) = self.get_last_consistent_frame(&generation).await?;
         println!("Generation {} for {}", generation, self.db_name);
         println!("\tcreated at:           {}", uuid_to_datetime(&generation));
+        if let Some(label) = self.get_generation_label(&generation).await? {
+            println!("\tlabel:                {label}");
+        }
         println!("\tchange counter:       {counter:?}");
         println!("\tconsistent WAL frame: {consistent_frame}");
         println!("\tWAL frame checksum:   {checksum:x}");
@@ -269,11 +277,39 @@ impl Replicator {
         Ok(())
     }
 
+    pub(crate) async fn reconcile(&mut self, resolution: Option<Resolution>) -> Result<()> {
+        let report = self.inner.reconcile(resolution).await?;
+        match report.generation {
+            Some(generation) => println!(
+                "Database {} against generation {}",
+                self.db_name, generation
+            ),
+            None => println!("Database {}, no generation found", self.db_name),
+        }
+        println!("\tstatus:                {:?}", report.status);
+        println!("\tlocal change counter:  {:?}", report.local_change_counter);
+        println!(
+            "\tremote change counter: {:?}",
+            report.remote_change_counter
+        );
+        println!("\tlocal WAL frames:      {}", report.local_wal_frames);
+        println!("\tconsistent WAL frame:  {}", report.last_consistent_frame);
+        match resolution {
+            _ if report.status == SyncStatus::InSync => (),
+            Some(Resolution::KeepLocal) => {
+                println!("Snapshotted the local database into a new generation")
+            }
+            Some(Resolution::KeepRemote) => println!(
+                "Restored the database, previous local files were moved to *.bottomless.backup"
+            ),
+            None => println!("Pass --keep-local or --keep-remote to resolve the difference"),
+        }
+        Ok(())
+    }
+
     pub(crate) async fn detect_db(&self) -> Option<String> {
         let response = match self
-            .client
             .list_objects()
-            .bucket(&self.bucket)
             .set_delimiter(Some("/".to_string()))
             .prefix(&self.db_name)
             .send()
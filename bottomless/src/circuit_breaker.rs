@@ -0,0 +1,124 @@
+use std::time::{Duration, Instant};
+
+// Configuration of the circuit breaker around S3 calls
+#[derive(Clone, Debug)]
+pub struct CircuitBreakerOptions {
+    // Number of consecutive failed S3 uploads after which uploads fail fast for `cooldown`
+    pub failure_threshold: u32,
+    pub cooldown: Duration,
+}
+
+impl CircuitBreakerOptions {
+    const DEFAULT_COOLDOWN: Duration = Duration::from_secs(30);
+
+    // Configured with `LIBSQL_BOTTOMLESS_CIRCUIT_BREAKER_THRESHOLD` and
+    // `LIBSQL_BOTTOMLESS_CIRCUIT_BREAKER_COOLDOWN_SECS`. The circuit breaker is disabled,
+    // and None returned, unless the threshold is set.
+    pub fn from_env() -> Option<Self> {
+        Some(Self {
+            failure_threshold: crate::env_value("LIBSQL_BOTTOMLESS_CIRCUIT_BREAKER_THRESHOLD")?,
+            cooldown: crate::env_value("LIBSQL_BOTTOMLESS_CIRCUIT_BREAKER_COOLDOWN_SECS")
+                .map_or(Self::DEFAULT_COOLDOWN, Duration::from_secs),
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CircuitBreakerState {
+    // S3 calls go through
+    Closed,
+    // S3 calls fail fast until the cooldown passes
+    Open,
+    // Cooldown passed, the next call probes the bucket before letting traffic through
+    HalfOpen,
+}
+
+// Stops hammering a failing S3 endpoint: after `failure_threshold` consecutive failures
+// it opens for `cooldown`, and then lets a single probe decide whether to close again.
+#[derive(Debug)]
+pub(crate) struct CircuitBreaker {
+    failure_threshold: u32,
+    cooldown: Duration,
+    consecutive_failures: u32,
+    open_until: Option<Instant>,
+}
+
+impl CircuitBreaker {
+    pub(crate) fn new(failure_threshold: u32, cooldown: Duration) -> Self {
+        Self {
+            failure_threshold,
+            cooldown,
+            consecutive_failures: 0,
+            open_until: None,
+        }
+    }
+
+    pub(crate) fn state(&self, now: Instant) -> CircuitBreakerState {
+        match self.open_until {
+            None => CircuitBreakerState::Closed,
+            Some(open_until) if now < open_until => CircuitBreakerState::Open,
+            Some(_) => CircuitBreakerState::HalfOpen,
+        }
+    }
+
+    pub(crate) fn on_success(&mut self) {
+        if self.open_until.is_some() {
+            tracing::info!("S3 endpoint recovered, closing the circuit breaker");
+        }
+        self.consecutive_failures = 0;
+        self.open_until = None;
+    }
+
+    pub(crate) fn on_failure(&mut self, now: Instant) {
+        self.consecutive_failures = self.consecutive_failures.saturating_add(1);
+        if self.consecutive_failures >= self.failure_threshold {
+            if self.open_until.is_none() {
+                tracing::warn!(
+                    "{} consecutive S3 failures, opening the circuit breaker for {:?}",
+                    self.consecutive_failures,
+                    self.cooldown
+                );
+            }
+            self.open_until = Some(now + self.cooldown);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn circuit_breaker_opens_and_closes() {
+        let now = Instant::now();
+        let mut breaker = CircuitBreaker::new(3, Duration::from_secs(10));
+        assert_eq!(breaker.state(now), CircuitBreakerState::Closed);
+
+        breaker.on_failure(now);
+        breaker.on_failure(now);
+        assert_eq!(breaker.state(now), CircuitBreakerState::Closed);
+        breaker.on_failure(now);
+        assert_eq!(breaker.state(now), CircuitBreakerState::Open);
+        assert_eq!(
+            breaker.state(now + Duration::from_secs(5)),
+            CircuitBreakerState::Open
+        );
+
+        // A failed probe after the cooldown keeps it open for another cooldown
+        let probe = now + Duration::from_secs(10);
+        assert_eq!(breaker.state(probe), CircuitBreakerState::HalfOpen);
+        breaker.on_failure(probe);
+        assert_eq!(
+            breaker.state(probe + Duration::from_secs(5)),
+            CircuitBreakerState::Open
+        );
+
+        // A successful probe closes it and resets the failure count
+        let probe = probe + Duration::from_secs(10);
+        assert_eq!(breaker.state(probe), CircuitBreakerState::HalfOpen);
+        breaker.on_success();
+        assert_eq!(breaker.state(probe), CircuitBreakerState::Closed);
+        breaker.on_failure(probe);
+        assert_eq!(breaker.state(probe), CircuitBreakerState::Closed);
+    }
+}
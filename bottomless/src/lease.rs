@@ -0,0 +1,129 @@
+use crate::replicator::Result;
+use aws_sdk_s3::types::{ByteStream, SdkError};
+use aws_sdk_s3::Client;
+use std::time::Duration;
+
+// Configuration of the lease taken on a database, against replicating it from two instances
+// at the same time
+#[derive(Clone, Debug)]
+pub struct LeaseOptions {
+    // Time after which a lease on the database, not refreshed by its owner, is considered
+    // abandoned
+    pub ttl: Duration,
+    // Identifier of this instance. Defaults to the host and database path once the database
+    // is registered, so that it survives restarts.
+    pub owner: Option<String>,
+}
+
+impl LeaseOptions {
+    // Configured with `LIBSQL_BOTTOMLESS_LEASE_TTL_SECS` and `LIBSQL_BOTTOMLESS_LEASE_OWNER`.
+    // Leases are disabled, and None returned, unless the time to live is set.
+    pub fn from_env() -> Option<Self> {
+        Some(Self {
+            ttl: crate::env_value("LIBSQL_BOTTOMLESS_LEASE_TTL_SECS").map(Duration::from_secs)?,
+            owner: std::env::var("LIBSQL_BOTTOMLESS_LEASE_OWNER").ok(),
+        })
+    }
+}
+
+fn now_millis() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+// Lease contents: [owner] [expiration time in milliseconds since the unix epoch]
+fn parse_lease(data: &[u8]) -> Option<(String, u64)> {
+    let data = std::str::from_utf8(data).ok()?;
+    let (owner, expires_at) = data.trim().rsplit_once(' ')?;
+    Some((owner.to_string(), expires_at.parse().ok()?))
+}
+
+// Returns the owner and expiration time of the lease stored under given key, if any
+pub(crate) async fn read_lease(
+    client: &Client,
+    bucket: &str,
+    key: String,
+) -> Result<Option<(String, u64)>> {
+    match client.get_object().bucket(bucket).key(key).send().await {
+        Ok(response) => {
+            let data = response.body.collect().await?.into_bytes();
+            Ok(parse_lease(&data))
+        }
+        Err(SdkError::ServiceError(err)) if err.err().is_no_such_key() => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+// A lease on a database taken by this instance, which can be written and kept alive
+// independently of the replicator
+pub(crate) struct Lease {
+    pub client: Client,
+    pub bucket: String,
+    pub key: String,
+    pub owner: String,
+    pub ttl: Duration,
+}
+
+impl Lease {
+    // Writes the lease, unless another owner holds one that hasn't expired yet
+    pub async fn write(&self) -> Result<()> {
+        let now = now_millis();
+        if let Some((owner, expires_at)) =
+            read_lease(&self.client, &self.bucket, self.key.clone()).await?
+        {
+            if owner != self.owner && expires_at > now {
+                return Err(anyhow::anyhow!(
+                    "Database is already replicated by {}, its lease {} expires in {:?}",
+                    owner,
+                    self.key,
+                    Duration::from_millis(expires_at - now)
+                ));
+            }
+        }
+        let lease = format!("{} {}", self.owner, now + self.ttl.as_millis() as u64);
+        self.client
+            .put_object()
+            .bucket(&self.bucket)
+            .key(&self.key)
+            .body(ByteStream::from(lease.into_bytes()))
+            .send()
+            .await?;
+        Ok(())
+    }
+
+    // Extends the lease every third of its time to live, so that a failed attempt is retried
+    // while the lease is still valid
+    pub async fn refresh(self) {
+        let mut interval = tokio::time::interval(self.ttl / 3);
+        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        // the first tick completes immediately, right after the lease was written
+        interval.tick().await;
+        loop {
+            interval.tick().await;
+            match self.write().await {
+                Ok(()) => tracing::trace!("Lease {} extended", self.key),
+                Err(e) => tracing::error!("Failed to extend lease {}: {}", self.key, e),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    #[test]
+    fn parse_lease() {
+        assert_eq!(
+            super::parse_lease(b"pid-42 1700000000000"),
+            Some(("pid-42".to_string(), 1700000000000))
+        );
+        assert_eq!(
+            super::parse_lease(b"host a/pid-42 1700000000000\n"),
+            Some(("host a/pid-42".to_string(), 1700000000000))
+        );
+        assert_eq!(super::parse_lease(b"pid-42"), None);
+        assert_eq!(super::parse_lease(b"pid-42 soon"), None);
+        assert_eq!(super::parse_lease(&[0xff, 0x20, 0x31]), None);
+    }
+}
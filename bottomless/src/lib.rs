@@ -2,7 +2,14 @@
 #![allow(clippy::not_unsafe_ptr_arg_deref)]
 #![allow(improper_ctypes)]
 
+mod circuit_breaker;
 mod ffi;
+mod lease;
+mod manifest;
+#[cfg(test)]
+mod mock_s3;
+mod rate_limiter;
+mod restore_validation;
 
 pub mod replicator;
 
@@ -20,6 +27,9 @@ fn is_regular(vfs: *const sqlite3_vfs) -> bool {
     vfs.starts_with("unix") || vfs.starts_with("win32")
 }
 
+// How long closing the WAL waits for committed frames to reach S3
+const DRAIN_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(30);
+
 macro_rules! block_on {
     ($runtime:expr, $e:expr) => {
         $runtime.block_on(async { $e.await })
@@ -27,12 +37,28 @@ macro_rules! block_on {
 }
 
 fn is_local() -> bool {
-    std::env::var("LIBSQL_BOTTOMLESS_LOCAL").map_or(false, |local| {
-        local.eq_ignore_ascii_case("true")
-            || local.eq_ignore_ascii_case("t")
-            || local.eq_ignore_ascii_case("yes")
-            || local.eq_ignore_ascii_case("y")
-            || local == "1"
+    env_flag("LIBSQL_BOTTOMLESS_LOCAL")
+}
+
+// Parses an environment variable, ignoring it with a warning if it's not valid
+fn env_value<T: std::str::FromStr>(name: &str) -> Option<T> {
+    let value = std::env::var(name).ok()?;
+    match value.parse() {
+        Ok(value) => Some(value),
+        Err(_) => {
+            tracing::warn!("Ignoring invalid value of {}: {}", name, value);
+            None
+        }
+    }
+}
+
+fn env_flag(name: &str) -> bool {
+    std::env::var(name).map_or(false, |value| {
+        value.eq_ignore_ascii_case("true")
+            || value.eq_ignore_ascii_case("t")
+            || value.eq_ignore_ascii_case("yes")
+            || value.eq_ignore_ascii_case("y")
+            || value == "1"
     })
 }
 
@@ -67,10 +93,16 @@ pub extern "C" fn xOpen(
         return ffi::SQLITE_OK;
     }
 
-    let runtime = match tokio::runtime::Builder::new_current_thread()
-        .enable_all()
-        .build()
-    {
+    // The lease is extended in the background, so that it's kept while the database is idle,
+    // which takes a worker thread
+    let mut builder = if replicator::Options::from_env().lease.is_some() {
+        let mut builder = tokio::runtime::Builder::new_multi_thread();
+        builder.worker_threads(1);
+        builder
+    } else {
+        tokio::runtime::Builder::new_current_thread()
+    };
+    let runtime = match builder.enable_all().build() {
         Ok(runtime) => runtime,
         Err(e) => {
             tracing::error!("Failed to initialize async runtime: {}", e);
@@ -134,6 +166,16 @@ pub extern "C" fn xClose(
     tracing::debug!("Closing wal");
     let orig_methods = get_orig_methods(wal);
     let methods_data = unsafe { (*wal).pMethodsData as *mut replicator::Context };
+    if !is_local() && !methods_data.is_null() {
+        let ctx = get_replicator_context(wal);
+        match block_on!(ctx.runtime, ctx.replicator.drain(DRAIN_TIMEOUT)) {
+            Ok(frame) => tracing::debug!("Replicated up to frame {} before closing", frame),
+            Err(e) => tracing::error!("Failed to drain the replication backlog: {}", e),
+        }
+        if let Err(e) = block_on!(ctx.runtime, ctx.replicator.release_lease()) {
+            tracing::error!("Failed to release the lease on the database: {}", e);
+        }
+    }
     let rc = unsafe { (orig_methods.xClose.unwrap())(wal, db, sync_flags, n_buf, z_buf) };
     if rc != ffi::SQLITE_OK {
         return rc;
@@ -202,7 +244,7 @@ pub extern "C" fn xUndo(
         ctx.replicator.peek_last_valid_frame(),
         last_valid_frame
     );
-    ctx.replicator.rollback_to_frame(last_valid_frame);
+    ctx.replicator.rollback_to_frame(last_valid_frame.into());
 
     ffi::SQLITE_OK
 }
@@ -226,7 +268,7 @@ pub extern "C" fn xSavepointUndo(wal: *mut Wal, wal_data: *mut u32) -> i32 {
         ctx.replicator.peek_last_valid_frame(),
         last_valid_frame
     );
-    ctx.replicator.rollback_to_frame(last_valid_frame);
+    ctx.replicator.rollback_to_frame(last_valid_frame.into());
 
     ffi::SQLITE_OK
 }
@@ -243,7 +285,8 @@ pub extern "C" fn xFrames(
     if !is_local() {
         let ctx = get_replicator_context(wal);
         let last_valid_frame = unsafe { (*wal).hdr.mxFrame };
-        ctx.replicator.register_last_valid_frame(last_valid_frame);
+        ctx.replicator
+            .register_last_valid_frame(last_valid_frame.into());
         // In theory it's enough to set the page size only once, but in practice
         // it's a very cheap operation anyway, and the page is not always known
         // upfront and can change dynamically.
@@ -253,8 +296,19 @@ pub extern "C" fn xFrames(
             tracing::error!("{}", e);
             return ffi::SQLITE_IOERR_WRITE;
         }
+        // The whole batch is checked upfront, so that it's never left partially buffered
+        let batch_bytes: usize = ffi::PageHdrIter::new(page_headers, page_size as usize)
+            .map(|(_, data)| data.len())
+            .sum();
+        if let Err(e) = ctx.replicator.check_buffer_limit(batch_bytes) {
+            tracing::error!("{}", e);
+            return ffi::SQLITE_IOERR_WRITE;
+        }
         for (pgno, data) in ffi::PageHdrIter::new(page_headers, page_size as usize) {
-            ctx.replicator.write(pgno, data);
+            if let Err(e) = ctx.replicator.write(pgno, data) {
+                tracing::error!("{}", e);
+                return ffi::SQLITE_IOERR_WRITE;
+            }
         }
 
         // TODO: flushing can be done even if is_commit == 0, in order to drain
@@ -330,11 +384,18 @@ pub extern "C" fn xCheckpoint(
      ** because these are guaranteed to block writes, copy all WAL pages
      ** back into the main database file and reset the frame number.
      ** In order to avoid autocheckpoint on close (that's too often),
-     ** checkpoint attempts weaker than TRUNCATE are ignored.
+     ** checkpoint attempts weaker than TRUNCATE are ignored, unless
+     ** the current generation grew past its frame limit - then a new
+     ** snapshot is due, and the checkpoint is upgraded to TRUNCATE.
      */
+    let mut emode = emode;
     if emode < ffi::SQLITE_CHECKPOINT_TRUNCATE {
-        tracing::trace!("Ignoring a checkpoint request weaker than TRUNCATE");
-        return ffi::SQLITE_OK;
+        if is_local() || !get_replicator_context(wal).replicator.snapshot_due() {
+            tracing::trace!("Ignoring a checkpoint request weaker than TRUNCATE");
+            return ffi::SQLITE_OK;
+        }
+        tracing::debug!("Generation frame limit reached, upgrading the checkpoint to TRUNCATE");
+        emode = ffi::SQLITE_CHECKPOINT_TRUNCATE;
     }
     /* If there's no busy handler, let's provide a default one,
      ** since we auto-upgrade the passive checkpoint
@@ -370,6 +431,10 @@ pub extern "C" fn xCheckpoint(
         return ffi::SQLITE_OK;
     }
 
+    // The manifest only speeds up and hardens restores, so failing to write it is not fatal
+    if let Err(e) = block_on!(ctx.runtime, ctx.replicator.finalize_generation()) {
+        tracing::warn!("Failed to write the generation manifest: {}", e);
+    }
     ctx.replicator.new_generation();
     tracing::debug!("Snapshotting after checkpoint");
     let result = block_on!(ctx.runtime, ctx.replicator.snapshot_main_db_file());
@@ -425,6 +490,10 @@ pub extern "C" fn xGetPathname(buf: *mut c_char, orig: *const c_char, orig_len:
 }
 
 async fn try_restore(replicator: &mut replicator::Replicator) -> i32 {
+    if let Err(e) = replicator.acquire_lease().await {
+        tracing::error!("Failed to take the lease on the database: {}", e);
+        return ffi::SQLITE_CANTOPEN;
+    }
     match replicator.restore().await {
         Ok(replicator::RestoreAction::None) => (),
         Ok(replicator::RestoreAction::SnapshotMainDbFile) => {
@@ -441,7 +510,8 @@ async fn try_restore(replicator: &mut replicator::Replicator) -> i32 {
             }
         }
         Ok(replicator::RestoreAction::ReuseGeneration(gen)) => {
-            replicator.set_generation(gen);
+            // The replicator already continues the generation where it ended
+            tracing::debug!("Reusing generation {}", gen);
         }
         Err(e) => {
             tracing::error!("Failed to restore the database: {}", e);
@@ -487,8 +557,7 @@ pub extern "C" fn xPreMainDbOpen(_methods: *mut libsql_wal_methods, path: *const
         runtime,
         replicator::Replicator::create(replicator::Options {
             create_bucket_if_not_exists: true,
-            verify_crc: true,
-            use_compression: false,
+            ..replicator::Options::from_env()
         })
     );
     let mut replicator = match replicator {
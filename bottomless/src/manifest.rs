@@ -0,0 +1,138 @@
+use crate::replicator::FrameNo;
+use crate::restore_validation::ListedFrame;
+
+// A frame object uploaded to a generation, as recorded in its manifest
+#[derive(Clone, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct ManifestFrame {
+    pub key: String,
+    pub frameno: FrameNo,
+    pub pgno: i32,
+    pub crc: u64,
+    // Size and CRC-64 of the stored (possibly compressed) page
+    pub size: u64,
+    pub checksum: String,
+}
+
+// Every frame object uploaded to a generation, written as its manifest.json once the
+// generation is finalized. Restore fetches the frames it lists instead of listing the
+// generation, and so notices objects that went missing.
+#[derive(Clone, Debug, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct GenerationManifest {
+    pub last_frame: FrameNo,
+    pub frames: Vec<ManifestFrame>,
+}
+
+impl GenerationManifest {
+    // Frames recorded in the manifest of a generation before it's given up on, to bound the
+    // memory it takes. Such generations are restored by listing them instead.
+    pub(crate) const MAX_FRAMES: usize = 100_000;
+
+    // Adds frames to the manifest, once all of them were uploaded. Frames uploaded again,
+    // after a failed flush was rolled back, replace the earlier ones. Returns false if the
+    // manifest holds too many frames to be kept.
+    pub(crate) fn record(&mut self, frames: Vec<ManifestFrame>) -> bool {
+        if let Some(first) = frames.first() {
+            let kept = self
+                .frames
+                .partition_point(|frame| frame.frameno < first.frameno);
+            self.frames.truncate(kept);
+            self.frames.extend(frames);
+        }
+        self.frames.len() <= Self::MAX_FRAMES
+    }
+
+    // Returns the frames to restore up to the last consistent frame, as recorded in the
+    // manifest, or None, if the generation was resumed and written to after it was finalized
+    pub(crate) fn restore_frames(self, last_consistent_frame: FrameNo) -> Option<Vec<ListedFrame>> {
+        if self.last_frame < last_consistent_frame {
+            return None;
+        }
+        Some(
+            self.frames
+                .into_iter()
+                .filter(|frame| frame.frameno <= last_consistent_frame)
+                .map(|frame| ListedFrame {
+                    frameno: frame.frameno,
+                    pgno: frame.pgno,
+                    crc: frame.crc,
+                    key: frame.key,
+                    size: Some(frame.size),
+                    checksum: Some(frame.checksum),
+                    last_modified: None,
+                })
+                .collect(),
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::restore_validation::order_restore_frames;
+
+    fn manifest(frames: &[FrameNo], last_frame: FrameNo) -> GenerationManifest {
+        GenerationManifest {
+            last_frame,
+            frames: frames
+                .iter()
+                .map(|&frameno| ManifestFrame {
+                    key: format!("db-generation/{:012}-{:012}-{:016x}", frameno, 1, 0),
+                    frameno,
+                    pgno: 1,
+                    crc: 0,
+                    size: 4096,
+                    checksum: format!("{:016x}", frameno),
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn restore_frames() {
+        let complete = manifest(&[1, 2, 3, 4], 4);
+        let json = serde_json::to_vec(&complete).unwrap();
+        assert_eq!(
+            serde_json::from_slice::<GenerationManifest>(&json).unwrap(),
+            complete
+        );
+
+        // frames past the last consistent frame are not restored
+        let frames = complete.clone().restore_frames(3).unwrap();
+        let frames = order_restore_frames(frames, 3).unwrap();
+        assert_eq!(frames.len(), 3);
+        assert_eq!(frames[2].key, complete.frames[2].key);
+        assert_eq!(frames[2].size, Some(4096));
+        assert_eq!(frames[2].checksum.as_deref(), Some("0000000000000003"));
+
+        // the generation was written to after it was finalized
+        assert!(complete.restore_frames(5).is_none());
+
+        // a frame missing from the manifest fails the restore
+        let frames = manifest(&[1, 3, 4], 4).restore_frames(4).unwrap();
+        let err = order_restore_frames(frames, 4).unwrap_err();
+        assert!(err.to_string().contains("Frames 2 to 2 are missing"));
+    }
+
+    #[test]
+    fn record() {
+        let mut recorded = GenerationManifest::default();
+        assert!(recorded.record(manifest(&[1, 2, 3], 3).frames));
+        // frames uploaded again after a rollback replace the earlier ones
+        let mut frames = manifest(&[2, 3], 3).frames;
+        frames[0].pgno = 7;
+        assert!(recorded.record(frames));
+        assert_eq!(
+            recorded
+                .frames
+                .iter()
+                .map(|f| f.frameno)
+                .collect::<Vec<_>>(),
+            vec![1, 2, 3]
+        );
+        assert_eq!(recorded.frames[1].pgno, 7);
+
+        // past the limit, the manifest is not worth keeping
+        let frames: Vec<FrameNo> = (4..=GenerationManifest::MAX_FRAMES as FrameNo + 1).collect();
+        assert!(!recorded.record(manifest(&frames, 0).frames));
+    }
+}
@@ -0,0 +1,439 @@
+// A minimal in-memory S3 server, speaking just enough of the path-style REST API for the
+// replicator tests: GetObject, PutObject, DeleteObject, HeadBucket, CreateBucket and
+// ListObjects (v1). The bucket name is ignored, all requests share a single bucket.
+
+use aws_sdk_s3::{Client, Credentials, Endpoint, Region};
+use bytes::Bytes;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+
+#[derive(Clone, Debug, Default)]
+pub struct Object {
+    pub body: Bytes,
+    pub metadata: BTreeMap<String, String>,
+    // Number of the write which stored this object, listed as its modification time
+    pub written: u64,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    objects: BTreeMap<String, Object>,
+    // Keys of the objects fetched with GetObject, in order
+    fetched: Vec<String>,
+    // Keys left out of the given number of upcoming listings, like freshly written objects
+    // on stores with eventually consistent listings
+    unlisted: HashMap<String, usize>,
+    // Status codes returned instead of serving requests for given keys
+    failures: HashMap<String, u16>,
+    // Number of objects written so far
+    writes: u64,
+}
+
+#[derive(Clone, Debug)]
+pub struct MockS3 {
+    state: Arc<Mutex<State>>,
+    endpoint: String,
+}
+
+struct Response {
+    status: u16,
+    headers: Vec<(String, String)>,
+    body: Bytes,
+}
+
+impl Response {
+    fn empty(status: u16) -> Self {
+        Self {
+            status,
+            headers: Vec::new(),
+            body: Bytes::new(),
+        }
+    }
+
+    fn xml(status: u16, body: String) -> Self {
+        Self {
+            status,
+            headers: vec![("content-type".to_string(), "application/xml".to_string())],
+            body: Bytes::from(body),
+        }
+    }
+
+    fn error(status: u16, code: &str) -> Self {
+        Self::xml(
+            status,
+            format!(
+                "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<Error><Code>{code}</Code><Message>{code}</Message></Error>"
+            ),
+        )
+    }
+
+    fn to_bytes(&self, head: bool) -> Vec<u8> {
+        let mut response = format!(
+            "HTTP/1.1 {} {}\r\ncontent-length: {}\r\n",
+            self.status,
+            Self::reason(self.status),
+            self.body.len()
+        );
+        for (name, value) in &self.headers {
+            response.push_str(&format!("{name}: {value}\r\n"));
+        }
+        response.push_str("\r\n");
+        let mut response = response.into_bytes();
+        if !head {
+            response.extend_from_slice(&self.body);
+        }
+        response
+    }
+
+    fn reason(status: u16) -> &'static str {
+        match status {
+            200 => "OK",
+            204 => "No Content",
+            403 => "Forbidden",
+            404 => "Not Found",
+            503 => "Service Unavailable",
+            _ => "Internal Server Error",
+        }
+    }
+}
+
+impl MockS3 {
+    // Starts serving on a random local port, for as long as the current runtime lives
+    pub async fn start() -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let endpoint = format!("http://{}", listener.local_addr().unwrap());
+        let s3 = Self {
+            state: Arc::default(),
+            endpoint,
+        };
+        let server = s3.clone();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let server = server.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = server.serve(stream).await {
+                        tracing::debug!("Mock S3 connection failed: {}", e);
+                    }
+                });
+            }
+        });
+        s3
+    }
+
+    // Returns a client sending its requests to this server
+    pub async fn client(&self) -> Client {
+        let config = aws_config::from_env()
+            .region(Region::new("us-east-1"))
+            .credentials_provider(Credentials::new("test", "test", None, None, "mock"))
+            .endpoint_resolver(Endpoint::immutable(self.endpoint.clone()).unwrap())
+            .load()
+            .await;
+        Client::new(&config)
+    }
+
+    pub fn put(&self, key: impl Into<String>, body: impl Into<Bytes>) {
+        let mut state = self.state.lock().unwrap();
+        state.writes += 1;
+        let written = state.writes;
+        state.objects.insert(
+            key.into(),
+            Object {
+                body: body.into(),
+                metadata: BTreeMap::new(),
+                written,
+            },
+        );
+    }
+
+    // Replaces the body of an existing object, keeping its metadata, like data corrupted
+    // at rest
+    pub fn replace_body(&self, key: &str, body: impl Into<Bytes>) {
+        if let Some(object) = self.state.lock().unwrap().objects.get_mut(key) {
+            object.body = body.into();
+        }
+    }
+
+    // Returns the keys starting with given prefix, in order
+    pub fn keys(&self, prefix: &str) -> Vec<String> {
+        let state = self.state.lock().unwrap();
+        state
+            .objects
+            .keys()
+            .filter(|key| key.starts_with(prefix))
+            .cloned()
+            .collect()
+    }
+
+    pub fn get(&self, key: &str) -> Option<Object> {
+        self.state.lock().unwrap().objects.get(key).cloned()
+    }
+
+    // Returns the keys fetched with GetObject since the last call, in order
+    pub fn take_fetched(&self) -> Vec<String> {
+        std::mem::take(&mut self.state.lock().unwrap().fetched)
+    }
+
+    // Leaves given key out of the given number of upcoming listings
+    pub fn hide_from_listings(&self, key: impl Into<String>, listings: usize) {
+        self.state
+            .lock()
+            .unwrap()
+            .unlisted
+            .insert(key.into(), listings);
+    }
+
+    // Fails all requests for given key with given status
+    pub fn fail(&self, key: impl Into<String>, status: u16) {
+        self.state
+            .lock()
+            .unwrap()
+            .failures
+            .insert(key.into(), status);
+    }
+
+    // Serves requests for given key again
+    pub fn recover(&self, key: &str) {
+        self.state.lock().unwrap().failures.remove(key);
+    }
+
+    async fn serve(self, stream: TcpStream) -> std::io::Result<()> {
+        let mut stream = BufReader::new(stream);
+        loop {
+            let mut request_line = String::new();
+            if stream.read_line(&mut request_line).await? == 0 {
+                return Ok(());
+            }
+            let mut parts = request_line.split_whitespace();
+            let (method, target) = match (parts.next(), parts.next()) {
+                (Some(method), Some(target)) => (method.to_string(), target.to_string()),
+                _ => return Ok(()),
+            };
+            let mut headers = HashMap::new();
+            loop {
+                let mut line = String::new();
+                stream.read_line(&mut line).await?;
+                let line = line.trim_end();
+                if line.is_empty() {
+                    break;
+                }
+                if let Some((name, value)) = line.split_once(':') {
+                    headers.insert(name.trim().to_ascii_lowercase(), value.trim().to_string());
+                }
+            }
+            assert!(
+                !headers.contains_key("transfer-encoding"),
+                "chunked request bodies are not supported"
+            );
+            let len = headers
+                .get("content-length")
+                .and_then(|len| len.parse().ok())
+                .unwrap_or(0);
+            let mut body = vec![0u8; len];
+            stream.read_exact(&mut body).await?;
+            let response = self.handle(&method, &target, &headers, Bytes::from(body));
+            stream
+                .get_mut()
+                .write_all(&response.to_bytes(method == "HEAD"))
+                .await?;
+        }
+    }
+
+    fn handle(
+        &self,
+        method: &str,
+        target: &str,
+        headers: &HashMap<String, String>,
+        body: Bytes,
+    ) -> Response {
+        let (path, query) = target.split_once('?').unwrap_or((target, ""));
+        let path = percent_decode(path);
+        // path-style addressing: /<bucket>/<key>
+        let key = path
+            .trim_start_matches('/')
+            .split_once('/')
+            .map(|(_, key)| key)
+            .unwrap_or("");
+        let query: HashMap<String, String> = query
+            .split('&')
+            .filter(|param| !param.is_empty())
+            .map(|param| {
+                let (name, value) = param.split_once('=').unwrap_or((param, ""));
+                (percent_decode(name), percent_decode(value))
+            })
+            .collect();
+
+        let mut state = self.state.lock().unwrap();
+        if let Some(&status) = state.failures.get(key) {
+            let code = match status {
+                403 => "AccessDenied",
+                503 => "SlowDown",
+                _ => "InternalError",
+            };
+            return Response::error(status, code);
+        }
+        match (method, key) {
+            ("HEAD", "") | ("PUT", "") => Response::empty(200),
+            ("GET", "") => Self::list(&mut state, &query),
+            ("GET", key) => {
+                state.fetched.push(key.to_string());
+                match state.objects.get(key) {
+                    Some(object) => {
+                        let mut headers: Vec<_> = object
+                            .metadata
+                            .iter()
+                            .map(|(name, value)| (format!("x-amz-meta-{name}"), value.clone()))
+                            .collect();
+                        headers.push((
+                            "content-type".to_string(),
+                            "application/octet-stream".to_string(),
+                        ));
+                        Response {
+                            status: 200,
+                            headers,
+                            body: object.body.clone(),
+                        }
+                    }
+                    None => Response::error(404, "NoSuchKey"),
+                }
+            }
+            ("PUT", key) => {
+                let metadata = headers
+                    .iter()
+                    .filter_map(|(name, value)| {
+                        Some((name.strip_prefix("x-amz-meta-")?.to_string(), value.clone()))
+                    })
+                    .collect();
+                state.writes += 1;
+                let written = state.writes;
+                state.objects.insert(
+                    key.to_string(),
+                    Object {
+                        body,
+                        metadata,
+                        written,
+                    },
+                );
+                Response::empty(200)
+            }
+            ("DELETE", key) => {
+                state.objects.remove(key);
+                Response::empty(204)
+            }
+            _ => Response::error(400, "NotImplemented"),
+        }
+    }
+
+    fn list(state: &mut State, query: &HashMap<String, String>) -> Response {
+        let prefix = query.get("prefix").map(String::as_str).unwrap_or("");
+        let marker = query.get("marker").map(String::as_str).unwrap_or("");
+        let delimiter = query
+            .get("delimiter")
+            .filter(|delimiter| !delimiter.is_empty());
+        let max_keys: usize = query
+            .get("max-keys")
+            .and_then(|max_keys| max_keys.parse().ok())
+            .unwrap_or(1000);
+
+        // Keys and common prefixes, in order, as a listing without a limit would return them
+        let mut entries: Vec<(String, Option<&Object>)> = Vec::new();
+        let mut common_prefixes = BTreeSet::new();
+        for (key, object) in state.objects.iter() {
+            if !key.starts_with(prefix) || state.unlisted.contains_key(key) {
+                continue;
+            }
+            let rolled_up = delimiter.and_then(|delimiter| {
+                let rest = &key[prefix.len()..];
+                rest.find(delimiter.as_str())
+                    .map(|index| key[..prefix.len() + index + delimiter.len()].to_string())
+            });
+            match rolled_up {
+                Some(common_prefix) => {
+                    if common_prefix.as_str() > marker
+                        && common_prefixes.insert(common_prefix.clone())
+                    {
+                        entries.push((common_prefix, None));
+                    }
+                }
+                None if key.as_str() > marker => entries.push((key.clone(), Some(object))),
+                None => (),
+            }
+        }
+        for listings in state.unlisted.values_mut() {
+            *listings -= 1;
+        }
+        state.unlisted.retain(|_, listings| *listings > 0);
+
+        let is_truncated = entries.len() > max_keys;
+        entries.truncate(max_keys);
+        let mut xml = format!(
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<ListBucketResult xmlns=\"http://s3.amazonaws.com/doc/2006-03-01/\"><Name>bottomless</Name><Prefix>{}</Prefix><Marker>{}</Marker><MaxKeys>{}</MaxKeys><IsTruncated>{}</IsTruncated>",
+            escape(prefix),
+            escape(marker),
+            max_keys,
+            is_truncated
+        );
+        if is_truncated {
+            if let Some((last, _)) = entries.last() {
+                xml.push_str(&format!("<NextMarker>{}</NextMarker>", escape(last)));
+            }
+        }
+        for (entry, object) in &entries {
+            match object {
+                Some(object) => xml.push_str(&format!(
+                    "<Contents><Key>{}</Key><LastModified>{}</LastModified><Size>{}</Size></Contents>",
+                    escape(entry),
+                    last_modified(object.written),
+                    object.body.len()
+                )),
+                None => xml.push_str(&format!(
+                    "<CommonPrefixes><Prefix>{}</Prefix></CommonPrefixes>",
+                    escape(entry)
+                )),
+            }
+        }
+        xml.push_str("</ListBucketResult>");
+        Response::xml(200, xml)
+    }
+}
+
+fn percent_decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes
+            .get(i + 1..i + 3)
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match (bytes[i], hex) {
+            (b'%', Some(byte)) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            (byte, _) => {
+                decoded.push(byte);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+fn escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+// Modification time listed for the object stored by given write, one second after the one
+// stored by the write before
+fn last_modified(written: u64) -> String {
+    format!(
+        "2023-01-01T{:02}:{:02}:{:02}.000Z",
+        written / 3600 % 24,
+        written / 60 % 60,
+        written % 60
+    )
+}
@@ -0,0 +1,128 @@
+use std::time::{Duration, Instant};
+
+// Token bucket limiting the rate at which bytes are downloaded. Up to a second worth of
+// bytes can be downloaded in a burst, before readers are made to wait.
+#[derive(Debug)]
+pub(crate) struct RateLimiter {
+    bytes_per_sec: u64,
+    // The point in time at which all the bytes reserved so far are paid for
+    paid_until: Instant,
+}
+
+impl RateLimiter {
+    const BURST: Duration = Duration::from_secs(1);
+
+    pub(crate) fn new(bytes_per_sec: u64) -> Self {
+        let now = Instant::now();
+        Self {
+            bytes_per_sec: bytes_per_sec.max(1),
+            // starts with a full burst available
+            paid_until: now.checked_sub(Self::BURST).unwrap_or(now),
+        }
+    }
+
+    // Reserves `bytes` and returns how long to wait before using them
+    pub(crate) fn reserve(&mut self, bytes: u64, now: Instant) -> Duration {
+        let earliest = now.checked_sub(Self::BURST).unwrap_or(now);
+        self.paid_until = self.paid_until.max(earliest)
+            + Duration::from_secs_f64(bytes as f64 / self.bytes_per_sec as f64);
+        self.paid_until.saturating_duration_since(now)
+    }
+}
+
+// Reader which waits after each read until the bytes read are allowed by the rate limiter,
+// or just passes them through if there is no limiter
+pub(crate) struct ThrottledReader<'a, R> {
+    inner: R,
+    rate_limiter: Option<&'a mut RateLimiter>,
+    delay: Option<std::pin::Pin<Box<tokio::time::Sleep>>>,
+}
+
+impl<'a, R> ThrottledReader<'a, R> {
+    pub(crate) fn new(inner: R, rate_limiter: Option<&'a mut RateLimiter>) -> Self {
+        Self {
+            inner,
+            rate_limiter,
+            delay: None,
+        }
+    }
+}
+
+impl<R: tokio::io::AsyncRead + Unpin> tokio::io::AsyncRead for ThrottledReader<'_, R> {
+    fn poll_read(
+        mut self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &mut tokio::io::ReadBuf<'_>,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        use std::future::Future;
+        use std::task::Poll;
+
+        if let Some(delay) = self.delay.as_mut() {
+            if delay.as_mut().poll(cx).is_pending() {
+                return Poll::Pending;
+            }
+            self.delay = None;
+        }
+        let filled = buf.filled().len();
+        match std::pin::Pin::new(&mut self.inner).poll_read(cx, buf) {
+            Poll::Ready(Ok(())) => (),
+            other => return other,
+        }
+        let read = (buf.filled().len() - filled) as u64;
+        if let Some(rate_limiter) = self.rate_limiter.as_mut() {
+            let delay = rate_limiter.reserve(read, Instant::now());
+            if !delay.is_zero() {
+                self.delay = Some(Box::pin(tokio::time::sleep(delay)));
+            }
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn rate_limiter_reserve() {
+        let mut rate_limiter = RateLimiter::new(1000);
+        let now = rate_limiter.paid_until + Duration::from_secs(10);
+        // a second worth of bytes is allowed in a burst
+        assert_eq!(rate_limiter.reserve(600, now), Duration::ZERO);
+        assert_eq!(rate_limiter.reserve(400, now), Duration::ZERO);
+        assert_eq!(rate_limiter.reserve(500, now), Duration::from_millis(500));
+        assert_eq!(rate_limiter.reserve(500, now), Duration::from_secs(1));
+        // waiting pays the debt off
+        assert_eq!(
+            rate_limiter.reserve(0, now + Duration::from_secs(1)),
+            Duration::ZERO
+        );
+    }
+
+    #[tokio::test]
+    async fn throttled_reader() {
+        use tokio::io::AsyncReadExt;
+
+        let data: Vec<u8> = (0..30_000u32).map(|i| i as u8).collect();
+
+        let start = Instant::now();
+        let mut copied = Vec::new();
+        ThrottledReader::new(&data[..], None)
+            .read_to_end(&mut copied)
+            .await
+            .unwrap();
+        assert_eq!(copied, data);
+        assert!(start.elapsed() < Duration::from_millis(500));
+
+        // the first 20kB pass as a burst, the remaining 10kB take half a second
+        let mut rate_limiter = RateLimiter::new(20_000);
+        let start = Instant::now();
+        let mut copied = Vec::new();
+        ThrottledReader::new(&data[..], Some(&mut rate_limiter))
+            .read_to_end(&mut copied)
+            .await
+            .unwrap();
+        assert_eq!(copied, data);
+        assert!(start.elapsed() >= Duration::from_millis(450));
+    }
+}
@@ -1,12 +1,39 @@
-use aws_sdk_s3::types::ByteStream;
+use aws_sdk_s3::types::{ByteStream, SdkError};
 use aws_sdk_s3::{Client, Endpoint};
 use bytes::{Bytes, BytesMut};
 use std::cmp::Ordering;
 use std::collections::BTreeMap;
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use crate::circuit_breaker::CircuitBreaker;
+pub use crate::circuit_breaker::{CircuitBreakerOptions, CircuitBreakerState};
+pub use crate::lease::LeaseOptions;
+use crate::lease::{read_lease, Lease};
+pub use crate::manifest::{GenerationManifest, ManifestFrame};
+use crate::rate_limiter::{RateLimiter, ThrottledReader};
+pub use crate::restore_validation::RestoreOptions;
+use crate::restore_validation::{
+    frame_gaps, order_restore_frames, read_frame_page, verify_object_checksum, verify_object_size,
+    ListedFrame,
+};
 
 pub type Result<T> = anyhow::Result<T>;
 
-const CRC_64: crc::Crc<u64> = crc::Crc::<u64>::new(&crc::CRC_64_ECMA_182);
+/// Frame number in the replicated log. Generations written before frame numbers were widened
+/// to 64 bits store them as 32-bit values in their `.consistent` objects.
+pub type FrameNo = u64;
+
+pub(crate) const CRC_64: crc::Crc<u64> = crc::Crc::<u64>::new(&crc::CRC_64_ECMA_182);
+
+// Object metadata entry holding the checksum of the stored (possibly compressed) page
+const CHECKSUM_METADATA_KEY: &str = "bottomless-checksum";
+// Object metadata entry telling how the stored page is compressed, so that it can be read
+// regardless of the compression setting of the reader
+const COMPRESSION_METADATA_KEY: &str = "bottomless-compression";
+const GZIP_COMPRESSION: &str = "gzip";
+const NO_COMPRESSION: &str = "none";
 
 #[derive(Debug)]
 struct Frame {
@@ -15,15 +42,23 @@ struct Frame {
     crc: u64,
 }
 
+// Bucket, possibly behind another endpoint, which the database is restored from instead of
+// the one backups are written to, e.g. while migrating to a new bucket
+#[derive(Debug)]
+struct RestoreSource {
+    client: Client,
+    bucket: String,
+}
+
 #[derive(Debug)]
 pub struct Replicator {
     pub client: Client,
-    write_buffer: BTreeMap<u32, Frame>,
+    write_buffer: BTreeMap<FrameNo, Frame>,
 
     pub page_size: usize,
     generation: uuid::Uuid,
     pub commits_in_current_generation: u32,
-    next_frame: u32,
+    next_frame: FrameNo,
     verify_crc: bool,
     last_frame_crc: u64,
     last_transaction_crc: u64,
@@ -32,6 +67,29 @@ pub struct Replicator {
     pub db_name: String,
 
     use_compression: bool,
+    verify_compression: bool,
+    buffered_bytes: usize,
+    max_buffered_bytes: Option<usize>,
+    object_checksums: bool,
+    restore_stats: RestoreStats,
+    lease_ttl: Option<Duration>,
+    lease_owner: String,
+    // Task extending the lease held by this instance, None if it is not held
+    lease_refresher: Option<tokio::task::JoinHandle<()>>,
+    circuit_breaker: Option<CircuitBreaker>,
+    list_page_size: i32,
+    temp_dir: Option<PathBuf>,
+    max_frames_per_generation: Option<FrameNo>,
+    restore_options: RestoreOptions,
+    restore_source: Option<RestoreSource>,
+    // Manifest of the current generation, kept up to date as frames are uploaded. None when
+    // this replicator did not start the generation, as the frames uploaded before are unknown.
+    manifest: Option<GenerationManifest>,
+    // Last consistent frame stored by this replicator in the current generation. None when
+    // the generation is reused and the stored frame hasn't been read yet.
+    last_committed_frame: Option<FrameNo>,
+    // Last commit which wasn't marked as consistent in S3 yet, because finalizing it failed
+    pending_commit: Option<(FrameNo, [u32; 2])>,
 }
 
 #[derive(Debug)]
@@ -40,6 +98,16 @@ pub struct FetchedResults {
     pub next_marker: Option<String>,
 }
 
+// Contents of the .consistent object of a generation
+#[derive(Debug, Default, PartialEq, Eq)]
+struct ConsistentInfo {
+    frame: FrameNo,
+    checksum: u64,
+    // CRC-64 chain value of the last consistent frame, missing from objects written by
+    // older versions
+    frame_crc: Option<u64>,
+}
+
 #[derive(Debug)]
 pub enum RestoreAction {
     None,
@@ -47,26 +115,131 @@ pub enum RestoreAction {
     ReuseGeneration(uuid::Uuid),
 }
 
-#[derive(Clone, Copy, Debug)]
+// How the local database relates to its backup
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SyncStatus {
+    InSync,
+    // The local database contains changes missing from the backup
+    LocalAhead,
+    // The backup contains changes missing from the local database
+    RemoteAhead,
+    // Both start from the same snapshot, but their logs contain different changes
+    Diverged,
+}
+
+// Which side is kept when reconciling the local database with its backup
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Resolution {
+    // Snapshots the local database into a new generation
+    KeepLocal,
+    // Restores the backup over the local database, which is moved aside
+    KeepRemote,
+}
+
+#[derive(Clone, Debug)]
+pub struct ReconcileReport {
+    // Generation the local database was compared with, None if there is no backup
+    pub generation: Option<uuid::Uuid>,
+    pub status: SyncStatus,
+    pub local_change_counter: [u8; 4],
+    pub remote_change_counter: [u8; 4],
+    pub local_wal_frames: FrameNo,
+    pub last_consistent_frame: FrameNo,
+}
+
+// Where the time of the last restore went, to tell slow downloads from slow local writes
+#[derive(Clone, Copy, Debug, Default)]
+pub struct RestoreStats {
+    // Time spent downloading and writing the main database snapshot
+    pub snapshot_time: Duration,
+    // Number of bytes of frames downloaded (after decompression)
+    pub downloaded_bytes: u64,
+    // Time spent fetching and reading frames from S3, including decompression
+    pub network_time: Duration,
+    // Number of bytes of frames written to the local database file
+    pub written_bytes: u64,
+    // Time spent seeking, writing and flushing frames to the local database file
+    pub disk_time: Duration,
+}
+
+#[derive(Clone, Debug)]
 pub struct Options {
     pub create_bucket_if_not_exists: bool,
     pub verify_crc: bool,
     pub use_compression: bool,
+    // Decompresses each compressed page before upload and checks that it matches the source
+    pub verify_compression: bool,
+    // Upper bound on the number of bytes of uncommitted pages kept in memory
+    // while waiting for a commit. None means no limit.
+    pub max_buffered_bytes: Option<usize>,
+    // None disables the circuit breaker
+    pub circuit_breaker: Option<CircuitBreakerOptions>,
+    // Stores a checksum of each uploaded page object in its metadata and verifies it
+    // during restore, before the page is decompressed and applied
+    pub object_checksums: bool,
+    // None disables leases
+    pub lease: Option<LeaseOptions>,
+    // Maximum number of keys returned by a single object listing request
+    pub list_page_size: i32,
+    // Directory in which the compressed snapshot of the main db file is created before
+    // it's uploaded. None means the directory of the db file itself.
+    pub temp_dir: Option<PathBuf>,
+    // Skips checking that the bucket exists on startup, for environments where it is known
+    // to exist and the credentials are not allowed to access the bucket itself
+    pub skip_bucket_check: bool,
+    // How many times the startup bucket check is retried on transient errors, with exponential
+    // backoff. Other errors fail right away.
+    pub bucket_check_retries: u32,
+    // Number of frames replicated in a generation after which the next automatic checkpoint
+    // is upgraded to TRUNCATE, so that a new snapshot is taken and restore doesn't have to
+    // replay an ever-growing list of frames. None leaves checkpoints to the application.
+    pub max_frames_per_generation: Option<FrameNo>,
+    pub restore: RestoreOptions,
+}
+
+impl Options {
+    // Options of the replicator used by the WAL methods, configured with `LIBSQL_BOTTOMLESS_*`
+    // environment variables
+    pub fn from_env() -> Self {
+        Self {
+            create_bucket_if_not_exists: false,
+            verify_crc: true,
+            use_compression: crate::env_flag("LIBSQL_BOTTOMLESS_COMPRESSION"),
+            verify_compression: crate::env_flag("LIBSQL_BOTTOMLESS_VERIFY_COMPRESSION"),
+            max_buffered_bytes: crate::env_value("LIBSQL_BOTTOMLESS_MAX_BUFFERED_BYTES"),
+            circuit_breaker: CircuitBreakerOptions::from_env(),
+            object_checksums: crate::env_flag("LIBSQL_BOTTOMLESS_OBJECT_CHECKSUMS"),
+            lease: LeaseOptions::from_env(),
+            list_page_size: Replicator::DEFAULT_LIST_PAGE_SIZE,
+            temp_dir: std::env::var_os("LIBSQL_BOTTOMLESS_TEMP_DIR").map(PathBuf::from),
+            skip_bucket_check: crate::env_flag("LIBSQL_BOTTOMLESS_SKIP_BUCKET_CHECK"),
+            bucket_check_retries: 3,
+            max_frames_per_generation: crate::env_value(
+                "LIBSQL_BOTTOMLESS_MAX_FRAMES_PER_GENERATION",
+            ),
+            restore: RestoreOptions::from_env(),
+        }
+    }
 }
 
 impl Replicator {
     pub const UNSET_PAGE_SIZE: usize = usize::MAX;
+    // Largest page size supported by SQLite, and so the largest frame accepted by restore
+    pub(crate) const MAX_PAGE_SIZE: usize = 65536;
+    const RESTORE_LIST_RETRY_DELAY: Duration = Duration::from_millis(500);
+    const BUCKET_CHECK_RETRY_DELAY: Duration = Duration::from_millis(500);
+    // The maximum number of keys S3 returns in a single listing
+    pub const DEFAULT_LIST_PAGE_SIZE: i32 = 1000;
+    // Layout of the .consistent object written by this version, see put_consistent_info
+    const CONSISTENT_INFO_VERSION: u8 = 3;
 
     pub async fn new() -> Result<Self> {
-        Self::create(Options {
-            create_bucket_if_not_exists: false,
-            verify_crc: true,
-            use_compression: false,
-        })
-        .await
+        Self::create(Options::from_env()).await
     }
 
     pub async fn create(options: Options) -> Result<Self> {
+        Self::validate_list_page_size(options.list_page_size)?;
+        Self::validate_page_size(options.restore.max_page_size)?;
         let write_buffer = BTreeMap::new();
         let mut loader = aws_config::from_env();
         if let Ok(endpoint) = std::env::var("LIBSQL_BOTTOMLESS_ENDPOINT") {
@@ -75,24 +248,14 @@ impl Replicator {
         let bucket =
             std::env::var("LIBSQL_BOTTOMLESS_BUCKET").unwrap_or_else(|_| "bottomless".to_string());
         let client = Client::new(&loader.load().await);
+        let restore_source = Self::create_restore_source(&bucket).await?;
         let generation = Self::generate_generation();
         tracing::debug!("Generation {}", generation);
 
-        match client.head_bucket().bucket(&bucket).send().await {
-            Ok(_) => tracing::info!("Bucket {} exists and is accessible", bucket),
-            Err(aws_sdk_s3::types::SdkError::ServiceError(err)) if err.err().is_not_found() => {
-                if options.create_bucket_if_not_exists {
-                    tracing::info!("Bucket {} not found, recreating", bucket);
-                    client.create_bucket().bucket(&bucket).send().await?;
-                } else {
-                    tracing::error!("Bucket {} does not exist", bucket);
-                    return Err(aws_sdk_s3::types::SdkError::ServiceError(err).into());
-                }
-            }
-            Err(e) => {
-                tracing::error!("Bucket checking error: {}", e);
-                return Err(e.into());
-            }
+        if options.skip_bucket_check {
+            tracing::info!("Skipping the check of bucket {}", bucket);
+        } else {
+            Self::check_bucket(&client, &bucket, &options).await?;
         }
 
         Ok(Self {
@@ -109,9 +272,125 @@ impl Replicator {
             db_path: String::new(),
             db_name: String::new(),
             use_compression: options.use_compression,
+            verify_compression: options.verify_compression,
+            buffered_bytes: 0,
+            max_buffered_bytes: options.max_buffered_bytes,
+            object_checksums: options.object_checksums,
+            restore_stats: RestoreStats::default(),
+            lease_ttl: options.lease.as_ref().map(|lease| lease.ttl),
+            // Filled in once the database is registered, unless configured
+            lease_owner: options
+                .lease
+                .and_then(|lease| lease.owner)
+                .unwrap_or_default(),
+            lease_refresher: None,
+            circuit_breaker: options
+                .circuit_breaker
+                .map(|options| CircuitBreaker::new(options.failure_threshold, options.cooldown)),
+            list_page_size: options.list_page_size,
+            temp_dir: options.temp_dir,
+            max_frames_per_generation: options.max_frames_per_generation,
+            restore_options: options.restore,
+            restore_source,
+            manifest: None,
+            last_committed_frame: None,
+            pending_commit: None,
         })
     }
 
+    // Returns the name of the bucket to restore from, if it differs from the backup bucket
+    // or lives behind another endpoint
+    fn restore_source_bucket(
+        bucket: &str,
+        restore_bucket: Option<String>,
+        restore_endpoint: Option<&str>,
+    ) -> Option<String> {
+        match (restore_bucket, restore_endpoint) {
+            (Some(restore_bucket), None) if restore_bucket == bucket => None,
+            (Some(restore_bucket), _) => Some(restore_bucket),
+            (None, Some(_)) => Some(bucket.to_string()),
+            (None, None) => None,
+        }
+    }
+
+    async fn create_restore_source(bucket: &str) -> Result<Option<RestoreSource>> {
+        let restore_endpoint = std::env::var("LIBSQL_BOTTOMLESS_RESTORE_ENDPOINT").ok();
+        let restore_bucket = match Self::restore_source_bucket(
+            bucket,
+            std::env::var("LIBSQL_BOTTOMLESS_RESTORE_BUCKET").ok(),
+            restore_endpoint.as_deref(),
+        ) {
+            Some(restore_bucket) => restore_bucket,
+            None => return Ok(None),
+        };
+        let mut loader = aws_config::from_env();
+        if let Some(endpoint) =
+            restore_endpoint.or_else(|| std::env::var("LIBSQL_BOTTOMLESS_ENDPOINT").ok())
+        {
+            loader = loader.endpoint_resolver(Endpoint::immutable(endpoint)?);
+        }
+        Ok(Some(RestoreSource {
+            client: Client::new(&loader.load().await),
+            bucket: restore_bucket,
+        }))
+    }
+
+    // Checks that the bucket exists, creating it if allowed to
+    async fn check_bucket(client: &Client, bucket: &str, options: &Options) -> Result<()> {
+        let mut retries = 0;
+        loop {
+            match client.head_bucket().bucket(bucket).send().await {
+                Ok(_) => {
+                    tracing::info!("Bucket {} exists and is accessible", bucket);
+                    return Ok(());
+                }
+                Err(SdkError::ServiceError(err)) if err.err().is_not_found() => {
+                    if options.create_bucket_if_not_exists {
+                        tracing::info!("Bucket {} not found, recreating", bucket);
+                        client.create_bucket().bucket(bucket).send().await?;
+                        return Ok(());
+                    } else {
+                        tracing::error!("Bucket {} does not exist", bucket);
+                        return Err(SdkError::ServiceError(err).into());
+                    }
+                }
+                Err(e) if retries < options.bucket_check_retries && Self::is_transient(&e) => {
+                    let delay = Self::BUCKET_CHECK_RETRY_DELAY * 2u32.pow(retries);
+                    retries += 1;
+                    tracing::warn!(
+                        "Bucket checking error: {}, retrying in {:?} ({}/{})",
+                        e,
+                        delay,
+                        retries,
+                        options.bucket_check_retries
+                    );
+                    tokio::time::sleep(delay).await;
+                }
+                Err(e) => {
+                    tracing::error!("Bucket checking error: {}", e);
+                    return Err(e.into());
+                }
+            }
+        }
+    }
+
+    // Tells errors worth retrying, like timeouts or throttling, from ones like missing permissions
+    fn is_transient<E>(error: &SdkError<E>) -> bool {
+        match error {
+            SdkError::TimeoutError(_)
+            | SdkError::DispatchFailure(_)
+            | SdkError::ResponseError(_) => true,
+            SdkError::ServiceError(err) => {
+                Self::is_transient_status(err.raw().http().status().as_u16())
+            }
+            _ => false,
+        }
+    }
+
+    fn is_transient_status(status: u16) -> bool {
+        status == 429 || (500..600).contains(&status)
+    }
+
     // The database can use different page size - as soon as it's known,
     // it should be communicated to the replicator via this call.
     // NOTICE: in practice, WAL journaling mode does not allow changing page sizes,
@@ -119,6 +398,7 @@ impl Replicator {
     // it will be useful, if WAL ever allows changing the page size.
     pub fn set_page_size(&mut self, page_size: usize) -> Result<()> {
         tracing::trace!("Setting page size from {} to {}", self.page_size, page_size);
+        Self::validate_page_size(page_size)?;
         if self.page_size != Self::UNSET_PAGE_SIZE && self.page_size != page_size {
             return Err(anyhow::anyhow!(
                 "Cannot set page size to {}, it was already set to {}",
@@ -130,14 +410,39 @@ impl Replicator {
         Ok(())
     }
 
+    // SQLite page size must be a power of two between 512 and 65536
+    fn validate_page_size(page_size: usize) -> Result<()> {
+        if !(512..=Self::MAX_PAGE_SIZE).contains(&page_size) || !page_size.is_power_of_two() {
+            return Err(anyhow::anyhow!(
+                "Invalid page size {}: it must be a power of two between 512 and 65536",
+                page_size
+            ));
+        }
+        Ok(())
+    }
+
+    fn validate_list_page_size(list_page_size: i32) -> Result<()> {
+        if !(1..=Self::DEFAULT_LIST_PAGE_SIZE).contains(&list_page_size) {
+            return Err(anyhow::anyhow!(
+                "Invalid list page size {}: it must be between 1 and {}",
+                list_page_size,
+                Self::DEFAULT_LIST_PAGE_SIZE
+            ));
+        }
+        Ok(())
+    }
+
     // Gets an object from the current bucket
     fn get_object(&self, key: String) -> aws_sdk_s3::client::fluent_builders::GetObject {
         self.client.get_object().bucket(&self.bucket).key(key)
     }
 
-    // Lists objects from the current bucket
-    fn list_objects(&self) -> aws_sdk_s3::client::fluent_builders::ListObjects {
-        self.client.list_objects().bucket(&self.bucket)
+    // Lists objects from the current bucket, up to the configured page size at a time
+    pub fn list_objects(&self) -> aws_sdk_s3::client::fluent_builders::ListObjects {
+        self.client
+            .list_objects()
+            .bucket(&self.bucket)
+            .max_keys(self.list_page_size)
     }
 
     // Generates a new generation UUID v7, which contains a timestamp and is binary-sortable.
@@ -156,6 +461,8 @@ impl Replicator {
     pub fn new_generation(&mut self) {
         tracing::debug!("New generation started: {}", self.generation);
         self.set_generation(Self::generate_generation());
+        self.manifest = Some(GenerationManifest::default());
+        self.last_committed_frame = Some(0);
     }
 
     // Sets a generation for this replicator instance. This function
@@ -165,6 +472,12 @@ impl Replicator {
         self.generation = generation;
         self.commits_in_current_generation = 0;
         self.next_frame = 1; // New generation marks a new WAL
+                             // ... and a new CRC chain, so that it can be verified without previous generations
+        self.last_frame_crc = 0;
+        self.last_transaction_crc = 0;
+        self.manifest = None;
+        self.last_committed_frame = None;
+        self.pending_commit = None;
         tracing::debug!("Generation set to {}", self.generation);
     }
 
@@ -178,23 +491,29 @@ impl Replicator {
             None => &db_path,
         };
         self.db_name = db_id + name;
+        if self.lease_owner.is_empty() {
+            let hostname = nix::unistd::gethostname()
+                .map(|hostname| hostname.to_string_lossy().into_owned())
+                .unwrap_or_default();
+            self.lease_owner = format!("{hostname}:{db_path}");
+        }
         self.db_path = db_path;
         tracing::trace!("Registered {} (full path: {})", self.db_name, self.db_path);
     }
 
     // Returns the next free frame number for the replicated log
-    fn next_frame(&mut self) -> u32 {
+    fn next_frame(&mut self) -> FrameNo {
         self.next_frame += 1;
         self.next_frame - 1
     }
 
     // Returns the current last valid frame in the replicated log
-    pub fn peek_last_valid_frame(&self) -> u32 {
+    pub fn peek_last_valid_frame(&self) -> FrameNo {
         self.next_frame.saturating_sub(1)
     }
 
     // Sets the last valid frame in the replicated log.
-    pub fn register_last_valid_frame(&mut self, frame: u32) {
+    pub fn register_last_valid_frame(&mut self, frame: FrameNo) {
         if frame != self.peek_last_valid_frame() {
             if self.next_frame != 1 {
                 tracing::error!(
@@ -207,8 +526,29 @@ impl Replicator {
         }
     }
 
+    // Returns the number of bytes of uncommitted pages currently buffered in memory
+    pub fn buffered_bytes(&self) -> usize {
+        self.buffered_bytes
+    }
+
+    // Checks that `bytes` more bytes of uncommitted pages can be buffered without exceeding
+    // the limit, so that a batch of pages can be rejected before any of it is buffered
+    pub fn check_buffer_limit(&self, bytes: usize) -> Result<()> {
+        if let Some(max_buffered_bytes) = self.max_buffered_bytes {
+            if self.buffered_bytes + bytes > max_buffered_bytes {
+                return Err(anyhow::anyhow!(
+                    "Uncommitted pages would take {} bytes, which exceeds the limit of {} bytes",
+                    self.buffered_bytes + bytes,
+                    max_buffered_bytes
+                ));
+            }
+        }
+        Ok(())
+    }
+
     // Writes pages to a local in-memory buffer
-    pub fn write(&mut self, pgno: u32, data: &[u8]) {
+    pub fn write(&mut self, pgno: u32, data: &[u8]) -> Result<()> {
+        self.check_buffer_limit(data.len())?;
         let frame = self.next_frame();
         let mut crc = CRC_64.digest_with_initial(self.last_frame_crc);
         crc.update(data);
@@ -223,16 +563,28 @@ impl Replicator {
         let mut bytes = BytesMut::new();
         bytes.extend_from_slice(data);
         self.write_buffer.insert(frame, Frame { pgno, bytes, crc });
+        self.buffered_bytes += data.len();
         self.last_frame_crc = crc;
+        Ok(())
     }
 
     // Sends pages participating in current transaction to S3.
     // Returns the frame number holding the last flushed page.
-    pub async fn flush(&mut self) -> Result<u32> {
+    #[tracing::instrument(
+        level = "debug",
+        skip(self),
+        fields(generation = %self.generation, first_frame, last_frame, bytes)
+    )]
+    pub async fn flush(&mut self) -> Result<FrameNo> {
         if self.write_buffer.is_empty() {
             tracing::trace!("Attempting to flush an empty buffer");
             return Ok(0);
         }
+        let span = tracing::Span::current();
+        span.record("first_frame", self.write_buffer.keys().next());
+        span.record("last_frame", self.write_buffer.keys().next_back());
+        span.record("bytes", self.buffered_bytes);
+        self.check_circuit_breaker().await?;
         tracing::trace!("Flushing {} frames", self.write_buffer.len());
         self.commits_in_current_generation += 1;
         let mut tasks = vec![];
@@ -240,6 +592,8 @@ impl Replicator {
         const CONCURRENCY: usize = 64;
         let last_frame_in_transaction_crc = self.write_buffer.iter().last().unwrap().1.crc;
         let write_buffer = std::mem::take(&mut self.write_buffer);
+        self.buffered_bytes = 0;
+        let mut manifest_frames = Vec::with_capacity(write_buffer.len());
         for (frame, Frame { pgno, bytes, crc }) in write_buffer.into_iter() {
             let data = bytes;
             if data.len() != self.page_size {
@@ -247,69 +601,399 @@ impl Replicator {
             }
 
             let key = format!(
-                "{}-{}/{:012}-{:012}-{:016x}",
+                "{}-{}/{:020}-{:012}-{:016x}",
                 self.db_name, self.generation, frame, pgno, crc
             );
 
-            let body: ByteStream = if self.use_compression {
+            let body: Bytes = if self.use_compression {
                 let mut compressor = async_compression::tokio::bufread::GzipEncoder::new(&data[..]);
                 let mut compressed: Vec<u8> = Vec::with_capacity(self.page_size);
                 tokio::io::copy(&mut compressor, &mut compressed).await?;
                 tracing::trace!("Flushing {} (compressed size: {})", key, compressed.len());
-                ByteStream::from(compressed)
+                if self.verify_compression {
+                    Self::verify_compressed_page(&key, &compressed, &data).await?;
+                }
+                Bytes::from(compressed)
             } else {
-                ByteStream::from(data.freeze())
+                data.freeze()
             };
 
-            tasks.push(
-                self.client
-                    .put_object()
-                    .bucket(&self.bucket)
-                    .key(key)
-                    .body(body)
-                    .send(),
-            );
+            let checksum = format!("{:016x}", CRC_64.checksum(&body));
+            if self.manifest.is_some() {
+                manifest_frames.push(ManifestFrame {
+                    key: key.clone(),
+                    frameno: frame,
+                    pgno: pgno as i32,
+                    crc,
+                    size: body.len() as u64,
+                    checksum: checksum.clone(),
+                });
+            }
+            let compression = if self.use_compression {
+                GZIP_COMPRESSION
+            } else {
+                NO_COMPRESSION
+            };
+            let mut request = self
+                .client
+                .put_object()
+                .bucket(&self.bucket)
+                .key(key)
+                .metadata(COMPRESSION_METADATA_KEY, compression);
+            if self.object_checksums {
+                request = request.metadata(CHECKSUM_METADATA_KEY, checksum);
+            }
+            tasks.push(request.body(ByteStream::from(body)).send());
             if tasks.len() >= CONCURRENCY {
-                futures::future::try_join_all(std::mem::take(&mut tasks)).await?;
+                let result = futures::future::try_join_all(std::mem::take(&mut tasks)).await;
+                self.record_s3_result(&result);
+                result?;
                 tasks.clear();
             }
         }
         if !tasks.is_empty() {
-            futures::future::try_join_all(tasks).await?;
+            let result = futures::future::try_join_all(tasks).await;
+            self.record_s3_result(&result);
+            result?;
         }
+        self.record_manifest_frames(manifest_frames);
         self.last_transaction_crc = last_frame_in_transaction_crc;
         tracing::trace!("Last transaction crc: {}", self.last_transaction_crc);
         Ok(self.next_frame - 1)
     }
 
+    // Records uploaded frames in the manifest of the current generation, if it's kept
+    fn record_manifest_frames(&mut self, frames: Vec<ManifestFrame>) {
+        let manifest = match self.manifest.as_mut() {
+            Some(manifest) => manifest,
+            None => return,
+        };
+        if !manifest.record(frames) {
+            tracing::info!(
+                "Generation {} holds more than {} frames, it will be restored without a manifest",
+                self.generation,
+                GenerationManifest::MAX_FRAMES
+            );
+            self.manifest = None;
+        }
+    }
+
+    // Decompresses given page in memory and checks that it matches its source
+    async fn verify_compressed_page(key: &str, compressed: &[u8], expected: &[u8]) -> Result<()> {
+        let mut decompressor = async_compression::tokio::bufread::GzipDecoder::new(compressed);
+        let mut decompressed: Vec<u8> = Vec::with_capacity(expected.len());
+        tokio::io::copy(&mut decompressor, &mut decompressed).await?;
+        if decompressed.len() != expected.len() {
+            return Err(anyhow::anyhow!(
+                "Compression verification failed for {}: decompressed size {} != {} (expected)",
+                key,
+                decompressed.len(),
+                expected.len()
+            ));
+        }
+        if decompressed != expected {
+            return Err(anyhow::anyhow!(
+                "Compression verification failed for {}: decompressed page differs from the source",
+                key
+            ));
+        }
+        Ok(())
+    }
+
     // Marks all recently flushed pages as committed and updates the frame number
     // holding the newest consistent committed transaction.
-    pub async fn finalize_commit(&mut self, last_frame: u32, checksum: [u32; 2]) -> Result<()> {
+    #[tracing::instrument(level = "debug", skip(self, checksum), fields(generation = %self.generation))]
+    pub async fn finalize_commit(&mut self, last_frame: FrameNo, checksum: [u32; 2]) -> Result<()> {
         // Last consistent frame is persisted in S3 in order to be able to recover
         // from failured that happen in the middle of a commit, when only some
         // of the pages that belong to a transaction are replicated.
-        let last_consistent_frame_key = format!("{}-{}/.consistent", self.db_name, self.generation);
+        // A stale flush, e.g. from a restarted task, must not move the consistent frame backward.
+        // The stored frame is only read from S3 the first time a reused generation is committed to.
+        let stored_frame = match self.last_committed_frame {
+            Some(frame) => frame,
+            None => self.get_last_consistent_frame(&self.generation).await?.0,
+        };
+        Self::check_consistent_frame(stored_frame, last_frame)?;
+        // The transaction is committed locally by now, so it's kept to be finalized again
+        // by drain if this attempt fails
+        self.pending_commit = Some((last_frame, checksum));
+        self.check_circuit_breaker().await?;
         tracing::trace!("Finalizing frame: {}, checksum: {:?}", last_frame, checksum);
-        // Information kept in this entry: [last consistent frame number: 4 bytes][last checksum: 8 bytes]
-        let mut consistent_info = BytesMut::with_capacity(12);
+        self.put_consistent_info(last_frame, checksum, self.last_transaction_crc)
+            .await?;
+        self.pending_commit = None;
+        if let Some(manifest) = self.manifest.as_mut() {
+            manifest.last_frame = last_frame;
+        }
+        tracing::trace!("Commit successful");
+        Ok(())
+    }
+
+    // Stores the last consistent frame of the current generation, which also marks the
+    // generation as complete
+    async fn put_consistent_info(
+        &mut self,
+        last_frame: FrameNo,
+        checksum: [u32; 2],
+        frame_crc: u64,
+    ) -> Result<()> {
+        let last_consistent_frame_key = format!("{}-{}/.consistent", self.db_name, self.generation);
+        // Information kept in this entry:
+        // [layout version: 1 byte][last consistent frame number: 8 bytes][last checksum: 8 bytes]
+        // [CRC-64 of the last consistent frame: 8 bytes]
+        // Version 2 has no CRC. Generations created before frame numbers were widened store
+        // a 4-byte frame number, and no version.
+        let mut consistent_info = BytesMut::with_capacity(25);
+        consistent_info.extend_from_slice(&[Self::CONSISTENT_INFO_VERSION]);
         consistent_info.extend_from_slice(&last_frame.to_be_bytes());
         consistent_info.extend_from_slice(&checksum[0].to_be_bytes());
         consistent_info.extend_from_slice(&checksum[1].to_be_bytes());
-        self.client
+        consistent_info.extend_from_slice(&frame_crc.to_be_bytes());
+        let result = self
+            .client
             .put_object()
             .bucket(&self.bucket)
             .key(last_consistent_frame_key)
             .body(ByteStream::from(Bytes::from(consistent_info)))
             .send()
+            .await;
+        self.record_s3_result(&result);
+        result?;
+        self.last_committed_frame = Some(last_frame);
+        Ok(())
+    }
+
+    // Lists the frames stored in given generation and returns the ranges of frames, up to
+    // the last consistent one, that are missing from it. Those would fail a restore.
+    pub async fn find_frame_gaps(
+        &self,
+        generation: &uuid::Uuid,
+    ) -> Result<Vec<RangeInclusive<FrameNo>>> {
+        let (last_consistent_frame, _) = self.get_last_consistent_frame(generation).await?;
+        let prefix = format!("{}-{}/", self.db_name, generation);
+        let mut frames = Vec::new();
+        let mut next_marker = None;
+        loop {
+            let mut list_request = self.list_objects().prefix(&prefix);
+            if let Some(marker) = next_marker {
+                list_request = list_request.marker(marker);
+            }
+            let response = list_request.send().await?;
+            let objs = response.contents().unwrap_or_default();
+            frames.extend(
+                objs.iter()
+                    .filter_map(|obj| obj.key())
+                    .filter_map(Self::parse_frame_page_crc)
+                    .map(|(frameno, _, _)| frameno),
+            );
+            next_marker = response
+                .is_truncated()
+                .then(|| objs.last().map(|elem| elem.key().unwrap().to_string()))
+                .flatten();
+            if next_marker.is_none() {
+                break;
+            }
+        }
+        Ok(frame_gaps(frames, last_consistent_frame))
+    }
+
+    // Rejects finalizing a frame older than the last consistent frame already stored
+    fn check_consistent_frame(stored_frame: FrameNo, last_frame: FrameNo) -> Result<()> {
+        if last_frame < stored_frame {
+            return Err(anyhow::anyhow!(
+                "Refusing to move the last consistent frame backward from {} to {}",
+                stored_frame,
+                last_frame
+            ));
+        }
+        Ok(())
+    }
+
+    // The lease is kept next to the generations, but outside of the `{db_name}-` prefix
+    // used to look them up
+    fn lease_key(&self) -> String {
+        format!("{}.lease", self.db_name)
+    }
+
+    // Logical backups are kept next to the generations, but outside of the `{db_name}-` prefix
+    // used to look them up, so that restore never reads them
+    fn logical_backup_key(&self, key: &str) -> String {
+        format!("{}.logical/{}", self.db_name, key)
+    }
+
+    // Uploads a logical backup of the database, e.g. a compressed SQL dump, from given file.
+    // Its manifest is stored next to it, as `<key>.manifest`.
+    pub async fn upload_logical_backup(
+        &self,
+        key: &str,
+        path: &Path,
+        manifest: Bytes,
+    ) -> Result<()> {
+        let key = self.logical_backup_key(key);
+        self.client
+            .put_object()
+            .bucket(&self.bucket)
+            .key(&key)
+            .body(ByteStream::from_path(path).await?)
+            .send()
             .await?;
-        tracing::trace!("Commit successful");
+        self.client
+            .put_object()
+            .bucket(&self.bucket)
+            .key(format!("{key}.manifest"))
+            .body(ByteStream::from(manifest))
+            .send()
+            .await?;
+        tracing::info!("Uploaded logical backup {}", key);
+        Ok(())
+    }
+
+    // Returns the owner and expiration time of the current lease, if any
+    async fn read_lease(&self) -> Result<Option<(String, u64)>> {
+        read_lease(&self.client, &self.bucket, self.lease_key()).await
+    }
+
+    // Takes the lease on this database, so that no other instance replicates it at the same
+    // time. Fails if another instance holds a lease that hasn't expired yet.
+    // NOTICE: S3 has no compare-and-swap, so two instances starting at the very same time
+    // can still both take the lease. This is a guard against mistakes, not a lock.
+    // The lease is extended every third of its time to live, for as long as it's held, whether
+    // or not anything is committed meanwhile.
+    pub async fn acquire_lease(&mut self) -> Result<()> {
+        let lease_ttl = match self.lease_ttl {
+            Some(lease_ttl) => lease_ttl,
+            None => return Ok(()),
+        };
+        let lease = Lease {
+            client: self.client.clone(),
+            bucket: self.bucket.clone(),
+            key: self.lease_key(),
+            owner: self.lease_owner.clone(),
+            ttl: lease_ttl,
+        };
+        lease.write().await?;
+        tracing::debug!("Lease on {} taken by {}", self.db_name, self.lease_owner);
+        if self.lease_refresher.is_none() {
+            self.lease_refresher = Some(tokio::spawn(lease.refresh()));
+        }
+        Ok(())
+    }
+
+    // Gives up the lease, if it's held by this instance
+    pub async fn release_lease(&mut self) -> Result<()> {
+        match self.lease_refresher.take() {
+            Some(refresher) => refresher.abort(),
+            None => return Ok(()),
+        }
+        if let Some((owner, _)) = self.read_lease().await? {
+            if owner == self.lease_owner {
+                self.client
+                    .delete_object()
+                    .bucket(&self.bucket)
+                    .key(self.lease_key())
+                    .send()
+                    .await?;
+                tracing::debug!("Lease on {} released", self.db_name);
+            }
+        }
         Ok(())
     }
 
+    // Fails fast if the circuit breaker is open. Once its cooldown passes,
+    // probes the bucket and only lets the call through if the probe succeeds.
+    async fn check_circuit_breaker(&mut self) -> Result<()> {
+        let state = match &self.circuit_breaker {
+            Some(breaker) => breaker.state(Instant::now()),
+            None => return Ok(()),
+        };
+        match state {
+            CircuitBreakerState::Closed => Ok(()),
+            CircuitBreakerState::Open => Err(anyhow::anyhow!(
+                "S3 circuit breaker is open, not attempting the request"
+            )),
+            CircuitBreakerState::HalfOpen => {
+                tracing::debug!(
+                    "Probing bucket {} before closing the circuit breaker",
+                    self.bucket
+                );
+                let result = self.client.head_bucket().bucket(&self.bucket).send().await;
+                self.record_s3_result(&result);
+                result?;
+                Ok(())
+            }
+        }
+    }
+
+    fn record_s3_result<T, E>(&mut self, result: &std::result::Result<T, E>) {
+        if let Some(breaker) = &mut self.circuit_breaker {
+            match result {
+                Ok(_) => breaker.on_success(),
+                Err(_) => breaker.on_failure(Instant::now()),
+            }
+        }
+    }
+
+    // Returns the state of the S3 circuit breaker, or None if it is disabled
+    pub fn circuit_breaker_state(&self) -> Option<CircuitBreakerState> {
+        self.circuit_breaker
+            .as_ref()
+            .map(|breaker| breaker.state(Instant::now()))
+    }
+
+    // Finalizes the last commit, if replicating it failed, so that nothing committed is lost
+    // when the replicator is dropped. Returns the last committed frame, or an error if it isn't
+    // replicated within given timeout.
+    pub async fn drain(&mut self, timeout: Duration) -> Result<FrameNo> {
+        if let Some((last_frame, checksum)) = self.pending_commit {
+            tracing::info!(
+                "Draining frames of {} up to frame {}",
+                self.db_name,
+                last_frame
+            );
+            tokio::time::timeout(timeout, self.finalize_commit(last_frame, checksum))
+                .await
+                .map_err(|_| {
+                    anyhow::anyhow!(
+                        "Timed out after {:?} draining frames of {} up to frame {}",
+                        timeout,
+                        self.db_name,
+                        last_frame
+                    )
+                })??;
+        }
+        // Whatever is left in the buffer belongs to a transaction that is still in progress
+        Ok(match self.write_buffer.keys().next() {
+            Some(first_uncommitted) => first_uncommitted - 1,
+            None => self.peek_last_valid_frame(),
+        })
+    }
+
+    // Checks if enough frames were replicated in the current generation to take a new snapshot
+    pub fn snapshot_due(&self) -> bool {
+        Self::generation_frame_limit_reached(
+            self.peek_last_valid_frame(),
+            self.max_frames_per_generation,
+        )
+    }
+
+    fn generation_frame_limit_reached(frames: FrameNo, limit: Option<FrameNo>) -> bool {
+        matches!(limit, Some(limit) if frames >= limit)
+    }
+
+    // Returns the network and disk timings of the last restore
+    pub fn restore_stats(&self) -> RestoreStats {
+        self.restore_stats
+    }
+
     // Drops uncommitted frames newer than given last valid frame
-    pub fn rollback_to_frame(&mut self, last_valid_frame: u32) {
+    pub fn rollback_to_frame(&mut self, last_valid_frame: FrameNo) {
         // NOTICE: O(size), can be optimized to O(removed) if ever needed
         self.write_buffer.retain(|&k, _| k <= last_valid_frame);
+        self.buffered_bytes = self
+            .write_buffer
+            .values()
+            .map(|frame| frame.bytes.len())
+            .sum();
         self.next_frame = last_valid_frame + 1;
         self.last_frame_crc = self
             .write_buffer
@@ -334,26 +1018,67 @@ impl Replicator {
         Ok(counter)
     }
 
+    // Tries to read the user version from the given database file
+    async fn read_user_version(reader: &mut tokio::fs::File) -> Result<u32> {
+        use tokio::io::{AsyncReadExt, AsyncSeekExt};
+        reader.seek(std::io::SeekFrom::Start(60)).await?;
+        Ok(reader.read_u32().await?)
+    }
+
     // Tries to read the local page size from the given database file
     async fn read_page_size(reader: &mut tokio::fs::File) -> Result<usize> {
         use tokio::io::{AsyncReadExt, AsyncSeekExt};
         reader.seek(std::io::SeekFrom::Start(16)).await?;
         let page_size = reader.read_u16().await?;
-        if page_size == 1 {
-            Ok(65536)
-        } else {
-            Ok(page_size as usize)
+        Self::page_size_from_header(page_size)
+    }
+
+    // Checks that the page size from the WAL header matches the main database file one
+    fn check_wal_page_size(wal_page_size: u32, db_page_size: usize) -> Result<()> {
+        if wal_page_size as usize != db_page_size {
+            return Err(anyhow::anyhow!(
+                "Local WAL page size {} does not match the main database file page size {}, refusing to replicate it",
+                wal_page_size,
+                db_page_size
+            ));
+        }
+        Ok(())
+    }
+
+    // Checks that the page size from the main database file header matches the one in use
+    fn check_db_page_size(db_page_size: usize, page_size: usize) -> Result<()> {
+        if db_page_size != page_size {
+            return Err(anyhow::anyhow!(
+                "Main database file page size {} does not match the replicated page size {}, refusing to replicate the local WAL",
+                db_page_size,
+                page_size
+            ));
         }
+        Ok(())
+    }
+
+    // Decodes the page size stored in the database header, where 65536 is encoded as 1
+    fn page_size_from_header(page_size: u16) -> Result<usize> {
+        let page_size = if page_size == 1 {
+            65536
+        } else {
+            page_size as usize
+        };
+        Self::validate_page_size(page_size)?;
+        Ok(page_size)
     }
 
     // Returns the compressed database file path and its change counter, extracted
     // from the header of page1 at offset 24..27 (as per SQLite documentation).
-    pub async fn compress_main_db_file(&self) -> Result<(&'static str, [u8; 4])> {
+    pub async fn compress_main_db_file(&self) -> Result<(PathBuf, [u8; 4])> {
         use tokio::io::AsyncWriteExt;
-        let compressed_db = "db.gz";
+        let compressed_db = self.compressed_db_path();
         let mut reader = tokio::fs::File::open(&self.db_path).await?;
+        // Compressed output is not expected to be larger than the db file itself
+        let db_size = reader.metadata().await?.len();
+        Self::check_free_space(compressed_db.parent().unwrap_or(Path::new(".")), db_size)?;
         let mut writer = async_compression::tokio::write::GzipEncoder::new(
-            tokio::fs::File::create(compressed_db).await?,
+            tokio::fs::File::create(&compressed_db).await?,
         );
         tokio::io::copy(&mut reader, &mut writer).await?;
         writer.shutdown().await?;
@@ -361,6 +1086,35 @@ impl Replicator {
         Ok((compressed_db, change_counter))
     }
 
+    // Path of the temporary file holding the compressed snapshot of the main db file
+    fn compressed_db_path(&self) -> PathBuf {
+        let db_path = Path::new(&self.db_path);
+        let dir = match &self.temp_dir {
+            Some(dir) => dir.as_path(),
+            None => db_path.parent().unwrap_or(Path::new(".")),
+        };
+        let file_name = db_path
+            .file_name()
+            .map(|name| name.to_string_lossy())
+            .unwrap_or_else(|| "db".into());
+        dir.join(format!("{file_name}.bottomless.gz"))
+    }
+
+    // Fails if the filesystem of `dir` does not have `required` bytes available
+    fn check_free_space(dir: &Path, required: u64) -> Result<()> {
+        let stat = nix::sys::statvfs::statvfs(dir)?;
+        let available = stat.blocks_available() as u64 * stat.fragment_size() as u64;
+        if available < required {
+            return Err(anyhow::anyhow!(
+                "Not enough space in {} to compress the main db file: {} bytes required, {} available",
+                dir.display(),
+                required,
+                available
+            ));
+        }
+        Ok(())
+    }
+
     // Replicates local WAL pages to S3, if local WAL is present.
     // This function is called under the assumption that if local WAL
     // file is present, it was already detected to be newer than its
@@ -387,6 +1141,18 @@ impl Replicator {
             return Ok(());
         }
 
+        // Frames are split into pages of the main database file, so its header has to agree
+        // with the page size. An empty file has no header yet, all its pages are in the WAL.
+        if self.main_db_exists_and_not_empty().await {
+            let mut db = tokio::fs::File::open(&self.db_path).await?;
+            Self::check_db_page_size(Self::read_page_size(&mut db).await?, self.page_size)?;
+        }
+
+        // Page size is stored in WAL file at offset [8-12), and frames can only be
+        // split into pages if it matches the one from the main database file header
+        wal_file.seek(tokio::io::SeekFrom::Start(8)).await?;
+        Self::check_wal_page_size(wal_file.read_u32().await?, self.page_size)?;
+
         tracing::trace!("Local WAL pages: {}", (len - 32) / self.page_size as u64);
         wal_file.seek(tokio::io::SeekFrom::Start(24)).await?;
         let checksum: [u32; 2] = [wal_file.read_u32().await?, wal_file.read_u32().await?];
@@ -402,7 +1168,7 @@ impl Replicator {
                 .await?;
             let mut data = vec![0u8; self.page_size];
             wal_file.read_exact(&mut data).await?;
-            self.write(pgno, &data);
+            self.write(pgno, &data)?;
             // In multi-page transactions, only the last page in the transaction contains
             // the size_after_transaction field. If it's zero, it means it's an uncommited
             // page.
@@ -417,6 +1183,7 @@ impl Replicator {
             tracing::warn!("Uncommited WAL entries: {}", self.write_buffer.len());
         }
         self.write_buffer.clear();
+        self.buffered_bytes = 0;
         tracing::info!("Local WAL replicated");
         Ok(())
     }
@@ -436,6 +1203,7 @@ impl Replicator {
     // Sends the main database file to S3 - if -wal file is present, it's replicated
     // too - it means that the local file was detected to be newer than its remote
     // counterpart.
+    #[tracing::instrument(skip(self), fields(generation = %self.generation))]
     pub async fn snapshot_main_db_file(&mut self) -> Result<()> {
         if !self.main_db_exists_and_not_empty().await {
             tracing::debug!("Not snapshotting, the main db file does not exist or is empty");
@@ -452,9 +1220,16 @@ impl Replicator {
                 .put_object()
                 .bucket(&self.bucket)
                 .key(key)
-                .body(ByteStream::from_path(compressed_db_path).await?)
+                .body(ByteStream::from_path(&compressed_db_path).await?)
                 .send()
                 .await?;
+            if let Err(e) = tokio::fs::remove_file(&compressed_db_path).await {
+                tracing::warn!(
+                    "Failed to remove the compressed snapshot {}: {}",
+                    compressed_db_path.display(),
+                    e
+                );
+            }
             change_counter
         } else {
             self.client
@@ -482,6 +1257,8 @@ impl Replicator {
             .body(ByteStream::from(Bytes::copy_from_slice(&change_counter)))
             .send()
             .await?;
+        // The snapshot alone can be restored from, before any frame is committed on top of it
+        self.put_consistent_info(0, [0, 0], 0).await?;
         tracing::debug!("Main db snapshot complete");
         Ok(())
     }
@@ -509,45 +1286,396 @@ impl Replicator {
         uuid::Uuid::parse_str(key).ok()
     }
 
-    // Tries to fetch the remote database change counter from given generation
-    pub async fn get_remote_change_counter(&self, generation: &uuid::Uuid) -> Result<[u8; 4]> {
-        use bytes::Buf;
-        let mut remote_change_counter = [0u8; 4];
-        if let Ok(response) = self
-            .get_object(format!("{}-{}/.changecounter", self.db_name, generation))
+    // Parses the generation out of a `<db-name>-<generation-uuid>/` common prefix.
+    fn parse_generation_prefix(db_name: &str, prefix: &str) -> Option<uuid::Uuid> {
+        let generation = prefix
+            .strip_prefix(db_name)?
+            .strip_prefix('-')?
+            .strip_suffix('/')?;
+        uuid::Uuid::parse_str(generation).ok()
+    }
+
+    // Checks if given generation can be safely restored from. A generation is complete once
+    // it has a valid .consistent marker, written after its snapshot or a commit is fully
+    // uploaded. Errors other than a missing marker are returned, not taken for incompleteness.
+    async fn is_generation_complete(&self, generation: &uuid::Uuid) -> Result<bool> {
+        match self
+            .get_object(format!("{}-{}/.consistent", self.db_name, generation))
             .send()
             .await
         {
-            response
-                .body
-                .collect()
-                .await?
-                .copy_to_slice(&mut remote_change_counter)
+            Ok(response) => {
+                let mut data = response.body.collect().await?;
+                Ok(Self::parse_consistent_info(&mut data).is_ok())
+            }
+            Err(SdkError::ServiceError(err)) if err.err().is_no_such_key() => Ok(false),
+            Err(e) => Err(e.into()),
         }
-        Ok(remote_change_counter)
     }
 
-    // Tries to fetch the last consistent frame number stored in the remote generation
-    pub async fn get_last_consistent_frame(&self, generation: &uuid::Uuid) -> Result<(u32, u64)> {
-        use bytes::Buf;
-        Ok(
-            match self
-                .get_object(format!("{}-{}/.consistent", self.db_name, generation))
-                .send()
-                .await
-                .ok()
-            {
-                Some(response) => {
-                    let mut collected = response.body.collect().await?;
-                    (collected.get_u32(), collected.get_u64())
+    // Returns newest generation which is complete, or None, if one is not found.
+    pub async fn find_newest_complete_generation(&self) -> Result<Option<uuid::Uuid>> {
+        let mut next_marker = None;
+        loop {
+            let mut list_request = self
+                .list_objects()
+                .set_delimiter(Some("/".to_string()))
+                .prefix(format!("{}-", self.db_name));
+            if let Some(marker) = next_marker {
+                list_request = list_request.marker(marker);
+            }
+
+            let response = list_request.send().await?;
+            // Generations are listed newest first
+            for prefix in response.common_prefixes().unwrap_or_default() {
+                let generation = match prefix
+                    .prefix()
+                    .and_then(|prefix| Self::parse_generation_prefix(&self.db_name, prefix))
+                {
+                    Some(generation) => generation,
+                    None => continue,
+                };
+                if self.is_generation_complete(&generation).await? {
+                    return Ok(Some(generation));
                 }
-                None => (0, 0),
-            },
-        )
+                tracing::debug!("Skipping incomplete generation {}", generation);
+            }
+
+            next_marker = response.next_marker().map(|s| s.to_owned());
+            if next_marker.is_none() {
+                return Ok(None);
+            }
+        }
+    }
+
+    fn generation_label_key(&self, generation: &uuid::Uuid) -> String {
+        format!("{}-{}/.label", self.db_name, generation)
+    }
+
+    // Attaches a user-supplied label to given generation, replacing the previous one
+    pub async fn label_generation(&self, generation: &uuid::Uuid, label: &str) -> Result<()> {
+        let label = label.trim();
+        if label.is_empty() {
+            return Err(anyhow::anyhow!("Generation label cannot be empty"));
+        }
+        self.client
+            .put_object()
+            .bucket(&self.bucket)
+            .key(self.generation_label_key(generation))
+            .body(ByteStream::from(Bytes::copy_from_slice(label.as_bytes())))
+            .send()
+            .await?;
+        tracing::info!("Generation {} labeled as {:?}", generation, label);
+        Ok(())
+    }
+
+    // Returns the label of given generation, or None, if it's not labeled
+    pub async fn get_generation_label(&self, generation: &uuid::Uuid) -> Result<Option<String>> {
+        match self
+            .get_object(self.generation_label_key(generation))
+            .send()
+            .await
+        {
+            Ok(response) => {
+                let data = response.body.collect().await?.into_bytes();
+                Ok(Some(String::from_utf8_lossy(&data).into_owned()))
+            }
+            Err(SdkError::ServiceError(err)) if err.err().is_no_such_key() => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    // Removes the label of given generation, if any
+    pub async fn remove_generation_label(&self, generation: &uuid::Uuid) -> Result<()> {
+        self.client
+            .delete_object()
+            .bucket(&self.bucket)
+            .key(self.generation_label_key(generation))
+            .send()
+            .await?;
+        tracing::info!("Label removed from generation {}", generation);
+        Ok(())
+    }
+
+    fn generation_manifest_key(&self, generation: &uuid::Uuid) -> String {
+        format!("{}-{}/manifest.json", self.db_name, generation)
+    }
+
+    // Writes the manifest of the current generation, once no more frames are going to be
+    // uploaded to it. Generations without a manifest are restored by listing them.
+    pub async fn finalize_generation(&mut self) -> Result<()> {
+        let manifest = match self.manifest.take() {
+            Some(manifest) => manifest,
+            None => {
+                tracing::debug!(
+                    "Generation {} was not started in this session, not writing its manifest",
+                    self.generation
+                );
+                return Ok(());
+            }
+        };
+        self.check_circuit_breaker().await?;
+        let body = serde_json::to_vec(&manifest)?;
+        let result = self
+            .client
+            .put_object()
+            .bucket(&self.bucket)
+            .key(self.generation_manifest_key(&self.generation))
+            .body(ByteStream::from(Bytes::from(body)))
+            .send()
+            .await;
+        self.record_s3_result(&result);
+        result?;
+        tracing::debug!(
+            "Generation {} finalized with {} frames, up to frame {}",
+            self.generation,
+            manifest.frames.len(),
+            manifest.last_frame
+        );
+        Ok(())
+    }
+
+    // Returns the manifest of given generation, or None, if it was never finalized
+    // or predates manifests
+    pub async fn get_generation_manifest(
+        &self,
+        generation: &uuid::Uuid,
+    ) -> Result<Option<GenerationManifest>> {
+        match self
+            .get_object(self.generation_manifest_key(generation))
+            .send()
+            .await
+        {
+            Ok(response) => {
+                let data = response.body.collect().await?.into_bytes();
+                let manifest = serde_json::from_slice(&data).map_err(|e| {
+                    anyhow::anyhow!("Invalid manifest of generation {}: {}", generation, e)
+                })?;
+                Ok(Some(manifest))
+            }
+            Err(SdkError::ServiceError(err)) if err.err().is_no_such_key() => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    // Lists the frames stored in given generation, up to the last consistent frame
+    // Returns the frames of given generation up to its last consistent frame, in order
+    async fn generation_restore_frames(
+        &self,
+        generation: &uuid::Uuid,
+        last_consistent_frame: FrameNo,
+    ) -> Result<Vec<ListedFrame>> {
+        // A finalized generation is restored from its manifest, which also tells the expected
+        // size and checksum of every frame object
+        let manifest_frames = self
+            .get_generation_manifest(generation)
+            .await?
+            .and_then(|manifest| manifest.restore_frames(last_consistent_frame));
+        let listed_frames = match manifest_frames {
+            Some(frames) => {
+                tracing::debug!("Restoring generation {} from its manifest", generation);
+                frames
+            }
+            None => {
+                self.list_restore_frames(generation, last_consistent_frame)
+                    .await?
+            }
+        };
+        order_restore_frames(listed_frames, last_consistent_frame)
+    }
+
+    async fn list_restore_frames(
+        &self,
+        generation: &uuid::Uuid,
+        last_consistent_frame: FrameNo,
+    ) -> Result<Vec<ListedFrame>> {
+        let mut next_marker = None;
+        let prefix = format!("{}-{}/", self.db_name, generation);
+        // Newest frame seen so far, used to detect an incomplete listing
+        let mut last_listed_frame = 0;
+        let mut listed_any = false;
+        let mut list_retries = 0;
+        // Frames are collected from all pages first, and only applied once sorted, since the
+        // listing order cannot be trusted to be the frame order
+        let mut listed_frames = Vec::new();
+        loop {
+            let mut list_request = self.list_objects().prefix(&prefix);
+            if let Some(marker) = next_marker {
+                list_request = list_request.marker(marker);
+            }
+            let response = list_request.send().await?;
+            let objs = response.contents().unwrap_or_default();
+            if objs.is_empty() && !listed_any {
+                tracing::debug!("No objects found in generation {}", generation);
+            }
+            for obj in objs {
+                let key = obj
+                    .key()
+                    .ok_or_else(|| anyhow::anyhow!("Failed to get key for an object"))?;
+                listed_any = true;
+                let (frameno, pgno, crc) = match Self::parse_frame_page_crc(key) {
+                    Some(result) => result,
+                    None => {
+                        if !key.ends_with(".gz")
+                            && !key.ends_with(".db")
+                            && !key.ends_with(".consistent")
+                            && !key.ends_with(".changecounter")
+                            && !key.ends_with(".label")
+                            && !key.ends_with("manifest.json")
+                        {
+                            tracing::warn!("Failed to parse frame/page from key {}", key);
+                        }
+                        continue;
+                    }
+                };
+                last_listed_frame = last_listed_frame.max(frameno);
+                if frameno > last_consistent_frame {
+                    tracing::warn!("Remote log contains frame {} larger than last consistent frame ({}), skipping it",
+                                frameno, last_consistent_frame);
+                    continue;
+                }
+                listed_frames.push(ListedFrame {
+                    frameno,
+                    pgno,
+                    crc,
+                    key: key.to_string(),
+                    size: None,
+                    checksum: None,
+                    last_modified: obj
+                        .last_modified()
+                        .map(|time| (time.secs(), time.subsec_nanos())),
+                });
+            }
+            next_marker = response
+                .is_truncated()
+                .then(|| objs.last().map(|elem| elem.key().unwrap().to_string()))
+                .flatten();
+            if next_marker.is_none() {
+                if last_listed_frame >= last_consistent_frame {
+                    break;
+                }
+                // Some S3-compatible stores do not list freshly written objects right away,
+                // so give the tail of the generation a chance to show up before giving up
+                if list_retries >= self.restore_options.list_retries {
+                    return Err(anyhow::anyhow!(
+                        "Generation {} is incomplete: last listed frame is {}, but the last consistent frame is {}",
+                        generation,
+                        last_listed_frame,
+                        last_consistent_frame
+                    ));
+                }
+                list_retries += 1;
+                tracing::warn!(
+                    "Listing of generation {} ended at frame {} before the last consistent frame {}, retrying ({}/{})",
+                    generation,
+                    last_listed_frame,
+                    last_consistent_frame,
+                    list_retries,
+                    self.restore_options.list_retries
+                );
+                tokio::time::sleep(Self::RESTORE_LIST_RETRY_DELAY).await;
+                // The listing starts over, since the missing frames may be anywhere in it, and
+                // its last key is rather the snapshot or the manifest, which sort after frames
+                last_listed_frame = 0;
+                listed_frames.clear();
+            }
+        }
+        Ok(listed_frames)
+    }
+
+    // Tries to fetch the remote database change counter from given generation
+    pub async fn get_remote_change_counter(&self, generation: &uuid::Uuid) -> Result<[u8; 4]> {
+        use bytes::Buf;
+        let mut remote_change_counter = [0u8; 4];
+        match self
+            .get_object(format!("{}-{}/.changecounter", self.db_name, generation))
+            .send()
+            .await
+        {
+            Ok(response) => response
+                .body
+                .collect()
+                .await?
+                .copy_to_slice(&mut remote_change_counter),
+            Err(SdkError::ServiceError(err)) if err.err().is_no_such_key() => (),
+            Err(e) => return Err(e.into()),
+        }
+        Ok(remote_change_counter)
+    }
+
+    // Fetches the last consistent frame number stored in the remote generation, or 0,
+    // if nothing was committed to it yet
+    pub async fn get_last_consistent_frame(
+        &self,
+        generation: &uuid::Uuid,
+    ) -> Result<(FrameNo, u64)> {
+        let info = self.get_consistent_info(generation).await?;
+        Ok((info.frame, info.checksum))
+    }
+
+    async fn get_consistent_info(&self, generation: &uuid::Uuid) -> Result<ConsistentInfo> {
+        match self
+            .get_object(format!("{}-{}/.consistent", self.db_name, generation))
+            .send()
+            .await
+        {
+            Ok(response) => {
+                let mut collected = response.body.collect().await?;
+                Self::parse_consistent_info(&mut collected)
+            }
+            Err(SdkError::ServiceError(err)) if err.err().is_no_such_key() => {
+                Ok(ConsistentInfo::default())
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    // Parses the contents of a .consistent object, in either the legacy 32-bit frame number
+    // format (12 bytes), the unversioned 64-bit one (16 bytes), or a versioned one.
+    fn parse_consistent_info(info: &mut impl bytes::Buf) -> Result<ConsistentInfo> {
+        match info.remaining() {
+            12 => {
+                return Ok(ConsistentInfo {
+                    frame: info.get_u32() as FrameNo,
+                    checksum: info.get_u64(),
+                    frame_crc: None,
+                })
+            }
+            16 => {
+                return Ok(ConsistentInfo {
+                    frame: info.get_u64(),
+                    checksum: info.get_u64(),
+                    frame_crc: None,
+                })
+            }
+            0 => return Err(anyhow::anyhow!("Empty .consistent object")),
+            _ => (),
+        }
+        match (info.get_u8(), info.remaining()) {
+            (2, 16) => Ok(ConsistentInfo {
+                frame: info.get_u64(),
+                checksum: info.get_u64(),
+                frame_crc: None,
+            }),
+            (3, 24) => Ok(ConsistentInfo {
+                frame: info.get_u64(),
+                checksum: info.get_u64(),
+                frame_crc: Some(info.get_u64()),
+            }),
+            (version, _) if version > Self::CONSISTENT_INFO_VERSION => Err(anyhow::anyhow!(
+                "Unsupported .consistent object version {}, written by a newer version of bottomless",
+                version
+            )),
+            (version, len) => Err(anyhow::anyhow!(
+                "Invalid .consistent object of version {} and size {}",
+                version,
+                len + 1
+            )),
+        }
     }
 
     // Returns the number of pages stored in the local WAL file, or 0, if there aren't any.
-    async fn get_local_wal_page_count(&mut self) -> u32 {
+    async fn get_local_wal_page_count(&mut self) -> FrameNo {
         use tokio::io::{AsyncReadExt, AsyncSeekExt};
         match tokio::fs::File::open(&format!("{}-wal", &self.db_path)).await {
             Ok(mut file) => {
@@ -569,7 +1697,7 @@ impl Replicator {
                         return 0;
                     }
                     // Each WAL file consists of a 32-byte WAL header and N entries of size (page size + 24)
-                    (len / (self.page_size + 24) as u64) as u32
+                    len / (self.page_size + 24) as u64
                 } else {
                     0
                 }
@@ -580,69 +1708,367 @@ impl Replicator {
 
     // Parses the frame and page number from given key.
     // Format: <db-name>-<generation>/<frame-number>-<page-number>-<crc64>
-    fn parse_frame_page_crc(key: &str) -> Option<(u32, i32, u64)> {
+    fn parse_frame_page_crc(key: &str) -> Option<(FrameNo, i32, u64)> {
         let checksum_delim = key.rfind('-')?;
         let page_delim = key[0..checksum_delim].rfind('-')?;
         let frame_delim = key[0..page_delim].rfind('/')?;
-        let frameno = key[frame_delim + 1..page_delim].parse::<u32>().ok()?;
+        let frameno = key[frame_delim + 1..page_delim].parse::<FrameNo>().ok()?;
         let pgno = key[page_delim + 1..checksum_delim].parse::<i32>().ok()?;
         let crc = u64::from_str_radix(&key[checksum_delim + 1..], 16).ok()?;
         tracing::debug!(frameno, pgno, crc);
         Some((frameno, pgno, crc))
     }
 
+    // Computes the CRC of a frame chained after the frame with `prev_crc`
+    fn expected_frame_crc(prev_crc: u64, page: &[u8]) -> u64 {
+        let mut crc = CRC_64.digest_with_initial(prev_crc);
+        crc.update(page);
+        crc.finalize()
+    }
+
+    // Checks the CRC of a frame against its predecessor
+    fn frame_crc_matches(crc: u64, prev_crc: u64, page: &[u8]) -> bool {
+        crc == Self::expected_frame_crc(prev_crc, page)
+    }
+
+    #[allow(clippy::too_many_arguments)]
     async fn restore_frame(
         &mut self,
         pgno: i32,
         crc: u64,
         prev_crc: u64,
+        restored_crc: &mut u64,
         page_buffer: &mut Vec<u8>,
         main_db_writer: &mut (impl tokio::io::AsyncWriteExt
                   + tokio::io::AsyncSeekExt
                   + std::marker::Unpin),
         reader: &mut (impl tokio::io::AsyncRead + std::marker::Unpin),
+        stats: &mut RestoreStats,
     ) -> Result<()> {
-        // If page size is unknown *or* crc verification is performed,
-        // a page needs to be loaded to memory first
-        if self.verify_crc || self.page_size == Self::UNSET_PAGE_SIZE {
-            let page_size = tokio::io::copy(reader, page_buffer).await?;
-            if self.verify_crc {
-                let mut expected_crc = CRC_64.digest_with_initial(prev_crc);
-                expected_crc.update(page_buffer);
-                let expected_crc = expected_crc.finalize();
-                tracing::debug!(crc, expected_crc);
-                if crc != expected_crc {
-                    tracing::warn!(
-                        "CRC check failed: {:016x} != {:016x} (expected)",
-                        crc,
-                        expected_crc
-                    );
-                }
+        // The page is loaded to memory first, so that the time spent reading it from S3
+        // can be told apart from the time spent writing it to disk
+        let start = Instant::now();
+        let page_size =
+            read_frame_page(pgno, self.max_frame_page_size(), reader, page_buffer).await;
+        stats.network_time += start.elapsed();
+        let page_size = page_size?;
+        stats.downloaded_bytes += page_size;
+        if self.verify_crc {
+            let expected_crc = Self::expected_frame_crc(prev_crc, page_buffer);
+            tracing::debug!(crc, expected_crc);
+            if !Self::frame_crc_matches(crc, prev_crc, page_buffer) {
+                tracing::warn!(
+                    "CRC check failed: {:016x} != {:016x} (expected)",
+                    crc,
+                    expected_crc
+                );
+            }
+        };
+        if self.restore_options.verify_frames {
+            *restored_crc = Self::expected_frame_crc(*restored_crc, page_buffer);
+        }
+        self.set_page_size(page_size as usize)?;
+        let start = Instant::now();
+        let offset = Self::page_offset(pgno as i64, page_size)?;
+        main_db_writer
+            .seek(tokio::io::SeekFrom::Start(offset))
+            .await?;
+        // FIXME: we only need to overwrite with the newest page,
+        // no need to replay the whole WAL
+        tokio::io::copy(&mut &page_buffer[..], main_db_writer).await?;
+        main_db_writer.flush().await?;
+        stats.disk_time += start.elapsed();
+        stats.written_bytes += page_size;
+        page_buffer.clear();
+        Ok(())
+    }
+
+    // Largest page a frame object may hold: the page size of the database, once known
+    fn max_frame_page_size(&self) -> usize {
+        if self.page_size == Self::UNSET_PAGE_SIZE {
+            self.restore_options.max_page_size
+        } else {
+            self.page_size.min(self.restore_options.max_page_size)
+        }
+    }
+
+    // Downloads given frame object and returns a reader of the page it holds, after checking
+    // the object against the size and checksum recorded for it. The page is decompressed if
+    // it was stored compressed, whatever the current compression setting.
+    async fn open_frame<'a>(
+        &self,
+        generation: &uuid::Uuid,
+        frame: &ListedFrame,
+        rate_limiter: Option<&'a mut RateLimiter>,
+    ) -> Result<Box<dyn tokio::io::AsyncRead + Unpin + Send + 'a>> {
+        let key = frame.key.as_str();
+        let object = match self.get_object(key.to_string()).send().await {
+            Ok(object) => object,
+            Err(SdkError::ServiceError(err)) if err.err().is_no_such_key() => {
+                return Err(anyhow::anyhow!(
+                    "Frame {} is missing from generation {}: {} does not exist",
+                    frame.frameno,
+                    generation,
+                    key
+                ));
+            }
+            Err(e) => return Err(e.into()),
+        };
+        if let Some(size) = frame.size {
+            verify_object_size(key, object.content_length() as u64, size)?;
+        }
+        let metadata = object.metadata();
+        let compressed = match metadata
+            .and_then(|metadata| metadata.get(COMPRESSION_METADATA_KEY))
+            .map(String::as_str)
+        {
+            Some(GZIP_COMPRESSION) => true,
+            Some(NO_COMPRESSION) => false,
+            Some(compression) => {
+                return Err(anyhow::anyhow!(
+                    "Unknown compression {:?} in metadata of {}",
+                    compression,
+                    key
+                ))
+            }
+            // Objects uploaded before the compression was recorded follow the current setting
+            None => self.use_compression,
+        };
+        let body = if self.object_checksums || frame.checksum.is_some() {
+            // The checksum recorded in the manifest is preferred to the object metadata
+            let checksum = frame.checksum.clone().or_else(|| {
+                metadata
+                    .and_then(|metadata| metadata.get(CHECKSUM_METADATA_KEY))
+                    .cloned()
+            });
+            let data = object.body.collect().await?.into_bytes();
+            match checksum {
+                Some(checksum) => verify_object_checksum(key, &data, &checksum)?,
+                // Objects uploaded before checksums were enabled carry no metadata
+                None => tracing::trace!("No checksum stored for {}", key),
+            }
+            ByteStream::from(data)
+        } else {
+            object.body
+        };
+        let body_reader = ThrottledReader::new(body.into_async_read(), rate_limiter);
+        Ok(Self::decompressed(body_reader, compressed))
+    }
+
+    // Downloads the main database snapshot of given generation and returns a reader of its
+    // contents, or None if the generation holds no snapshot, e.g. of an empty database.
+    // Both formats are looked up, as the generation may have been written with another
+    // compression setting than the current one.
+    async fn open_snapshot<'a>(
+        &self,
+        generation: &uuid::Uuid,
+        rate_limiter: Option<&'a mut RateLimiter>,
+    ) -> Result<Option<Box<dyn tokio::io::AsyncRead + Unpin + Send + 'a>>> {
+        let formats = if self.use_compression {
+            [true, false]
+        } else {
+            [false, true]
+        };
+        for compressed in formats {
+            let key = if compressed {
+                format!("{}-{}/db.gz", self.db_name, generation)
+            } else {
+                format!("{}-{}/db.db", self.db_name, generation)
             };
-            self.set_page_size(page_size as usize)?;
-            let offset = (pgno - 1) as u64 * page_size;
-            main_db_writer
-                .seek(tokio::io::SeekFrom::Start(offset))
-                .await?;
-            tokio::io::copy(&mut &page_buffer[..], main_db_writer).await?;
-            page_buffer.clear();
+            match self.get_object(key).send().await {
+                Ok(snapshot) => {
+                    let body_reader =
+                        ThrottledReader::new(snapshot.body.into_async_read(), rate_limiter);
+                    return Ok(Some(Self::decompressed(body_reader, compressed)));
+                }
+                Err(SdkError::ServiceError(err)) if err.err().is_no_such_key() => continue,
+                Err(e) => return Err(e.into()),
+            }
+        }
+        Ok(None)
+    }
+
+    fn decompressed<'a>(
+        reader: impl tokio::io::AsyncRead + Unpin + Send + 'a,
+        compressed: bool,
+    ) -> Box<dyn tokio::io::AsyncRead + Unpin + Send + 'a> {
+        if compressed {
+            Box::new(async_compression::tokio::bufread::GzipDecoder::new(
+                tokio::io::BufReader::new(reader),
+            ))
         } else {
-            let offset = (pgno - 1) as u64 * self.page_size as u64;
+            Box::new(reader)
+        }
+    }
+
+    // Offset of given page in the main database file. Page numbers start from 1, so anything
+    // else comes from a corrupted frame.
+    fn page_offset(pgno: i64, page_size: u64) -> Result<u64> {
+        u64::try_from(pgno)
+            .ok()
+            .and_then(|pgno| pgno.checked_sub(1))
+            .map(|index| index * page_size)
+            .ok_or_else(|| anyhow::anyhow!("Invalid page number {}", pgno))
+    }
+
+    // Checks that the first `frames` frames of the local WAL file hold the same pages as the
+    // frames of the remote generation, by recomputing their CRC chain from the local pages
+    async fn local_wal_matches(
+        &self,
+        remote_frames: &[ListedFrame],
+        frames: FrameNo,
+    ) -> Result<bool> {
+        use tokio::io::{AsyncReadExt, AsyncSeekExt};
+        if (remote_frames.len() as u64) < frames {
+            return Ok(false);
+        }
+        let mut wal_file = tokio::fs::File::open(&format!("{}-wal", &self.db_path)).await?;
+        let mut page = vec![0u8; self.page_size];
+        let mut prev_crc = 0;
+        for remote in &remote_frames[..frames as usize] {
+            // Each WAL file consists of a 32-byte WAL header and N entries of size (page size + 24)
+            let offset = 32 + (remote.frameno - 1) * (self.page_size + 24) as u64;
+            wal_file.seek(tokio::io::SeekFrom::Start(offset)).await?;
+            let pgno = wal_file.read_u32().await?;
+            wal_file
+                .seek(tokio::io::SeekFrom::Start(offset + 24))
+                .await?;
+            wal_file.read_exact(&mut page).await?;
+            if pgno as i64 != remote.pgno as i64
+                || !Self::frame_crc_matches(remote.crc, prev_crc, &page)
+            {
+                tracing::warn!(
+                    "Local WAL frame {} (page {}) differs from the remote frame {}",
+                    remote.frameno,
+                    pgno,
+                    remote.key
+                );
+                return Ok(false);
+            }
+            prev_crc = remote.crc;
+        }
+        Ok(true)
+    }
+
+    // Writes the first `frames` frames of the local WAL file into the main database file
+    async fn apply_local_wal(
+        &self,
+        frames: FrameNo,
+        main_db_writer: &mut tokio::fs::File,
+    ) -> Result<()> {
+        use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+        let mut wal_file = tokio::fs::File::open(&format!("{}-wal", &self.db_path)).await?;
+        let mut page = vec![0u8; self.page_size];
+        for frame in 0..frames {
+            // Each WAL file consists of a 32-byte WAL header and N entries of size (page size + 24)
+            let offset = 32 + frame * (self.page_size + 24) as u64;
+            wal_file.seek(tokio::io::SeekFrom::Start(offset)).await?;
+            let pgno = wal_file.read_u32().await?;
+            wal_file
+                .seek(tokio::io::SeekFrom::Start(offset + 24))
+                .await?;
+            wal_file.read_exact(&mut page).await?;
             main_db_writer
-                .seek(tokio::io::SeekFrom::Start(offset))
+                .seek(tokio::io::SeekFrom::Start(Self::page_offset(
+                    pgno as i64,
+                    self.page_size as u64,
+                )?))
                 .await?;
-            // FIXME: we only need to overwrite with the newest page,
-            // no need to replay the whole WAL
-            tokio::io::copy(reader, main_db_writer).await?;
+            main_db_writer.write_all(&page).await?;
         }
         main_db_writer.flush().await?;
         Ok(())
     }
 
+    // Fetches a single page, as it existed in given generation at given frame (or at the last
+    // consistent frame, if not specified), without restoring the whole database.
+    pub async fn fetch_page(
+        &self,
+        generation: uuid::Uuid,
+        pgno: u32,
+        at_frame: Option<FrameNo>,
+    ) -> Result<Bytes> {
+        use tokio::io::AsyncReadExt;
+
+        let (last_consistent_frame, _) = self.get_last_consistent_frame(&generation).await?;
+        let at_frame = at_frame
+            .unwrap_or(last_consistent_frame)
+            .min(last_consistent_frame);
+
+        // The page comes from the newest frame up to `at_frame` which contains it, picked
+        // from the frames a restore would apply
+        let frames = self
+            .generation_restore_frames(&generation, last_consistent_frame)
+            .await?;
+        let newest_frame = frames
+            .iter()
+            .rev()
+            .find(|frame| frame.frameno <= at_frame && frame.pgno as i64 == pgno as i64);
+        if let Some(frame) = newest_frame {
+            tracing::debug!("Page {} found in frame {}", pgno, frame.frameno);
+            let mut page = Vec::new();
+            let mut reader = self.open_frame(&generation, frame, None).await?;
+            read_frame_page(
+                frame.pgno,
+                self.max_frame_page_size(),
+                &mut reader,
+                &mut page,
+            )
+            .await?;
+            return Ok(Bytes::from(page));
+        }
+
+        // The page was not modified in this generation, so it comes from the main db snapshot
+        tracing::debug!(
+            "Page {} not found in the log, reading it from the snapshot",
+            pgno
+        );
+        let mut reader = self
+            .open_snapshot(&generation, None)
+            .await?
+            .ok_or_else(|| {
+                anyhow::anyhow!("Page {} is not stored in generation {}", pgno, generation)
+            })?;
+        let mut page = Vec::new();
+        let mut header = [0u8; 100];
+        reader.read_exact(&mut header).await?;
+        let page_size = Self::page_size_from_header(u16::from_be_bytes([header[16], header[17]]))?;
+        let offset = (pgno as u64)
+            .checked_sub(1)
+            .ok_or_else(|| anyhow::anyhow!("Invalid page number {}", pgno))?
+            * page_size as u64;
+        if offset == 0 {
+            page.extend_from_slice(&header);
+        } else {
+            let skip = offset - header.len() as u64;
+            tokio::io::copy(&mut (&mut reader).take(skip), &mut tokio::io::sink()).await?;
+        }
+        let remaining = page_size - page.len();
+        let start = page.len();
+        page.resize(page_size, 0);
+        reader
+            .read_exact(&mut page[start..start + remaining])
+            .await?;
+        Ok(Bytes::from(page))
+    }
+
     // Restores the database state from given remote generation
+    #[tracing::instrument(
+        skip(self, generation),
+        fields(
+            generation = %generation,
+            first_frame,
+            last_frame,
+            downloaded_bytes,
+            written_bytes
+        )
+    )]
     pub async fn restore_from(&mut self, generation: uuid::Uuid) -> Result<RestoreAction> {
         use tokio::io::AsyncWriteExt;
 
+        // Shared by all downloads of this restore
+        let mut rate_limiter = self.restore_options.bytes_per_sec.map(RateLimiter::new);
+
         // Check if the database needs to be restored by inspecting the database
         // change counter and the WAL size.
         let local_counter = match tokio::fs::File::open(&self.db_path).await {
@@ -660,7 +2086,9 @@ impl Replicator {
         let remote_counter = self.get_remote_change_counter(&generation).await?;
         tracing::debug!("Counters: l={:?}, r={:?}", local_counter, remote_counter);
 
-        let (last_consistent_frame, checksum) = self.get_last_consistent_frame(&generation).await?;
+        let consistent_info = self.get_consistent_info(&generation).await?;
+        let (last_consistent_frame, checksum) = (consistent_info.frame, consistent_info.checksum);
+        tracing::Span::current().record("last_frame", last_consistent_frame);
         tracing::debug!(
             "Last consistent remote frame: {}; checksum: {:x}",
             last_consistent_frame,
@@ -668,6 +2096,9 @@ impl Replicator {
         );
 
         let wal_pages = self.get_local_wal_page_count().await;
+        // If the local main database file matches the remote snapshot, only the frames
+        // missing from the local WAL need to be fetched from the remote generation.
+        let mut catch_up_from_frame = None;
         match local_counter.cmp(&remote_counter) {
             Ordering::Equal => {
                 tracing::debug!(
@@ -680,6 +2111,23 @@ impl Replicator {
                         tracing::info!(
                             "Remote generation is up-to-date, reusing it in this session"
                         );
+                        let frame_crc = match consistent_info.frame_crc {
+                            Some(frame_crc) => frame_crc,
+                            // Generations written by older versions only store it in the key
+                            // of the last frame
+                            None if last_consistent_frame > 0 => self
+                                .generation_restore_frames(&generation, last_consistent_frame)
+                                .await?
+                                .last()
+                                .map_or(0, |frame| frame.crc),
+                            None => 0,
+                        };
+                        self.set_generation(generation);
+                        // New frames continue the CRC chain of the generation, so that
+                        // restore can verify it as a whole
+                        self.last_frame_crc = frame_crc;
+                        self.last_transaction_crc = frame_crc;
+                        self.last_committed_frame = Some(last_consistent_frame);
                         self.next_frame = wal_pages + 1;
                         return Ok(RestoreAction::ReuseGeneration(generation));
                     }
@@ -687,7 +2135,15 @@ impl Replicator {
                         tracing::info!("Local change counter matches the remote one, but local WAL contains newer data, which needs to be replicated");
                         return Ok(RestoreAction::SnapshotMainDbFile);
                     }
-                    Ordering::Less => (),
+                    Ordering::Less => {
+                        if self.main_db_exists_and_not_empty().await {
+                            tracing::info!(
+                                "Local database is behind the remote generation by {} frames, catching up",
+                                last_consistent_frame - wal_pages
+                            );
+                            catch_up_from_frame = Some(wal_pages);
+                        }
+                    }
                 }
             }
             Ordering::Greater => {
@@ -697,34 +2153,62 @@ impl Replicator {
             Ordering::Less => (),
         }
 
-        tokio::fs::rename(&self.db_path, format!("{}.bottomless.backup", self.db_path))
-            .await
-            .ok(); // Best effort
-        let mut main_db_writer = tokio::fs::File::create(&self.db_path).await?;
-        // If the db file is not present, the database could have been empty
-
-        let main_db_path = if self.use_compression {
-            format!("{}-{}/db.gz", self.db_name, generation)
-        } else {
-            format!("{}-{}/db.db", self.db_name, generation)
-        };
-
-        if let Ok(db_file) = self.get_object(main_db_path).send().await {
-            let mut body_reader = db_file.body.into_async_read();
-            if self.use_compression {
-                let mut decompress_reader = async_compression::tokio::bufread::GzipDecoder::new(
-                    tokio::io::BufReader::new(body_reader),
+        let frames = self
+            .generation_restore_frames(&generation, last_consistent_frame)
+            .await?;
+        // The local WAL is only reused if it's a prefix of the remote log, a diverged one
+        // is replaced by a full restore
+        if let Some(local_frames) = catch_up_from_frame {
+            if !self.local_wal_matches(&frames, local_frames).await? {
+                tracing::warn!(
+                    "Local WAL diverged from generation {}, restoring the whole database",
+                    generation
                 );
-                tokio::io::copy(&mut decompress_reader, &mut main_db_writer).await?;
-            } else {
-                tokio::io::copy(&mut body_reader, &mut main_db_writer).await?;
+                catch_up_from_frame = None;
             }
-            main_db_writer.flush().await?;
         }
-        tracing::info!("Restored the main database file");
 
-        let mut next_marker = None;
-        let prefix = format!("{}-{}/", self.db_name, generation);
+        let mut stats = RestoreStats::default();
+        let mut main_db_writer = match catch_up_from_frame {
+            Some(local_frames) => {
+                // The main database file is patched in place, so a copy is kept in case
+                // the restore fails halfway
+                tokio::fs::copy(&self.db_path, format!("{}.bottomless.backup", self.db_path))
+                    .await?;
+                let mut main_db_writer = tokio::fs::OpenOptions::new()
+                    .write(true)
+                    .open(&self.db_path)
+                    .await?;
+                self.apply_local_wal(local_frames, &mut main_db_writer)
+                    .await?;
+                tracing::info!(
+                    "Applied {} local WAL frames to the main database file",
+                    local_frames
+                );
+                main_db_writer
+            }
+            None => {
+                tokio::fs::rename(&self.db_path, format!("{}.bottomless.backup", self.db_path))
+                    .await
+                    .ok(); // Best effort
+                let mut main_db_writer = tokio::fs::File::create(&self.db_path).await?;
+                // If the db file is not present, the database could have been empty
+                let start = Instant::now();
+                if let Some(mut snapshot_reader) = self
+                    .open_snapshot(&generation, rate_limiter.as_mut())
+                    .await?
+                {
+                    tokio::io::copy(&mut snapshot_reader, &mut main_db_writer).await?;
+                    main_db_writer.flush().await?;
+                }
+                stats.snapshot_time = start.elapsed();
+                tracing::info!("Restored the main database file");
+                main_db_writer
+            }
+        };
+        let skip_frames_up_to = catch_up_from_frame.unwrap_or(0);
+        tracing::Span::current().record("first_frame", skip_frames_up_to + 1);
+
         tracing::debug!("Overwriting any existing WAL file: {}-wal", &self.db_path);
         tokio::fs::remove_file(&format!("{}-wal", &self.db_path))
             .await
@@ -734,85 +2218,86 @@ impl Replicator {
             .ok();
 
         let mut applied_wal_frame = false;
-        loop {
-            let mut list_request = self.list_objects().prefix(&prefix);
-            if let Some(marker) = next_marker {
-                list_request = list_request.marker(marker);
+        let mut prev_crc = 0;
+        // CRC chain recomputed from the restored pages, if verify_frames is set
+        let mut restored_crc = 0;
+        let mut last_restored_frame = 0;
+        let mut page_buffer = Vec::with_capacity(self.restore_options.max_page_size); // best guess for the page size - it will certainly not be more than that
+        for frame in &frames {
+            let (frameno, pgno, crc) = (frame.frameno, frame.pgno, frame.crc);
+            // Frames are applied right away, so a page written more than once within
+            // a transaction ends up with its newest version only if they're applied in order
+            debug_assert!(
+                frameno > last_restored_frame,
+                "frame {frameno} restored after frame {last_restored_frame}"
+            );
+            last_restored_frame = frameno;
+            if frameno <= skip_frames_up_to {
+                tracing::trace!("Frame {} is already present locally, skipping", frameno);
+                // Already checked against the local pages by local_wal_matches
+                prev_crc = crc;
+                restored_crc = crc;
+                continue;
             }
-            let response = list_request.send().await?;
-            let objs = match response.contents() {
-                Some(objs) => objs,
-                None => {
-                    tracing::debug!("No objects found in generation {}", generation);
-                    break;
-                }
-            };
-            let mut prev_crc = 0;
-            let mut page_buffer = Vec::with_capacity(65536); // best guess for the page size - it will certainly not be more than 64KiB
-            for obj in objs {
-                let key = obj
-                    .key()
-                    .ok_or_else(|| anyhow::anyhow!("Failed to get key for an object"))?;
-                tracing::debug!("Loading {}", key);
-                let frame = self.get_object(key.into()).send().await?;
+            tracing::debug!("Loading {}", frame.key);
+            let start = Instant::now();
+            let mut page_reader = self
+                .open_frame(&generation, frame, rate_limiter.as_mut())
+                .await?;
+            stats.network_time += start.elapsed();
+            self.restore_frame(
+                pgno,
+                crc,
+                prev_crc,
+                &mut restored_crc,
+                &mut page_buffer,
+                &mut main_db_writer,
+                &mut page_reader,
+                &mut stats,
+            )
+            .await?;
+            tracing::debug!("Written frame {} as main db page {}", frameno, pgno);
 
-                let (frameno, pgno, crc) = match Self::parse_frame_page_crc(key) {
-                    Some(result) => result,
-                    None => {
-                        if !key.ends_with(".gz")
-                            && !key.ends_with(".db")
-                            && !key.ends_with(".consistent")
-                            && !key.ends_with(".changecounter")
-                        {
-                            tracing::warn!("Failed to parse frame/page from key {}", key);
-                        }
-                        continue;
-                    }
-                };
-                if frameno > last_consistent_frame {
-                    tracing::warn!("Remote log contains frame {} larger than last consistent frame ({}), stopping the restoration process",
-                                frameno, last_consistent_frame);
-                    break;
-                }
-                let mut body_reader = frame.body.into_async_read();
-                if self.use_compression {
-                    let mut compressed_reader = async_compression::tokio::bufread::GzipDecoder::new(
-                        tokio::io::BufReader::new(body_reader),
-                    );
-                    self.restore_frame(
-                        pgno,
-                        crc,
-                        prev_crc,
-                        &mut page_buffer,
-                        &mut main_db_writer,
-                        &mut compressed_reader,
-                    )
-                    .await?;
-                } else {
-                    self.restore_frame(
-                        pgno,
-                        crc,
-                        prev_crc,
-                        &mut page_buffer,
-                        &mut main_db_writer,
-                        &mut body_reader,
-                    )
-                    .await?;
-                };
-                tracing::debug!("Written frame {} as main db page {}", frameno, pgno);
+            prev_crc = crc;
+            applied_wal_frame = true;
+        }
 
-                prev_crc = crc;
-                applied_wal_frame = true;
-            }
-            next_marker = response
-                .is_truncated()
-                .then(|| objs.last().map(|elem| elem.key().unwrap().to_string()))
-                .flatten();
-            if next_marker.is_none() {
-                break;
+        if self.restore_options.verify_frames {
+            match consistent_info.frame_crc {
+                Some(expected_crc) if expected_crc != restored_crc => {
+                    return Err(anyhow::anyhow!(
+                        "Restored frames of generation {} do not match it: CRC {:016x} != {:016x} stored for frame {}",
+                        generation,
+                        restored_crc,
+                        expected_crc,
+                        last_consistent_frame
+                    ));
+                }
+                Some(_) => tracing::debug!("Restored frames match their CRC: {:016x}", restored_crc),
+                None => tracing::warn!(
+                    "Generation {} stores no CRC of its last consistent frame, restored frames were not verified",
+                    generation
+                ),
             }
         }
 
+        tracing::info!(
+            "Restore of generation {} finished: snapshot took {:?}, {} bytes of frames took {:?} to download and {:?} to write",
+            generation,
+            stats.snapshot_time,
+            stats.downloaded_bytes,
+            stats.network_time,
+            stats.disk_time
+        );
+        let span = tracing::Span::current();
+        span.record("downloaded_bytes", stats.downloaded_bytes);
+        span.record("written_bytes", stats.written_bytes);
+        self.restore_stats = stats;
+
+        if let Some(expected_user_version) = self.restore_options.expected_user_version {
+            self.verify_user_version(expected_user_version).await?;
+        }
+
         if applied_wal_frame {
             Ok::<_, anyhow::Error>(RestoreAction::SnapshotMainDbFile)
         } else {
@@ -820,18 +2305,223 @@ impl Replicator {
         }
     }
 
-    // Restores the database state from newest remote generation
+    // Checks that the restored database reports the expected user version
+    async fn verify_user_version(&self, expected_user_version: u32) -> Result<()> {
+        let mut db = tokio::fs::File::open(&self.db_path).await?;
+        let user_version = Self::read_user_version(&mut db).await.map_err(|e| {
+            anyhow::anyhow!(
+                "Failed to read the user version of the restored database: {}",
+                e
+            )
+        })?;
+        if user_version != expected_user_version {
+            return Err(anyhow::anyhow!(
+                "Restored database has user version {}, expected {}",
+                user_version,
+                expected_user_version
+            ));
+        }
+        Ok(())
+    }
+
+    // Restores the database state from newest remote generation. If a restore source is
+    // configured, the generation is read from there, and a fresh generation is started in the
+    // backup bucket.
     pub async fn restore(&mut self) -> Result<RestoreAction> {
-        let newest_generation = match self.find_newest_generation().await {
-            Some(gen) => gen,
+        let source = match self.restore_source.take() {
+            Some(source) => source,
+            None => return self.restore_newest_generation().await,
+        };
+        tracing::info!(
+            "Restoring from bucket {}, backing up to bucket {}",
+            source.bucket,
+            self.bucket
+        );
+        // Everything read while restoring comes from the restore source
+        let client = std::mem::replace(&mut self.client, source.client);
+        let bucket = std::mem::replace(&mut self.bucket, source.bucket);
+        let result = self.restore_newest_generation().await;
+        self.client = client;
+        self.bucket = bucket;
+        // None of the restored generations exist in the backup bucket, so a new lineage is
+        // started there from a snapshot of the restored database
+        result.map(|_| RestoreAction::SnapshotMainDbFile)
+    }
+
+    async fn restore_newest_generation(&mut self) -> Result<RestoreAction> {
+        match self.find_restore_generation().await? {
+            Some(generation) => {
+                tracing::info!("Restoring from generation {}", generation);
+                self.restore_from(generation).await
+            }
             None => {
                 tracing::debug!("No generation found, nothing to restore");
-                return Ok(RestoreAction::SnapshotMainDbFile);
+                Ok(RestoreAction::SnapshotMainDbFile)
             }
+        }
+    }
+
+    // Returns the generation to restore from: the newest one, unless it's incomplete
+    async fn find_restore_generation(&self) -> Result<Option<uuid::Uuid>> {
+        let newest_generation = match self.find_newest_generation().await {
+            Some(gen) => gen,
+            None => return Ok(None),
         };
 
-        tracing::info!("Restoring from generation {}", newest_generation);
-        self.restore_from(newest_generation).await
+        let generation = if self.is_generation_complete(&newest_generation).await? {
+            newest_generation
+        } else {
+            match self.find_newest_complete_generation().await? {
+                Some(gen) => {
+                    tracing::warn!(
+                        "Newest generation {} is incomplete, falling back to generation {}",
+                        newest_generation,
+                        gen
+                    );
+                    gen
+                }
+                None => {
+                    tracing::warn!(
+                        "Newest generation {} is incomplete and no complete generation was found",
+                        newest_generation
+                    );
+                    newest_generation
+                }
+            }
+        };
+        Ok(Some(generation))
+    }
+
+    // Compares the local database with the generation it would be restored from, without
+    // modifying either of them
+    pub async fn diagnose(&mut self) -> Result<ReconcileReport> {
+        let generation = self.find_restore_generation().await?;
+        let local_change_counter = match tokio::fs::File::open(&self.db_path).await {
+            Ok(mut db) => {
+                if let Ok(page_size) = Self::read_page_size(&mut db).await {
+                    self.set_page_size(page_size)?;
+                }
+                Self::read_change_counter(&mut db).await.unwrap_or([0u8; 4])
+            }
+            Err(_) => [0u8; 4],
+        };
+        let local_wal_frames = self.get_local_wal_page_count().await;
+        let (remote_change_counter, last_consistent_frame) = match generation {
+            Some(generation) => (
+                self.get_remote_change_counter(&generation).await?,
+                self.get_last_consistent_frame(&generation).await?.0,
+            ),
+            None => ([0u8; 4], 0),
+        };
+
+        // Logs on top of the same snapshot are compared up to the newest frame present in both
+        let common_frame = local_wal_frames.min(last_consistent_frame);
+        let common_frame_matches = match generation {
+            Some(generation)
+                if common_frame > 0 && local_change_counter == remote_change_counter =>
+            {
+                let frames = self
+                    .generation_restore_frames(&generation, last_consistent_frame)
+                    .await?;
+                self.local_wal_matches(&frames, common_frame).await?
+            }
+            _ => true,
+        };
+
+        Ok(ReconcileReport {
+            generation,
+            status: Self::classify_sync(
+                local_change_counter,
+                remote_change_counter,
+                local_wal_frames,
+                last_consistent_frame,
+                common_frame_matches,
+            ),
+            local_change_counter,
+            remote_change_counter,
+            local_wal_frames,
+            last_consistent_frame,
+        })
+    }
+
+    // Same decision restore_from makes, extended with a check of the frame present in both logs
+    fn classify_sync(
+        local_counter: [u8; 4],
+        remote_counter: [u8; 4],
+        local_frames: FrameNo,
+        remote_frames: FrameNo,
+        common_frame_matches: bool,
+    ) -> SyncStatus {
+        match local_counter.cmp(&remote_counter) {
+            Ordering::Greater => SyncStatus::LocalAhead,
+            Ordering::Less => SyncStatus::RemoteAhead,
+            Ordering::Equal if !common_frame_matches => SyncStatus::Diverged,
+            Ordering::Equal => match local_frames.cmp(&remote_frames) {
+                Ordering::Equal => SyncStatus::InSync,
+                Ordering::Greater => SyncStatus::LocalAhead,
+                Ordering::Less => SyncStatus::RemoteAhead,
+            },
+        }
+    }
+
+    // Diagnoses how the local database relates to its backup and, if a resolution is given and
+    // they differ, makes them consistent again by keeping the chosen side. The returned report
+    // describes the state before the resolution was applied.
+    pub async fn reconcile(&mut self, resolution: Option<Resolution>) -> Result<ReconcileReport> {
+        let report = self.diagnose().await?;
+        let resolution = match resolution {
+            Some(resolution) if report.status != SyncStatus::InSync => resolution,
+            _ => return Ok(report),
+        };
+        tracing::info!(
+            "Reconciling a database which is {:?} with its backup: {:?}",
+            report.status,
+            resolution
+        );
+
+        match resolution {
+            Resolution::KeepLocal => {
+                if !self.main_db_exists_and_not_empty().await {
+                    return Err(anyhow::anyhow!(
+                        "Local database {} does not exist or is empty, refusing to keep it",
+                        self.db_path
+                    ));
+                }
+                self.snapshot_new_generation().await?;
+            }
+            Resolution::KeepRemote => {
+                let generation = report
+                    .generation
+                    .ok_or_else(|| anyhow::anyhow!("No backup found to restore from"))?;
+                // restore_from keeps a local database which is ahead of the backup, so the local
+                // files are moved aside first
+                for path in [self.db_path.clone(), format!("{}-wal", self.db_path)] {
+                    if tokio::fs::metadata(&path).await.is_ok() {
+                        let backup_path = format!("{path}.bottomless.backup");
+                        tracing::info!("Moving {} to {}", path, backup_path);
+                        tokio::fs::rename(&path, backup_path).await?;
+                    }
+                }
+                if let RestoreAction::SnapshotMainDbFile = self.restore_from(generation).await? {
+                    self.snapshot_new_generation().await?;
+                }
+            }
+        }
+        Ok(report)
+    }
+
+    // Starts a new generation from the local database, the way it's done after a restore
+    async fn snapshot_new_generation(&mut self) -> Result<()> {
+        if let Err(e) = self.finalize_generation().await {
+            tracing::warn!(
+                "Failed to write the manifest of generation {}: {}",
+                self.generation,
+                e
+            );
+        }
+        self.new_generation();
+        self.snapshot_main_db_file().await?;
+        self.maybe_replicate_wal().await
     }
 }
 
@@ -839,3 +2529,1056 @@ pub struct Context {
     pub replicator: Replicator,
     pub runtime: tokio::runtime::Runtime,
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::mock_s3::MockS3;
+
+    async fn compress(data: &[u8]) -> Vec<u8> {
+        let mut compressor = async_compression::tokio::bufread::GzipEncoder::new(data);
+        let mut compressed = Vec::new();
+        tokio::io::copy(&mut compressor, &mut compressed)
+            .await
+            .unwrap();
+        compressed
+    }
+
+    #[test]
+    fn validate_page_size() {
+        assert!(Replicator::validate_page_size(0).is_err());
+        assert!(Replicator::validate_page_size(100).is_err());
+        assert!(Replicator::validate_page_size(256).is_err());
+        assert!(Replicator::validate_page_size(3000).is_err());
+        assert!(Replicator::validate_page_size(131072).is_err());
+        assert!(Replicator::validate_page_size(512).is_ok());
+        assert!(Replicator::validate_page_size(4096).is_ok());
+        assert!(Replicator::validate_page_size(65536).is_ok());
+    }
+
+    #[test]
+    fn validate_list_page_size() {
+        assert!(Replicator::validate_list_page_size(-1).is_err());
+        assert!(Replicator::validate_list_page_size(0).is_err());
+        assert!(Replicator::validate_list_page_size(1001).is_err());
+        assert!(Replicator::validate_list_page_size(1).is_ok());
+        assert!(Replicator::validate_list_page_size(100).is_ok());
+        assert!(Replicator::validate_list_page_size(Replicator::DEFAULT_LIST_PAGE_SIZE).is_ok());
+    }
+
+    #[test]
+    fn frame_crc_chain() {
+        let first = vec![1u8; 4096];
+        let second = vec![2u8; 4096];
+        let first_crc = Replicator::expected_frame_crc(0, &first);
+        let second_crc = Replicator::expected_frame_crc(first_crc, &second);
+        assert!(Replicator::frame_crc_matches(first_crc, 0, &first));
+        assert!(Replicator::frame_crc_matches(
+            second_crc, first_crc, &second
+        ));
+        // a frame spliced in from the start of another chain
+        let restarted_crc = Replicator::expected_frame_crc(0, &second);
+        assert!(!Replicator::frame_crc_matches(
+            restarted_crc,
+            first_crc,
+            &second
+        ));
+
+        // contents tampered with, but not the CRC recorded in the key
+        let mut tampered = second.clone();
+        tampered[100] ^= 0xff;
+        assert!(!Replicator::frame_crc_matches(
+            second_crc, first_crc, &tampered
+        ));
+        // frames reordered
+        assert!(!Replicator::frame_crc_matches(second_crc, 0, &first));
+    }
+
+    #[test]
+    fn check_free_space() {
+        let tmp = std::env::temp_dir();
+        assert!(Replicator::check_free_space(&tmp, 0).is_ok());
+        assert!(Replicator::check_free_space(&tmp, u64::MAX).is_err());
+        assert!(Replicator::check_free_space(&tmp.join("does-not-exist"), 0).is_err());
+    }
+
+    #[test]
+    fn parse_generation_prefix() {
+        let generation = uuid::Uuid::parse_str("01890a39-7a4d-7c6f-9c3e-4d1a5b8e2f60").unwrap();
+        assert_eq!(
+            Replicator::parse_generation_prefix("db", &format!("db-{generation}/")),
+            Some(generation)
+        );
+        assert_eq!(
+            Replicator::parse_generation_prefix("db", &format!("db2-{generation}/")),
+            None
+        );
+        assert_eq!(
+            Replicator::parse_generation_prefix("db", &format!("db-{generation}")),
+            None
+        );
+        assert_eq!(
+            Replicator::parse_generation_prefix("db", "db-garbage/"),
+            None
+        );
+    }
+
+    #[test]
+    fn page_size_from_header() {
+        assert_eq!(Replicator::page_size_from_header(1).unwrap(), 65536);
+        assert_eq!(Replicator::page_size_from_header(4096).unwrap(), 4096);
+        assert!(Replicator::page_size_from_header(0).is_err());
+        assert!(Replicator::page_size_from_header(100).is_err());
+    }
+
+    #[test]
+    fn parse_consistent_info() {
+        let mut legacy = BytesMut::new();
+        legacy.extend_from_slice(&42u32.to_be_bytes());
+        legacy.extend_from_slice(&7u64.to_be_bytes());
+        assert_eq!(
+            Replicator::parse_consistent_info(&mut legacy.freeze()).unwrap(),
+            ConsistentInfo {
+                frame: 42,
+                checksum: 7,
+                frame_crc: None
+            }
+        );
+
+        let frame_no = u32::MAX as FrameNo + 10;
+        let mut wide = BytesMut::new();
+        wide.extend_from_slice(&frame_no.to_be_bytes());
+        wide.extend_from_slice(&7u64.to_be_bytes());
+        assert_eq!(
+            Replicator::parse_consistent_info(&mut wide.freeze()).unwrap(),
+            ConsistentInfo {
+                frame: frame_no,
+                checksum: 7,
+                frame_crc: None
+            }
+        );
+
+        let mut versioned = BytesMut::new();
+        versioned.extend_from_slice(&[2]);
+        versioned.extend_from_slice(&frame_no.to_be_bytes());
+        versioned.extend_from_slice(&7u64.to_be_bytes());
+        assert_eq!(
+            Replicator::parse_consistent_info(&mut versioned.freeze()).unwrap(),
+            ConsistentInfo {
+                frame: frame_no,
+                checksum: 7,
+                frame_crc: None
+            }
+        );
+
+        let mut with_crc = BytesMut::new();
+        with_crc.extend_from_slice(&[3]);
+        with_crc.extend_from_slice(&frame_no.to_be_bytes());
+        with_crc.extend_from_slice(&7u64.to_be_bytes());
+        with_crc.extend_from_slice(&0xabcdu64.to_be_bytes());
+        assert_eq!(
+            Replicator::parse_consistent_info(&mut with_crc.freeze()).unwrap(),
+            ConsistentInfo {
+                frame: frame_no,
+                checksum: 7,
+                frame_crc: Some(0xabcd)
+            }
+        );
+
+        assert!(Replicator::parse_consistent_info(&mut Bytes::from_static(&[0; 3])).is_err());
+        assert!(Replicator::parse_consistent_info(&mut Bytes::new()).is_err());
+        // a layout from a newer version is not mistaken for a known one
+        let mut newer = vec![Replicator::CONSISTENT_INFO_VERSION + 1];
+        newer.extend_from_slice(&[0; 24]);
+        let err = Replicator::parse_consistent_info(&mut Bytes::from(newer)).unwrap_err();
+        assert!(err.to_string().contains("newer version"));
+    }
+
+    #[test]
+    fn check_db_page_size() {
+        assert!(Replicator::check_db_page_size(4096, 4096).is_ok());
+        assert!(Replicator::check_db_page_size(1024, 4096).is_err());
+    }
+
+    #[test]
+    fn check_wal_page_size() {
+        assert!(Replicator::check_wal_page_size(4096, 4096).is_ok());
+        assert!(Replicator::check_wal_page_size(65536, 65536).is_ok());
+        let err = Replicator::check_wal_page_size(1024, 4096).unwrap_err();
+        assert!(err.to_string().contains("does not match"));
+    }
+
+    #[test]
+    fn is_transient_status() {
+        assert!(Replicator::is_transient_status(500));
+        assert!(Replicator::is_transient_status(503));
+        assert!(Replicator::is_transient_status(429));
+        assert!(!Replicator::is_transient_status(403));
+        assert!(!Replicator::is_transient_status(400));
+        assert!(!Replicator::is_transient_status(404));
+    }
+
+    #[test]
+    fn check_consistent_frame() {
+        assert!(Replicator::check_consistent_frame(0, 1).is_ok());
+        assert!(Replicator::check_consistent_frame(10, 10).is_ok());
+        assert!(Replicator::check_consistent_frame(10, 11).is_ok());
+        assert!(Replicator::check_consistent_frame(10, 9).is_err());
+    }
+
+    // Collects the fields recorded on spans, by span name
+    #[derive(Clone, Default)]
+    struct SpanCapture {
+        spans: std::sync::Arc<
+            std::sync::Mutex<
+                std::collections::HashMap<tracing::span::Id, (String, BTreeMap<String, String>)>,
+            >,
+        >,
+    }
+
+    struct FieldVisitor<'a>(&'a mut BTreeMap<String, String>);
+
+    impl tracing::field::Visit for FieldVisitor<'_> {
+        fn record_debug(&mut self, field: &tracing::field::Field, value: &dyn std::fmt::Debug) {
+            self.0
+                .insert(field.name().to_string(), format!("{:?}", value));
+        }
+    }
+
+    impl<S: tracing::Subscriber> tracing_subscriber::Layer<S> for SpanCapture {
+        fn on_new_span(
+            &self,
+            attrs: &tracing::span::Attributes<'_>,
+            id: &tracing::span::Id,
+            _ctx: tracing_subscriber::layer::Context<'_, S>,
+        ) {
+            let mut fields = BTreeMap::new();
+            attrs.record(&mut FieldVisitor(&mut fields));
+            self.spans
+                .lock()
+                .unwrap()
+                .insert(id.clone(), (attrs.metadata().name().to_string(), fields));
+        }
+
+        fn on_record(
+            &self,
+            id: &tracing::span::Id,
+            values: &tracing::span::Record<'_>,
+            _ctx: tracing_subscriber::layer::Context<'_, S>,
+        ) {
+            if let Some((_, fields)) = self.spans.lock().unwrap().get_mut(id) {
+                values.record(&mut FieldVisitor(fields));
+            }
+        }
+    }
+
+    impl SpanCapture {
+        fn fields_of(&self, name: &str) -> Vec<BTreeMap<String, String>> {
+            self.spans
+                .lock()
+                .unwrap()
+                .values()
+                .filter(|(span_name, _)| span_name == name)
+                .map(|(_, fields)| fields.clone())
+                .collect()
+        }
+    }
+
+    // A region is set so that the client is created without querying instance metadata,
+    // and the bucket is not checked, so no request is sent
+    async fn test_replicator() -> Replicator {
+        std::env::set_var("AWS_REGION", "us-east-1");
+        Replicator::create(Options {
+            create_bucket_if_not_exists: false,
+            verify_crc: true,
+            use_compression: false,
+            verify_compression: false,
+            max_buffered_bytes: None,
+            circuit_breaker: None,
+            object_checksums: false,
+            lease: None,
+            list_page_size: Replicator::DEFAULT_LIST_PAGE_SIZE,
+            temp_dir: None,
+            skip_bucket_check: true,
+            bucket_check_retries: 3,
+            max_frames_per_generation: None,
+            restore: RestoreOptions::default(),
+        })
+        .await
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn flush_span_attributes() {
+        use tracing_subscriber::layer::SubscriberExt;
+
+        let capture = SpanCapture::default();
+        let _guard =
+            tracing::subscriber::set_default(tracing_subscriber::registry().with(capture.clone()));
+
+        let s3 = MockS3::start().await;
+        let mut replicator = mock_replicator(&s3, Path::new("test.db")).await;
+        replicator.set_page_size(4096).unwrap();
+        let generation = replicator.generation;
+
+        for pgno in 1..=3 {
+            replicator.write(pgno, &[pgno as u8; 4096]).unwrap();
+        }
+        assert_eq!(replicator.flush().await.unwrap(), 3);
+
+        let spans = capture.fields_of("flush");
+        assert_eq!(spans.len(), 1);
+        let fields = &spans[0];
+        assert_eq!(fields["generation"], generation.to_string());
+        assert_eq!(fields["first_frame"], "1");
+        assert_eq!(fields["last_frame"], "3");
+        assert_eq!(fields["bytes"], (3 * 4096).to_string());
+    }
+
+    #[tokio::test]
+    async fn manifest_kept_for_new_generations_only() {
+        let mut replicator = test_replicator().await;
+        replicator.register_db("test.db");
+        assert!(replicator.manifest.is_none());
+
+        replicator.new_generation();
+        assert_eq!(replicator.manifest, Some(GenerationManifest::default()));
+
+        // a generation with too many frames is left without a manifest
+        let frame = ManifestFrame {
+            key: String::new(),
+            frameno: 1,
+            pgno: 1,
+            crc: 0,
+            size: 4096,
+            checksum: String::new(),
+        };
+        let frames = (1..=GenerationManifest::MAX_FRAMES as FrameNo + 1)
+            .map(|frameno| ManifestFrame {
+                frameno,
+                ..frame.clone()
+            })
+            .collect();
+        replicator.record_manifest_frames(frames);
+        assert!(replicator.manifest.is_none());
+        replicator.new_generation();
+        assert!(replicator.manifest.is_some());
+
+        // a generation reused after a restore has no manifest, so finalizing it sends no request
+        replicator.set_generation(Replicator::generate_generation());
+        assert!(replicator.manifest.is_none());
+        replicator.finalize_generation().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn drain_keeps_uncommitted_frames() {
+        let mut replicator = test_replicator().await;
+        replicator.register_db("test.db");
+        replicator.set_page_size(4096).unwrap();
+        replicator.register_last_valid_frame(2);
+
+        // Without a backlog, draining sends no request
+        for pgno in 1..=2 {
+            replicator.write(pgno, &[pgno as u8; 4096]).unwrap();
+        }
+        let last_frame = replicator.drain(Duration::from_secs(1)).await.unwrap();
+        assert_eq!(last_frame, 2);
+        // Frames of the transaction in progress are neither uploaded nor dropped
+        assert_eq!(replicator.buffered_bytes(), 2 * 4096);
+        assert_eq!(replicator.peek_last_valid_frame(), 4);
+    }
+
+    #[tokio::test]
+    async fn buffer_limit() {
+        let mut replicator = test_replicator().await;
+        replicator.register_db("test.db");
+        replicator.set_page_size(4096).unwrap();
+        replicator.max_buffered_bytes = Some(2 * 4096);
+
+        // a batch over the limit is rejected as a whole
+        assert!(replicator.check_buffer_limit(3 * 4096).is_err());
+        assert_eq!(replicator.buffered_bytes(), 0);
+        replicator.check_buffer_limit(2 * 4096).unwrap();
+
+        replicator.write(1, &[1u8; 4096]).unwrap();
+        replicator.write(2, &[2u8; 4096]).unwrap();
+        let err = replicator.write(3, &[3u8; 4096]).unwrap_err();
+        assert!(err.to_string().contains("exceeds the limit of 8192 bytes"));
+        assert_eq!(replicator.buffered_bytes(), 2 * 4096);
+
+        // rolling back frees the space again
+        replicator.rollback_to_frame(1);
+        assert_eq!(replicator.buffered_bytes(), 4096);
+        replicator.write(2, &[2u8; 4096]).unwrap();
+    }
+
+    #[tokio::test]
+    async fn drain_finalizes_failed_commit() {
+        let s3 = MockS3::start().await;
+        let dir = test_dir();
+        let mut replicator = mock_replicator(&s3, &dir.join("data")).await;
+        replicator.set_page_size(4096).unwrap();
+        let generation = backup(&mut replicator, &db_file(4096, 2, 1, 1), &[&[(2, 2)]]).await;
+        let consistent_key = format!("data-{generation}/.consistent");
+
+        // the transaction is committed locally, but marking it as consistent fails
+        replicator.write(2, &[3; 4096]).unwrap();
+        let last_frame = replicator.flush().await.unwrap();
+        s3.fail(consistent_key.clone(), 403);
+        assert!(replicator
+            .finalize_commit(last_frame, [0, 0])
+            .await
+            .is_err());
+
+        s3.recover(&consistent_key);
+        assert_eq!(
+            replicator.drain(Duration::from_secs(5)).await.unwrap(),
+            last_frame
+        );
+        let mut consistent = s3.get(&consistent_key).unwrap().body;
+        let (stored_frame, _) = Replicator::parse_consistent_info(&mut consistent).unwrap();
+        assert_eq!(stored_frame, last_frame);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    // Writes a main database file and a WAL with a single frame, with given page sizes
+    fn write_db_and_wal(db_path: &Path, db_page_size: u16, wal_page_size: u32) {
+        let mut db = vec![0u8; db_page_size as usize];
+        db[16..18].copy_from_slice(&db_page_size.to_be_bytes());
+        std::fs::write(db_path, db).unwrap();
+
+        let mut wal = vec![0u8; 32 + 24 + wal_page_size as usize];
+        wal[8..12].copy_from_slice(&wal_page_size.to_be_bytes());
+        // page 1, committed
+        wal[32..36].copy_from_slice(&1u32.to_be_bytes());
+        wal[36..40].copy_from_slice(&1u32.to_be_bytes());
+        std::fs::write(format!("{}-wal", db_path.display()), wal).unwrap();
+    }
+
+    #[tokio::test]
+    async fn replicate_wal_refuses_page_size_mismatch() {
+        let db_path =
+            std::env::temp_dir().join(format!("{}.db", Replicator::generate_generation()));
+        let mut replicator = test_replicator().await;
+        replicator.register_db(db_path.to_str().unwrap());
+        replicator.set_page_size(4096).unwrap();
+
+        // the main database file header disagrees with the WAL and the replicator
+        write_db_and_wal(&db_path, 1024, 4096);
+        let err = replicator.maybe_replicate_wal().await.unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("Main database file page size 1024"));
+
+        // the WAL header disagrees with the main database file and the replicator
+        write_db_and_wal(&db_path, 4096, 1024);
+        let err = replicator.maybe_replicate_wal().await.unwrap_err();
+        assert!(err.to_string().contains("Local WAL page size 1024"));
+
+        // nothing was submitted for replication
+        assert_eq!(replicator.peek_last_valid_frame(), 0);
+        assert_eq!(replicator.buffered_bytes(), 0);
+
+        std::fs::remove_file(&db_path).unwrap();
+        std::fs::remove_file(format!("{}-wal", db_path.display())).unwrap();
+    }
+
+    // A replicator of given database file, backed by a mock S3 server
+    async fn mock_replicator(s3: &MockS3, db_path: &Path) -> Replicator {
+        let mut replicator = test_replicator().await;
+        replicator.client = s3.client().await;
+        replicator.register_db(db_path.to_str().unwrap());
+        replicator
+    }
+
+    // A fresh directory for the database files of a single test
+    fn test_dir() -> PathBuf {
+        let dir = std::env::temp_dir().join(Replicator::generate_generation().to_string());
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    // Contents of a database file of `pages` pages filled with `fill`, with given page size
+    // and change counter in its header
+    fn db_file(page_size: u16, pages: usize, change_counter: u32, fill: u8) -> Vec<u8> {
+        let mut db = vec![fill; page_size as usize * pages];
+        db[16..18].copy_from_slice(&page_size.to_be_bytes());
+        db[24..28].copy_from_slice(&change_counter.to_be_bytes());
+        db
+    }
+
+    // Contents of a WAL file holding given pages, as (page number, fill byte), one per frame
+    fn wal_file(page_size: u32, frames: &[(u32, u8)]) -> Vec<u8> {
+        let mut wal = vec![0u8; 32];
+        wal[8..12].copy_from_slice(&page_size.to_be_bytes());
+        for &(pgno, fill) in frames {
+            let mut header = [0u8; 24];
+            header[..4].copy_from_slice(&pgno.to_be_bytes());
+            wal.extend_from_slice(&header);
+            wal.extend(std::iter::repeat(fill).take(page_size as usize));
+        }
+        wal
+    }
+
+    // Snapshots given database file into a new generation and commits the transactions on
+    // top of it, each a list of pages given as (page number, fill byte)
+    async fn backup(
+        replicator: &mut Replicator,
+        db: &[u8],
+        transactions: &[&[(u32, u8)]],
+    ) -> uuid::Uuid {
+        std::fs::write(&replicator.db_path, db).unwrap();
+        replicator.new_generation();
+        replicator.snapshot_main_db_file().await.unwrap();
+        for transaction in transactions {
+            for &(pgno, fill) in transaction.iter() {
+                let page = vec![fill; replicator.page_size];
+                replicator.write(pgno, &page).unwrap();
+            }
+            let last_frame = replicator.flush().await.unwrap();
+            replicator
+                .finalize_commit(last_frame, [0, 0])
+                .await
+                .unwrap();
+        }
+        replicator.generation
+    }
+
+    #[tokio::test]
+    async fn restore_skips_incomplete_newest_generation() {
+        let s3 = MockS3::start().await;
+        let dir = test_dir();
+        let db_path = dir.join("data");
+        let mut writer = mock_replicator(&s3, &db_path).await;
+        writer.set_page_size(4096).unwrap();
+        let snapshot = db_file(4096, 2, 1, 1);
+        let complete = backup(&mut writer, &snapshot, &[&[(2, 2)]]).await;
+
+        // a newer generation, whose upload was interrupted before anything marked it complete
+        tokio::time::sleep(Duration::from_millis(2)).await;
+        let incomplete = Replicator::generate_generation();
+        s3.put(format!("data-{incomplete}/db.db"), db_file(4096, 2, 2, 3));
+        s3.put(
+            format!("data-{incomplete}/.changecounter"),
+            2u32.to_be_bytes().to_vec(),
+        );
+
+        std::fs::remove_file(&db_path).unwrap();
+        let mut restorer = mock_replicator(&s3, &db_path).await;
+        assert_eq!(
+            restorer.find_restore_generation().await.unwrap(),
+            Some(complete)
+        );
+        restorer.restore().await.unwrap();
+        let restored = std::fs::read(&db_path).unwrap();
+        assert_eq!(restored[..4096], snapshot[..4096]);
+        assert_eq!(restored[4096..], [2u8; 4096]);
+
+        // any other error checking a generation fails the restore, instead of skipping it
+        s3.fail(format!("data-{incomplete}/.consistent"), 403);
+        assert!(restorer.find_restore_generation().await.is_err());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn lease_kept_until_released() {
+        let s3 = MockS3::start().await;
+        let (dir, other_dir) = (test_dir(), test_dir());
+        let mut holder = mock_replicator(&s3, &dir.join("data")).await;
+        holder.lease_ttl = Some(Duration::from_millis(300));
+        holder.acquire_lease().await.unwrap();
+
+        // the lease is extended while idle, past its time to live
+        tokio::time::sleep(Duration::from_millis(600)).await;
+        let mut other = mock_replicator(&s3, &other_dir.join("data")).await;
+        other.lease_ttl = Some(Duration::from_millis(300));
+        assert!(other.acquire_lease().await.is_err());
+
+        // the same instance, restarted, takes its own lease back
+        let mut restarted = mock_replicator(&s3, &dir.join("data")).await;
+        restarted.lease_ttl = Some(Duration::from_millis(300));
+        restarted.acquire_lease().await.unwrap();
+        restarted.release_lease().await.unwrap();
+
+        holder.release_lease().await.unwrap();
+        other.acquire_lease().await.unwrap();
+        other.release_lease().await.unwrap();
+
+        std::fs::remove_dir_all(&dir).unwrap();
+        std::fs::remove_dir_all(&other_dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn restore_catches_up_with_remote_frames() {
+        let s3 = MockS3::start().await;
+        let dir = test_dir();
+        let db_path = dir.join("data");
+        let wal_path = dir.join("data-wal");
+        let mut writer = mock_replicator(&s3, &db_path).await;
+        writer.set_page_size(4096).unwrap();
+        let snapshot = db_file(4096, 2, 1, 1);
+        let generation = backup(&mut writer, &snapshot, &[&[(2, 2)], &[(2, 3)], &[(2, 4)]]).await;
+        let frames = s3.keys(&format!("data-{generation}/0"));
+        assert_eq!(frames.len(), 3);
+
+        // the local WAL holds the first two frames, so only the last one is fetched
+        std::fs::write(&wal_path, wal_file(4096, &[(2, 2), (2, 3)])).unwrap();
+        s3.take_fetched();
+        let mut restorer = mock_replicator(&s3, &db_path).await;
+        restorer.restore().await.unwrap();
+        let fetched: Vec<_> = s3
+            .take_fetched()
+            .into_iter()
+            .filter(|key| key.ends_with("db.db") || frames.contains(key))
+            .collect();
+        assert_eq!(fetched, vec![frames[2].clone()]);
+        let restored = std::fs::read(&db_path).unwrap();
+        assert_eq!(restored[..4096], snapshot[..4096]);
+        assert_eq!(restored[4096..], [4u8; 4096]);
+        // the file as it was before patching it
+        let backup_path = dir.join("data.bottomless.backup");
+        assert_eq!(std::fs::read(&backup_path).unwrap(), snapshot);
+        assert!(!wal_path.exists());
+
+        // a local WAL which diverged from the remote one is not applied
+        std::fs::write(&db_path, &snapshot).unwrap();
+        std::fs::write(&wal_path, wal_file(4096, &[(2, 2), (2, 9)])).unwrap();
+        let mut restorer = mock_replicator(&s3, &db_path).await;
+        restorer.restore().await.unwrap();
+        let fetched: Vec<_> = s3
+            .take_fetched()
+            .into_iter()
+            .filter(|key| key.ends_with("db.db") || frames.contains(key))
+            .collect();
+        assert_eq!(fetched.len(), 4);
+        let restored = std::fs::read(&db_path).unwrap();
+        assert_eq!(restored[..4096], snapshot[..4096]);
+        assert_eq!(restored[4096..], [4u8; 4096]);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn restore_retries_listing_from_the_start() {
+        let s3 = MockS3::start().await;
+        let dir = test_dir();
+        let db_path = dir.join("data");
+        let mut writer = mock_replicator(&s3, &db_path).await;
+        writer.set_page_size(4096).unwrap();
+        let snapshot = db_file(4096, 2, 1, 1);
+        let generation = backup(&mut writer, &snapshot, &[&[(2, 2)], &[(2, 3)], &[(2, 4)]]).await;
+
+        // the newest frame shows up in the second listing only, and the first one ends with
+        // the snapshot, listed after all frames
+        let frames = s3.keys(&format!("data-{generation}/0"));
+        assert_eq!(frames.len(), 3);
+        s3.hide_from_listings(frames[2].clone(), 1);
+
+        std::fs::remove_file(&db_path).unwrap();
+        let mut restorer = mock_replicator(&s3, &db_path).await;
+        restorer.list_page_size = 2;
+        restorer.restore().await.unwrap();
+        let restored = std::fs::read(&db_path).unwrap();
+        assert_eq!(restored[..4096], snapshot[..4096]);
+        assert_eq!(restored[4096..], [4u8; 4096]);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn restore_rejects_corrupted_objects() {
+        let s3 = MockS3::start().await;
+        let dir = test_dir();
+        let db_path = dir.join("data");
+        let mut writer = mock_replicator(&s3, &db_path).await;
+        writer.object_checksums = true;
+        writer.set_page_size(4096).unwrap();
+        let snapshot = db_file(4096, 2, 1, 1);
+        let generation = backup(&mut writer, &snapshot, &[&[(2, 2)], &[(2, 3)]]).await;
+        let frames = s3.keys(&format!("data-{generation}/0"));
+        assert_eq!(frames.len(), 2);
+        assert!(s3
+            .get(&frames[0])
+            .unwrap()
+            .metadata
+            .contains_key(CHECKSUM_METADATA_KEY));
+
+        // the whole object is replaced by another, well-formed page
+        s3.replace_body(&frames[0], vec![9u8; 4096]);
+        std::fs::remove_file(&db_path).unwrap();
+        let mut restorer = mock_replicator(&s3, &db_path).await;
+        restorer.object_checksums = true;
+        let err = restorer.restore().await.unwrap_err();
+        assert!(err.to_string().contains("Checksum mismatch"));
+        assert!(err.to_string().contains(&frames[0]));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn restore_keeps_last_write_of_a_page() {
+        let s3 = MockS3::start().await;
+        let dir = test_dir();
+        let db_path = dir.join("data");
+        let mut writer = mock_replicator(&s3, &db_path).await;
+        writer.set_page_size(4096).unwrap();
+        let snapshot = db_file(4096, 3, 1, 1);
+        // page 2 is written twice within a single transaction
+        let generation = backup(&mut writer, &snapshot, &[&[(2, 2), (3, 3), (2, 4)]]).await;
+        assert_eq!(s3.keys(&format!("data-{generation}/0")).len(), 3);
+
+        std::fs::remove_file(&db_path).unwrap();
+        let mut restorer = mock_replicator(&s3, &db_path).await;
+        restorer.restore().await.unwrap();
+        let restored = std::fs::read(&db_path).unwrap();
+        assert_eq!(restored[4096..8192], [4u8; 4096]);
+        assert_eq!(restored[8192..], [3u8; 4096]);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn fetch_page_matches_restore() {
+        let s3 = MockS3::start().await;
+        let dir = test_dir();
+        let db_path = dir.join("data");
+        let mut writer = mock_replicator(&s3, &db_path).await;
+        writer.use_compression = true;
+        writer.object_checksums = true;
+        writer.set_page_size(4096).unwrap();
+        let snapshot = db_file(4096, 3, 1, 1);
+        let generation = backup(&mut writer, &snapshot, &[&[(2, 2)], &[(2, 3), (3, 4)]]).await;
+        let frames = s3.keys(&format!("data-{generation}/0"));
+        assert_eq!(frames.len(), 3);
+        // frame 2 uploaded again, uncompressed, by a writer which restarted before committing it
+        s3.put(
+            format!("data-{generation}/{:020}-{:012}-{:016x}", 2, 2, 0xdead),
+            vec![5u8; 4096],
+        );
+
+        // a reader which doesn't compress still reads the generation the way it was written
+        std::fs::remove_file(&db_path).unwrap();
+        let mut reader = mock_replicator(&s3, &db_path).await;
+        reader.object_checksums = true;
+        let page = reader.fetch_page(generation, 2, None).await.unwrap();
+        assert_eq!(page[..], [5u8; 4096]);
+        let page = reader.fetch_page(generation, 2, Some(1)).await.unwrap();
+        assert_eq!(page[..], [2u8; 4096]);
+        let page = reader.fetch_page(generation, 1, None).await.unwrap();
+        assert_eq!(page[..], snapshot[..4096]);
+        reader.restore().await.unwrap();
+        let restored = std::fs::read(&db_path).unwrap();
+        assert_eq!(restored[..4096], snapshot[..4096]);
+        assert_eq!(restored[4096..8192], [5u8; 4096]);
+        assert_eq!(restored[8192..], [4u8; 4096]);
+
+        // frames are checked against their checksums, as when restoring them
+        s3.replace_body(&frames[2], vec![9u8; 4096]);
+        let err = reader.fetch_page(generation, 3, None).await.unwrap_err();
+        assert!(err.to_string().contains("Checksum mismatch"));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn restore_verifies_restored_frames() {
+        let s3 = MockS3::start().await;
+        let dir = test_dir();
+        let db_path = dir.join("data");
+        let wal_path = dir.join("data-wal");
+        let mut writer = mock_replicator(&s3, &db_path).await;
+        writer.set_page_size(4096).unwrap();
+        let snapshot = db_file(4096, 2, 1, 1);
+        let generation = backup(&mut writer, &snapshot, &[&[(2, 2)]]).await;
+
+        // a restarted writer reuses the generation and continues its CRC chain
+        std::fs::write(&wal_path, wal_file(4096, &[(2, 2)])).unwrap();
+        let mut resumed = mock_replicator(&s3, &db_path).await;
+        assert!(matches!(
+            resumed.restore().await.unwrap(),
+            RestoreAction::ReuseGeneration(reused) if reused == generation
+        ));
+        resumed.write(2, &[3u8; 4096]).unwrap();
+        let last_frame = resumed.flush().await.unwrap();
+        assert_eq!(last_frame, 2);
+        resumed.finalize_commit(last_frame, [0, 0]).await.unwrap();
+
+        std::fs::remove_file(&db_path).unwrap();
+        std::fs::remove_file(&wal_path).unwrap();
+        let mut restorer = mock_replicator(&s3, &db_path).await;
+        restorer.restore_options.verify_frames = true;
+        restorer.restore().await.unwrap();
+        let restored = std::fs::read(&db_path).unwrap();
+        assert_eq!(restored[4096..], [3u8; 4096]);
+
+        // a frame whose contents were replaced, while its key still holds the original CRC
+        let frames = s3.keys(&format!("data-{generation}/0"));
+        assert_eq!(frames.len(), 2);
+        s3.put(frames[0].clone(), vec![9u8; 4096]);
+        std::fs::remove_file(&db_path).unwrap();
+        let mut restorer = mock_replicator(&s3, &db_path).await;
+        restorer.verify_crc = false;
+        restorer.restore_options.verify_frames = true;
+        let err = restorer.restore().await.unwrap_err();
+        assert!(err.to_string().contains("do not match"));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn regressing_commit_leaves_consistent_frame() {
+        let s3 = MockS3::start().await;
+        let dir = test_dir();
+        let db_path = dir.join("data");
+        let mut writer = mock_replicator(&s3, &db_path).await;
+        writer.set_page_size(4096).unwrap();
+        let generation = backup(
+            &mut writer,
+            &db_file(4096, 2, 1, 1),
+            &[&[(1, 2)], &[(2, 3)]],
+        )
+        .await;
+        let consistent_key = format!("data-{generation}/.consistent");
+        let consistent = s3.get(&consistent_key).unwrap().body;
+        s3.take_fetched();
+
+        // the last committed frame is known without asking S3
+        assert!(writer.finalize_commit(1, [0, 0]).await.is_err());
+        assert_eq!(s3.get(&consistent_key).unwrap().body, consistent);
+        assert!(s3.take_fetched().is_empty());
+
+        // a reused generation reads it once
+        let mut resumed = mock_replicator(&s3, &db_path).await;
+        resumed.set_generation(generation);
+        assert!(resumed.finalize_commit(1, [0, 0]).await.is_err());
+        assert_eq!(s3.take_fetched(), vec![consistent_key.clone()]);
+        assert_eq!(s3.get(&consistent_key).unwrap().body, consistent);
+
+        // and fails the commit when it can't
+        let mut resumed = mock_replicator(&s3, &db_path).await;
+        resumed.set_generation(generation);
+        s3.fail(consistent_key.clone(), 503);
+        assert!(resumed.finalize_commit(3, [0, 0]).await.is_err());
+        assert_eq!(s3.get(&consistent_key).unwrap().body, consistent);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn classify_sync() {
+        let counter = [0, 0, 0, 5];
+        let newer_counter = [0, 0, 1, 0];
+
+        assert_eq!(
+            Replicator::classify_sync(counter, counter, 10, 10, true),
+            SyncStatus::InSync
+        );
+        assert_eq!(
+            Replicator::classify_sync([0; 4], [0; 4], 0, 0, true),
+            SyncStatus::InSync
+        );
+
+        assert_eq!(
+            Replicator::classify_sync(newer_counter, counter, 0, 10, true),
+            SyncStatus::LocalAhead
+        );
+        assert_eq!(
+            Replicator::classify_sync(counter, counter, 12, 10, true),
+            SyncStatus::LocalAhead
+        );
+        // no backup at all
+        assert_eq!(
+            Replicator::classify_sync(counter, [0; 4], 0, 0, true),
+            SyncStatus::LocalAhead
+        );
+
+        assert_eq!(
+            Replicator::classify_sync(counter, newer_counter, 10, 0, true),
+            SyncStatus::RemoteAhead
+        );
+        assert_eq!(
+            Replicator::classify_sync(counter, counter, 8, 10, true),
+            SyncStatus::RemoteAhead
+        );
+        // no local database
+        assert_eq!(
+            Replicator::classify_sync([0; 4], counter, 0, 0, true),
+            SyncStatus::RemoteAhead
+        );
+
+        assert_eq!(
+            Replicator::classify_sync(counter, counter, 10, 10, false),
+            SyncStatus::Diverged
+        );
+        assert_eq!(
+            Replicator::classify_sync(counter, counter, 12, 10, false),
+            SyncStatus::Diverged
+        );
+    }
+
+    #[test]
+    fn generation_frame_limit_reached() {
+        assert!(!Replicator::generation_frame_limit_reached(1_000_000, None));
+        assert!(!Replicator::generation_frame_limit_reached(0, Some(100)));
+        assert!(!Replicator::generation_frame_limit_reached(99, Some(100)));
+        assert!(Replicator::generation_frame_limit_reached(100, Some(100)));
+        assert!(Replicator::generation_frame_limit_reached(250, Some(100)));
+    }
+
+    #[test]
+    fn restore_source_bucket() {
+        assert_eq!(Replicator::restore_source_bucket("new", None, None), None);
+        assert_eq!(
+            Replicator::restore_source_bucket("new", Some("new".to_string()), None),
+            None
+        );
+        assert_eq!(
+            Replicator::restore_source_bucket("new", Some("old".to_string()), None),
+            Some("old".to_string())
+        );
+        assert_eq!(
+            Replicator::restore_source_bucket("new", None, Some("http://old:9000")),
+            Some("new".to_string())
+        );
+        assert_eq!(
+            Replicator::restore_source_bucket(
+                "new",
+                Some("new".to_string()),
+                Some("http://old:9000")
+            ),
+            Some("new".to_string())
+        );
+    }
+
+    #[test]
+    fn parse_frame_page_crc_beyond_u32() {
+        let frame_no = u32::MAX as FrameNo + 10;
+        let key = format!("db-generation/{:020}-{:012}-{:016x}", frame_no, 3, 42);
+        assert_eq!(
+            Replicator::parse_frame_page_crc(&key),
+            Some((frame_no, 3, 42))
+        );
+        // keys written before frame numbers were padded to 20 digits
+        let key = format!("db-generation/{:012}-{:012}-{:016x}", frame_no, 3, 42);
+        assert_eq!(
+            Replicator::parse_frame_page_crc(&key),
+            Some((frame_no, 3, 42))
+        );
+    }
+
+    #[tokio::test]
+    async fn restore_frame_rejects_oversized_frames() {
+        let mut replicator = test_replicator().await;
+        let mut page_buffer = Vec::new();
+        let mut main_db = std::io::Cursor::new(Vec::new());
+        let mut stats = RestoreStats::default();
+
+        // larger than any page SQLite supports, whatever the page size claimed
+        let frame = vec![0u8; 1 << 20];
+        let err = replicator
+            .restore_frame(
+                1,
+                0,
+                0,
+                &mut 0,
+                &mut page_buffer,
+                &mut main_db,
+                &mut &frame[..],
+                &mut stats,
+            )
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("larger than the page size 65536"));
+        assert!(page_buffer.len() <= Replicator::MAX_PAGE_SIZE + 1);
+        assert!(main_db.get_ref().is_empty());
+        assert_eq!(replicator.page_size, Replicator::UNSET_PAGE_SIZE);
+
+        // larger than the page size already known
+        replicator.set_page_size(4096).unwrap();
+        page_buffer.clear();
+        let frame = vec![0u8; 8192];
+        let err = replicator
+            .restore_frame(
+                1,
+                0,
+                0,
+                &mut 0,
+                &mut page_buffer,
+                &mut main_db,
+                &mut &frame[..],
+                &mut stats,
+            )
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("larger than the page size 4096"));
+        assert!(main_db.get_ref().is_empty());
+
+        page_buffer.clear();
+        let frame = vec![1u8; 4096];
+        replicator
+            .restore_frame(
+                2,
+                0,
+                0,
+                &mut 0,
+                &mut page_buffer,
+                &mut main_db,
+                &mut &frame[..],
+                &mut stats,
+            )
+            .await
+            .unwrap();
+        assert_eq!(main_db.get_ref().len(), 2 * 4096);
+
+        // larger than the configured maximum, even if it matches the page size
+        replicator.restore_options.max_page_size = 1024;
+        page_buffer.clear();
+        let err = replicator
+            .restore_frame(
+                3,
+                0,
+                0,
+                &mut 0,
+                &mut page_buffer,
+                &mut main_db,
+                &mut &frame[..],
+                &mut stats,
+            )
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("larger than the page size 1024"));
+        assert_eq!(main_db.get_ref().len(), 2 * 4096);
+
+        // the maximum must be a valid page size itself
+        let options = Options {
+            restore: RestoreOptions {
+                max_page_size: 100_000,
+                ..RestoreOptions::default()
+            },
+            ..Options::from_env()
+        };
+        assert!(Replicator::create(options).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn verify_compressed_page() {
+        let page = vec![42u8; 4096];
+        let compressed = compress(&page).await;
+        Replicator::verify_compressed_page("key", &compressed, &page)
+            .await
+            .unwrap();
+
+        let other_page = vec![7u8; 4096];
+        let corrupted = compress(&other_page).await;
+        assert!(Replicator::verify_compressed_page("key", &corrupted, &page)
+            .await
+            .is_err());
+
+        let truncated = compress(&page[..100]).await;
+        assert!(Replicator::verify_compressed_page("key", &truncated, &page)
+            .await
+            .is_err());
+    }
+}
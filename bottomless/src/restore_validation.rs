@@ -0,0 +1,306 @@
+use crate::replicator::{FrameNo, Replicator, Result, CRC_64};
+use std::ops::RangeInclusive;
+
+// Configuration of restore and of the checks it runs on the restored database
+#[derive(Clone, Debug)]
+pub struct RestoreOptions {
+    // Restore fails if the restored database header reports a different user_version
+    pub expected_user_version: Option<u32>,
+    // How many times restore lists the generation again when the listing ends before
+    // the last consistent frame, e.g. on stores with eventually consistent listings
+    pub list_retries: u32,
+    // Recomputes the CRC chain of the frames applied by restore, and fails restore if its
+    // final value differs from the one stored for the last consistent frame
+    pub verify_frames: bool,
+    // Upper bound on the rate at which restore downloads the snapshot and frames from S3,
+    // to leave bandwidth for other traffic. None means no limit.
+    pub bytes_per_sec: Option<u64>,
+    // Largest frame accepted by restore, so that a crafted backup can't make it buffer
+    // arbitrarily large objects. It must be a valid SQLite page size.
+    pub max_page_size: usize,
+}
+
+impl Default for RestoreOptions {
+    fn default() -> Self {
+        Self {
+            expected_user_version: None,
+            list_retries: 3,
+            verify_frames: false,
+            bytes_per_sec: None,
+            max_page_size: Replicator::MAX_PAGE_SIZE,
+        }
+    }
+}
+
+impl RestoreOptions {
+    // Configured with `LIBSQL_BOTTOMLESS_EXPECTED_USER_VERSION`,
+    // `LIBSQL_BOTTOMLESS_VERIFY_RESTORED_FRAMES`, `LIBSQL_BOTTOMLESS_RESTORE_BYTES_PER_SEC`
+    // and `LIBSQL_BOTTOMLESS_MAX_RESTORED_PAGE_SIZE`
+    pub fn from_env() -> Self {
+        let default = Self::default();
+        Self {
+            expected_user_version: crate::env_value("LIBSQL_BOTTOMLESS_EXPECTED_USER_VERSION"),
+            verify_frames: crate::env_flag("LIBSQL_BOTTOMLESS_VERIFY_RESTORED_FRAMES"),
+            bytes_per_sec: crate::env_value("LIBSQL_BOTTOMLESS_RESTORE_BYTES_PER_SEC"),
+            max_page_size: crate::env_value("LIBSQL_BOTTOMLESS_MAX_RESTORED_PAGE_SIZE")
+                .unwrap_or(default.max_page_size),
+            ..default
+        }
+    }
+}
+
+// A frame object listed from a generation, not downloaded yet
+#[derive(Debug)]
+pub(crate) struct ListedFrame {
+    pub frameno: FrameNo,
+    pub pgno: i32,
+    pub crc: u64,
+    pub key: String,
+    // Size and checksum of the stored object, when known from the generation manifest
+    pub size: Option<u64>,
+    pub checksum: Option<String>,
+    // Modification time of the object, in seconds and nanoseconds since the unix epoch,
+    // when known from the listing
+    pub last_modified: Option<(i64, u32)>,
+}
+
+// Sorts the frames listed from a generation by frame number, regardless of the listing
+// order, and checks that they form a contiguous range from frame 1 to the last consistent
+// frame. A frame stored more than once, e.g. uploaded again by a writer which restarted
+// before committing it, is resolved to the copy written last.
+pub(crate) fn order_restore_frames(
+    mut frames: Vec<ListedFrame>,
+    last_consistent_frame: FrameNo,
+) -> Result<Vec<ListedFrame>> {
+    frames.sort_by_key(|frame| (frame.frameno, frame.last_modified));
+    let mut ordered: Vec<ListedFrame> = Vec::with_capacity(frames.len());
+    for frame in frames {
+        match ordered.last_mut() {
+            Some(prev) if prev.frameno == frame.frameno => {
+                if prev.last_modified.is_none() || prev.last_modified == frame.last_modified {
+                    return Err(anyhow::anyhow!(
+                        "Frame {} is stored more than once, and it's unknown which copy was written last: {} and {}",
+                        frame.frameno,
+                        prev.key,
+                        frame.key
+                    ));
+                }
+                tracing::warn!(
+                    "Frame {} is stored more than once, restoring {} written last instead of {}",
+                    frame.frameno,
+                    frame.key,
+                    prev.key
+                );
+                *prev = frame;
+            }
+            _ => ordered.push(frame),
+        }
+    }
+    let mut next_frame = 1;
+    for frame in &ordered {
+        if frame.frameno != next_frame {
+            return Err(anyhow::anyhow!(
+                "Frames {} to {} are missing from the generation",
+                next_frame,
+                frame.frameno - 1
+            ));
+        }
+        next_frame += 1;
+    }
+    let frames = ordered;
+    let last_frame = frames.last().map(|frame| frame.frameno).unwrap_or(0);
+    if last_frame < last_consistent_frame {
+        return Err(anyhow::anyhow!(
+            "Frames {} to {} are missing from the generation",
+            last_frame + 1,
+            last_consistent_frame
+        ));
+    }
+    Ok(frames)
+}
+
+// Returns the ranges of frames, up to the last consistent one, that are missing from the
+// given frame numbers. Frames of a generation are numbered from 1.
+pub(crate) fn frame_gaps(
+    mut frames: Vec<FrameNo>,
+    last_consistent_frame: FrameNo,
+) -> Vec<RangeInclusive<FrameNo>> {
+    frames.sort_unstable();
+    frames.dedup();
+    let mut gaps = Vec::new();
+    let mut next_frame = 1;
+    for frame in frames
+        .into_iter()
+        .take_while(|&frame| frame <= last_consistent_frame)
+    {
+        if frame > next_frame {
+            gaps.push(next_frame..=frame - 1);
+        }
+        next_frame = frame + 1;
+    }
+    if next_frame <= last_consistent_frame {
+        gaps.push(next_frame..=last_consistent_frame);
+    }
+    gaps
+}
+
+// Checks the size of a frame object against the one recorded in the generation manifest,
+// before downloading it
+pub(crate) fn verify_object_size(key: &str, size: u64, expected: u64) -> Result<()> {
+    if size != expected {
+        return Err(anyhow::anyhow!(
+            "Size of {} is {} bytes, but the manifest records {} bytes",
+            key,
+            size,
+            expected
+        ));
+    }
+    Ok(())
+}
+
+// Checks the stored page object against the checksum kept in its metadata
+pub(crate) fn verify_object_checksum(key: &str, body: &[u8], checksum: &str) -> Result<()> {
+    let expected = u64::from_str_radix(checksum, 16).map_err(|e| {
+        anyhow::anyhow!(
+            "Invalid checksum {:?} in metadata of {}: {}",
+            checksum,
+            key,
+            e
+        )
+    })?;
+    let actual = CRC_64.checksum(body);
+    if actual != expected {
+        return Err(anyhow::anyhow!(
+            "Checksum mismatch for {}: {:016x} != {:016x} (expected)",
+            key,
+            actual,
+            expected
+        ));
+    }
+    Ok(())
+}
+
+// Reads the page held by a frame object into given buffer and returns its size. A frame
+// holds a single page, so reading stops right past the largest page accepted: an oversized
+// object, tampered with or decompressing into more than it claims, is never fully buffered.
+pub(crate) async fn read_frame_page(
+    pgno: i32,
+    max_page_size: usize,
+    reader: &mut (impl tokio::io::AsyncRead + std::marker::Unpin),
+    page_buffer: &mut Vec<u8>,
+) -> Result<u64> {
+    use tokio::io::AsyncReadExt;
+
+    let mut limited_reader = reader.take(max_page_size as u64 + 1);
+    let page_size = tokio::io::copy(&mut limited_reader, page_buffer).await?;
+    if page_size > max_page_size as u64 {
+        return Err(anyhow::anyhow!(
+            "Frame for page {} is larger than the page size {}",
+            pgno,
+            max_page_size
+        ));
+    }
+    Ok(page_size)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn listed_frames(frames: &[FrameNo]) -> Vec<ListedFrame> {
+        frames
+            .iter()
+            .map(|&frameno| ListedFrame {
+                frameno,
+                pgno: 1,
+                crc: 0,
+                key: format!("db-generation/{:012}-{:012}-{:016x}", frameno, 1, 0),
+                size: None,
+                checksum: None,
+                last_modified: None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn order_restore_frames() {
+        let frameno = |frames: Vec<ListedFrame>| -> Vec<FrameNo> {
+            frames.into_iter().map(|frame| frame.frameno).collect()
+        };
+
+        // shuffled listing order, e.g. across pages or from a mixed key scheme
+        let ordered = super::order_restore_frames(listed_frames(&[3, 1, 5, 2, 4]), 5).unwrap();
+        assert_eq!(frameno(ordered), vec![1, 2, 3, 4, 5]);
+        assert!(super::order_restore_frames(Vec::new(), 0)
+            .unwrap()
+            .is_empty());
+
+        // missing frames, at the start of the range, inside it or at its end
+        let err = super::order_restore_frames(listed_frames(&[8, 7, 9]), 9).unwrap_err();
+        assert!(err.to_string().contains("Frames 1 to 6 are missing"));
+        assert!(super::order_restore_frames(listed_frames(&[4, 1, 2, 5]), 5).is_err());
+        assert!(super::order_restore_frames(listed_frames(&[2, 1, 3]), 4).is_err());
+        assert!(super::order_restore_frames(Vec::new(), 1).is_err());
+
+        // duplicate frames resolve to the copy written last, whatever the listing order
+        let mut frames = listed_frames(&[1, 2, 2, 3]);
+        frames[1].last_modified = Some((1700000001, 0));
+        frames[2].last_modified = Some((1700000000, 500));
+        frames[2].key.push_str("-old");
+        frames.swap(1, 2);
+        let ordered = super::order_restore_frames(frames, 3).unwrap();
+        assert_eq!(ordered.len(), 3);
+        assert_eq!(ordered[1].last_modified, Some((1700000001, 0)));
+        assert!(!ordered[1].key.ends_with("-old"));
+        // unless there's no telling which one that is
+        assert!(super::order_restore_frames(listed_frames(&[1, 2, 2, 3]), 3).is_err());
+        let mut frames = listed_frames(&[1, 2, 2, 3]);
+        frames[1].last_modified = Some((1700000000, 0));
+        frames[2].last_modified = Some((1700000000, 0));
+        assert!(super::order_restore_frames(frames, 3).is_err());
+
+        // key order matches frame order thanks to zero-padding, up to the largest frame number
+        let keys = [9u64, 10, 1000, 1_000_000_000_000, FrameNo::MAX]
+            .map(|frame| format!("{:020}-{:012}-{:016x}", frame, 1, 0));
+        let mut sorted = keys.clone();
+        sorted.sort();
+        assert_eq!(keys, sorted);
+    }
+
+    #[test]
+    fn verify_object_checksum() {
+        let key =
+            "db-00000000-0000-0000-0000-000000000000/000000000001-000000000001-0000000000000000";
+        let body = vec![7u8; 4096];
+        let checksum = format!("{:016x}", CRC_64.checksum(&body));
+        assert!(super::verify_object_checksum(key, &body, &checksum).is_ok());
+
+        let mut corrupted = body.clone();
+        corrupted[100] ^= 0xff;
+        let err = super::verify_object_checksum(key, &corrupted, &checksum).unwrap_err();
+        assert!(err.to_string().contains(key));
+
+        assert!(super::verify_object_checksum(key, &body, "not a checksum").is_err());
+    }
+
+    #[test]
+    fn verify_object_size() {
+        assert!(super::verify_object_size("key", 4096, 4096).is_ok());
+        // the object was truncated or replaced after the manifest was written
+        assert!(super::verify_object_size("key", 0, 4096).is_err());
+    }
+
+    #[test]
+    fn frame_gaps() {
+        assert!(super::frame_gaps(vec![3, 1, 2], 3).is_empty());
+        assert!(super::frame_gaps(Vec::new(), 0).is_empty());
+        // frames past the last consistent one are not part of the backup yet
+        assert!(super::frame_gaps(vec![1, 2, 3, 5], 3).is_empty());
+        // duplicates don't hide a gap
+        assert_eq!(super::frame_gaps(vec![1, 1, 3], 3), vec![2..=2]);
+        assert_eq!(
+            super::frame_gaps(vec![2, 3, 6, 7], 9),
+            vec![1..=1, 4..=5, 8..=9]
+        );
+    }
+}
@@ -0,0 +1,249 @@
+use std::future::Future;
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use rusqlite::OpenFlags;
+
+use crate::stats::Stats;
+
+/// Periodically checks that the backup of the database can be restored: `restore` is asked to
+/// restore the backup into a database file in a temporary directory, which is then checked for
+/// corruption and removed. The outcome is recorded in `stats`.
+pub async fn run_backup_check<F, Fut>(
+    db_path: PathBuf,
+    stats: Stats,
+    period: Duration,
+    restore: F,
+) -> anyhow::Result<()>
+where
+    F: Fn(PathBuf) -> Fut,
+    Fut: Future<Output = anyhow::Result<()>>,
+{
+    let mut interval = tokio::time::interval(period);
+    // the first tick completes immediately, there's no point in checking the backup on startup
+    interval.tick().await;
+    loop {
+        interval.tick().await;
+        let start = Instant::now();
+        let result = check_backup(&db_path, &restore).await;
+        let duration = start.elapsed();
+        match result {
+            Ok(()) => tracing::info!("backup restored and checked in {duration:?}"),
+            Err(ref e) => tracing::error!("backup check failed after {duration:?}: {e}"),
+        }
+        stats.record_backup_check(result.is_ok(), duration);
+    }
+}
+
+async fn check_backup<F, Fut>(db_path: &Path, restore: &F) -> anyhow::Result<()>
+where
+    F: Fn(PathBuf) -> Fut,
+    Fut: Future<Output = anyhow::Result<()>>,
+{
+    // restored next to the database, so that a large backup doesn't fill up a small tmpfs
+    let dir = tempfile::Builder::new()
+        .prefix("backup_check")
+        .tempdir_in(db_path)?;
+    let restored_path = dir.path().join("data");
+    restore(restored_path.clone()).await?;
+
+    let result = tokio::task::spawn_blocking(move || quick_check(&restored_path)).await?;
+    dir.close()?;
+    result
+}
+
+fn quick_check(path: &Path) -> anyhow::Result<()> {
+    let conn = rusqlite::Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+    let mut stmt = conn.prepare("PRAGMA quick_check")?;
+    let problems = stmt
+        .query_map([], |row| row.get::<_, String>(0))?
+        .collect::<Result<Vec<_>, _>>()?;
+    match problems.as_slice() {
+        [ok] if ok == "ok" => Ok(()),
+        _ => anyhow::bail!("restored database is corrupted: {}", problems.join("; ")),
+    }
+}
+
+/// Periodically checks that no frame is missing from the backup of the database: `find_gaps` is
+/// asked for the ranges of frames missing from the backup, and `stats` flags the backup as long
+/// as any is found, so that the problem is caught before a restore is attempted.
+pub async fn run_frame_gap_check<F, Fut>(
+    stats: Stats,
+    period: Duration,
+    find_gaps: F,
+) -> anyhow::Result<()>
+where
+    F: Fn() -> Fut,
+    Fut: Future<Output = anyhow::Result<Vec<RangeInclusive<u64>>>>,
+{
+    let mut interval = tokio::time::interval(period);
+    loop {
+        interval.tick().await;
+        match find_gaps().await {
+            Ok(gaps) if gaps.is_empty() => {
+                tracing::debug!("no frame is missing from the backup");
+                stats.set_backup_frame_gaps(false);
+            }
+            Ok(gaps) => {
+                let gaps: Vec<_> = gaps
+                    .iter()
+                    .map(|gap| format!("{}-{}", gap.start(), gap.end()))
+                    .collect();
+                tracing::error!("frames missing from the backup: {}", gaps.join(", "));
+                stats.set_backup_frame_gaps(true);
+            }
+            // the outcome of the previous check still stands
+            Err(e) => tracing::warn!("failed to check the backup for missing frames: {e}"),
+        }
+    }
+}
+
+/// Returns the ranges of frames missing from the newest generation of the bottomless backup of
+/// the database in `db_path`.
+pub async fn find_bottomless_frame_gaps(
+    db_path: PathBuf,
+) -> anyhow::Result<Vec<RangeInclusive<u64>>> {
+    let path = db_path.join("data");
+    let path = path
+        .to_str()
+        .ok_or_else(|| anyhow::anyhow!("invalid path: {}", path.display()))?
+        .to_string();
+    let mut replicator = bottomless::replicator::Replicator::new().await?;
+    replicator.register_db(path);
+    match replicator.find_newest_generation().await {
+        Some(generation) => replicator.find_frame_gaps(&generation).await,
+        None => Ok(Vec::new()),
+    }
+}
+
+/// Restores the newest generation of the bottomless backup into `path`.
+pub async fn restore_bottomless_backup(path: PathBuf) -> anyhow::Result<()> {
+    let path = path
+        .to_str()
+        .ok_or_else(|| anyhow::anyhow!("invalid path: {}", path.display()))?
+        .to_string();
+    let mut replicator = bottomless::replicator::Replicator::new().await?;
+    replicator.register_db(path);
+    replicator.restore().await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+
+    async fn wait_for_check(stats: &Stats) -> (bool, Duration) {
+        loop {
+            if let Some(check) = stats.last_backup_check() {
+                return check;
+            }
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn restorable_backup_passes() {
+        let tmp = tempfile::tempdir().unwrap();
+        let stats = Stats::new(tmp.path()).unwrap();
+        let check = tokio::spawn(run_backup_check(
+            tmp.path().to_path_buf(),
+            stats.clone(),
+            Duration::from_millis(10),
+            |path: PathBuf| async move {
+                let conn = rusqlite::Connection::open(path)?;
+                conn.execute_batch("CREATE TABLE test (x); INSERT INTO test VALUES (1);")?;
+                Ok::<_, anyhow::Error>(())
+            },
+        ));
+
+        let (passed, _) = tokio::time::timeout(Duration::from_secs(5), wait_for_check(&stats))
+            .await
+            .unwrap();
+        check.abort();
+        let _ = check.await;
+        assert!(passed);
+        // the restored database is cleaned up
+        assert_eq!(std::fs::read_dir(tmp.path()).unwrap().count(), 1);
+    }
+
+    #[tokio::test]
+    async fn corrupted_backup_fails() {
+        let tmp = tempfile::tempdir().unwrap();
+        let stats = Stats::new(tmp.path()).unwrap();
+        let check = tokio::spawn(run_backup_check(
+            tmp.path().to_path_buf(),
+            stats.clone(),
+            Duration::from_millis(10),
+            |path: PathBuf| async move {
+                std::fs::write(path, b"definitely not a database")?;
+                Ok::<_, anyhow::Error>(())
+            },
+        ));
+
+        let (passed, _) = tokio::time::timeout(Duration::from_secs(5), wait_for_check(&stats))
+            .await
+            .unwrap();
+        check.abort();
+        assert!(!passed);
+    }
+
+    #[tokio::test]
+    async fn failed_restore_fails() {
+        let tmp = tempfile::tempdir().unwrap();
+        let stats = Stats::new(tmp.path()).unwrap();
+        let check = tokio::spawn(run_backup_check(
+            tmp.path().to_path_buf(),
+            stats.clone(),
+            Duration::from_millis(10),
+            |_: PathBuf| async move { Err::<(), _>(anyhow::anyhow!("no backup found")) },
+        ));
+
+        let (passed, _) = tokio::time::timeout(Duration::from_secs(5), wait_for_check(&stats))
+            .await
+            .unwrap();
+        check.abort();
+        assert!(!passed);
+    }
+
+    #[tokio::test]
+    async fn missing_frame_is_flagged() {
+        let tmp = tempfile::tempdir().unwrap();
+        let stats = Stats::new(tmp.path()).unwrap();
+        // frame numbers of the objects stored in the backup, the last one is consistent
+        let stored = Arc::new(Mutex::new(vec![1u64, 2, 3, 4, 5]));
+        let check = tokio::spawn(run_frame_gap_check(
+            stats.clone(),
+            Duration::from_millis(10),
+            {
+                let stored = stored.clone();
+                move || {
+                    let frames = stored.lock().unwrap().clone();
+                    async move {
+                        let gaps: Vec<_> = (1..=5)
+                            .filter(|frame| !frames.contains(frame))
+                            .map(|frame| frame..=frame)
+                            .collect();
+                        Ok::<_, anyhow::Error>(gaps)
+                    }
+                }
+            },
+        ));
+
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!stats.backup_frame_gaps());
+
+        stored.lock().unwrap().retain(|&frame| frame != 3);
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while !stats.backup_frame_gaps() {
+                tokio::time::sleep(Duration::from_millis(5)).await;
+            }
+        })
+        .await
+        .unwrap();
+        check.abort();
+    }
+}
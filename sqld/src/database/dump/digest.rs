@@ -0,0 +1,127 @@
+//! Deterministic digest of the contents of a database, to check whether two databases are
+//! identical.
+use rusqlite::types::ValueRef;
+use sha2::{Digest, Sha256};
+
+/// Computes a SHA-256 digest over the schema and the contents of all the tables of the database.
+/// Rows are hashed in a canonical order, so the digest doesn't depend on the order in which
+/// they were inserted, nor on the physical layout of the database file.
+pub fn content_digest(conn: &rusqlite::Connection) -> anyhow::Result<[u8; 32]> {
+    let mut hasher = Sha256::new();
+
+    let mut tables = Vec::new();
+    let mut schema = conn.prepare(
+        "SELECT type, name, tbl_name, sql FROM sqlite_schema
+         WHERE name NOT LIKE 'sqlite_%' ORDER BY type, name",
+    )?;
+    let mut rows = schema.query(())?;
+    while let Some(row) = rows.next()? {
+        for i in 0..4 {
+            hash_value(&mut hasher, row.get_ref(i)?);
+        }
+        let ty: String = row.get(0)?;
+        let sql: Option<String> = row.get(3)?;
+        let is_virtual = sql.map_or(false, |sql| sql.starts_with("CREATE VIRTUAL TABLE"));
+        if ty == "table" && !is_virtual {
+            tables.push(row.get::<_, String>(1)?);
+        }
+    }
+
+    for table in tables {
+        hash_table(&mut hasher, conn, &table)?;
+    }
+
+    Ok(hasher.finalize().into())
+}
+
+fn hash_table(hasher: &mut Sha256, conn: &rusqlite::Connection, table: &str) -> anyhow::Result<()> {
+    let quoted = format!("\"{}\"", table.replace('"', "\"\""));
+    let column_count = conn
+        .prepare(&format!("SELECT * FROM {quoted}"))?
+        .column_count();
+    let order_by = (1..=column_count)
+        .map(|i| i.to_string())
+        .collect::<Vec<_>>()
+        .join(",");
+    let mut stmt = conn.prepare(&format!("SELECT * FROM {quoted} ORDER BY {order_by}"))?;
+    let mut rows = stmt.query(())?;
+    while let Some(row) = rows.next()? {
+        for i in 0..column_count {
+            hash_value(hasher, row.get_ref(i)?);
+        }
+    }
+
+    Ok(())
+}
+
+/// Hashes a value prefixed with its type and length, so that different sequences of values
+/// can't produce the same input to the hasher.
+fn hash_value(hasher: &mut Sha256, value: ValueRef) {
+    match value {
+        ValueRef::Null => hasher.update([0]),
+        ValueRef::Integer(i) => {
+            hasher.update([1]);
+            hasher.update(i.to_be_bytes());
+        }
+        ValueRef::Real(f) => {
+            hasher.update([2]);
+            hasher.update(f.to_be_bytes());
+        }
+        ValueRef::Text(s) => {
+            hasher.update([3]);
+            hasher.update((s.len() as u64).to_be_bytes());
+            hasher.update(s);
+        }
+        ValueRef::Blob(b) => {
+            hasher.update([4]);
+            hasher.update((b.len() as u64).to_be_bytes());
+            hasher.update(b);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn open(sql: &str) -> rusqlite::Connection {
+        let conn = rusqlite::Connection::open_in_memory().unwrap();
+        conn.execute_batch(sql).unwrap();
+        conn
+    }
+
+    #[test]
+    fn digest_ignores_insertion_order() {
+        let first = open(
+            "CREATE TABLE test (x INTEGER, y TEXT);
+             INSERT INTO test VALUES (1, 'one'), (2, 'two'), (3, NULL);",
+        );
+        let second = open(
+            "CREATE TABLE test (x INTEGER, y TEXT);
+             INSERT INTO test VALUES (3, NULL), (1, 'one');
+             INSERT INTO test VALUES (2, 'two');",
+        );
+        assert_eq!(
+            content_digest(&first).unwrap(),
+            content_digest(&second).unwrap()
+        );
+
+        second
+            .execute("UPDATE test SET y = 'deux' WHERE x = 2", ())
+            .unwrap();
+        assert_ne!(
+            content_digest(&first).unwrap(),
+            content_digest(&second).unwrap()
+        );
+    }
+
+    #[test]
+    fn digest_covers_schema() {
+        let first = open("CREATE TABLE test (x INTEGER);");
+        let second = open("CREATE TABLE test (x INTEGER); CREATE INDEX test_x ON test(x);");
+        assert_ne!(
+            content_digest(&first).unwrap(),
+            content_digest(&second).unwrap()
+        );
+    }
+}
@@ -2,10 +2,15 @@ use std::fs::File;
 use std::io::{BufRead, BufReader};
 use std::path::PathBuf;
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use anyhow::anyhow;
+use fallible_iterator::FallibleIterator;
 use rusqlite::ErrorCode;
+use sqlite3_parser::ast::{
+    Cmd, Expr, InsertBody, Literal, Name, OneSelect, Select, SelectBody, Stmt,
+};
+use sqlite3_parser::lexer::sql::Parser;
 use tokio::sync::{mpsc, oneshot};
 
 use crate::database::libsql::open_db;
@@ -14,6 +19,19 @@ use crate::replication::ReplicationLogger;
 
 type OpMsg = Box<dyn FnOnce(&rusqlite::Connection) + 'static + Send + Sync>;
 
+/// Tally of what was imported by a dump load.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct DumpLoadSummary {
+    /// Number of SQL statements executed against the database.
+    pub statements_executed: usize,
+    /// Number of bytes read from the dump file.
+    pub bytes_read: usize,
+    /// Time it took to load the dump.
+    pub duration: Duration,
+    /// Number of tables created by the dump.
+    pub tables_created: usize,
+}
+
 #[derive(Debug)]
 pub struct DumpLoader {
     sender: mpsc::Sender<OpMsg>,
@@ -66,30 +84,61 @@ impl DumpLoader {
         Ok(Self { sender })
     }
 
-    /// Attempts to load the dump at `path` into the database.
-    pub async fn load_dump(&self, path: PathBuf) -> anyhow::Result<()> {
+    /// Attempts to load the dump at `path` into the database. If `table_prefix` is set, it is
+    /// prepended to the name of the tables and indexes created by the dump. If loading fails, the
+    /// transaction opened by the dump, if any, is rolled back.
+    pub async fn load_dump(
+        &self,
+        path: PathBuf,
+        table_prefix: Option<String>,
+    ) -> anyhow::Result<DumpLoadSummary> {
         tracing::info!("loading dump at `{}`", path.display());
         let (snd, ret) = oneshot::channel();
         self.sender
             .send(Box::new(move |conn| {
-                let ret = perform_load_dump(conn, path);
+                let ret = perform_load_dump(conn, path, table_prefix.as_deref());
                 let _ = snd.send(ret);
             }))
             .await
             .map_err(|_| anyhow!("dump loader channel closed"))?;
 
-        ret.await??;
+        let summary = ret.await??;
 
-        tracing::info!("dump loaded sucessfully");
+        tracing::info!(
+            statements_executed = summary.statements_executed,
+            bytes_read = summary.bytes_read,
+            tables_created = summary.tables_created,
+            "dump loaded sucessfully in {:?}",
+            summary.duration
+        );
 
-        Ok(())
+        Ok(summary)
     }
 }
 
 const WASM_TABLE_CREATE: &str =
     "CREATE TABLE libsql_wasm_func_table (name text PRIMARY KEY, body text) WITHOUT ROWID;";
 
-fn perform_load_dump(conn: &rusqlite::Connection, path: PathBuf) -> anyhow::Result<()> {
+fn perform_load_dump(
+    conn: &rusqlite::Connection,
+    path: PathBuf,
+    table_prefix: Option<&str>,
+) -> anyhow::Result<DumpLoadSummary> {
+    let result = read_dump(conn, path, table_prefix);
+    if result.is_err() && !conn.is_autocommit() {
+        // the failed statement left the transaction of the dump open
+        conn.execute_batch("ROLLBACK")?;
+    }
+    result
+}
+
+fn read_dump(
+    conn: &rusqlite::Connection,
+    path: PathBuf,
+    table_prefix: Option<&str>,
+) -> anyhow::Result<DumpLoadSummary> {
+    let start = Instant::now();
+    let mut summary = DumpLoadSummary::default();
     let mut f = BufReader::new(File::open(path)?);
     let mut curr = String::new();
     let mut line = String::new();
@@ -98,6 +147,7 @@ fn perform_load_dump(conn: &rusqlite::Connection, path: PathBuf) -> anyhow::Resu
         if n == 0 {
             break;
         }
+        summary.bytes_read += n;
         let frag = curr.trim();
 
         if frag.is_empty() || frag.starts_with("--") {
@@ -117,12 +167,306 @@ fn perform_load_dump(conn: &rusqlite::Connection, path: PathBuf) -> anyhow::Resu
         }
 
         if line.ends_with(';') {
-            conn.execute(&line, ())?;
+            match table_prefix {
+                // a prefixed statement can expand to several statements, or to none
+                Some(prefix) => conn.execute_batch(&prefix_tables(&line, prefix)?)?,
+                None => {
+                    conn.execute(&line, ())?;
+                }
+            }
+            summary.statements_executed += 1;
+            if is_create_table(&line) {
+                summary.tables_created += 1;
+            }
             line.clear();
         } else {
             line.push(' ');
         }
     }
 
-    Ok(())
+    summary.duration = start.elapsed();
+
+    Ok(summary)
+}
+
+/// Rewrites a statement from a dump so that the tables and indexes it creates or fills are named
+/// with `prefix`. Only the statements found in dumps are supported; references to other tables,
+/// e.g. in foreign keys, are left as is. The AUTOINCREMENT counters in `sqlite_sequence` are
+/// renamed along with their tables, without touching the counters of other dumps.
+fn prefix_tables(stmt: &str, prefix: &str) -> anyhow::Result<String> {
+    let mut parser = Parser::new(stmt.as_bytes());
+    let mut cmd = parser
+        .next()?
+        .ok_or_else(|| anyhow!("empty statement in dump"))?;
+    let prefixed = |name: &mut Name| {
+        let unquoted = unquote(&name.0);
+        // internal tables keep their name
+        if !unquoted.to_lowercase().starts_with("sqlite_") {
+            name.0 = format!("\"{}\"", format!("{prefix}{unquoted}").replace('"', "\"\""));
+        }
+    };
+    match cmd {
+        Cmd::Stmt(Stmt::Insert {
+            ref tbl_name,
+            ref mut body,
+            ..
+        }) if is_sqlite_sequence(&tbl_name.name) => {
+            let Some(names) = prefix_sequence_names(body, prefix) else {
+                anyhow::bail!("`{stmt}` can't be loaded with a table prefix")
+            };
+            // the counters are replaced rather than reset as a whole, as other dumps have theirs
+            // in the same table
+            return Ok(format!(
+                "DELETE FROM sqlite_sequence WHERE name IN ({}); {cmd}",
+                names.join(", ")
+            ));
+        }
+        Cmd::Stmt(Stmt::Delete { ref tbl_name, .. }) if is_sqlite_sequence(&tbl_name.name) => {
+            return Ok(String::new())
+        }
+        Cmd::Stmt(
+            Stmt::CreateTable {
+                ref mut tbl_name, ..
+            }
+            | Stmt::Insert {
+                ref mut tbl_name, ..
+            },
+        ) => prefixed(&mut tbl_name.name),
+        Cmd::Stmt(Stmt::CreateIndex {
+            ref mut idx_name,
+            ref mut tbl_name,
+            ..
+        }) => {
+            prefixed(&mut idx_name.name);
+            prefixed(tbl_name);
+        }
+        Cmd::Stmt(Stmt::Delete { ref tbl_name, .. })
+            if unquote(&tbl_name.name.0)
+                .to_lowercase()
+                .starts_with("sqlite_") =>
+        {
+            return Ok(stmt.to_string())
+        }
+        Cmd::Stmt(
+            Stmt::Begin { .. } | Stmt::Commit { .. } | Stmt::Pragma { .. } | Stmt::Analyze { .. },
+        ) => return Ok(stmt.to_string()),
+        _ => anyhow::bail!("`{stmt}` can't be loaded with a table prefix"),
+    }
+
+    Ok(cmd.to_string())
+}
+
+fn is_sqlite_sequence(name: &Name) -> bool {
+    unquote(&name.0).eq_ignore_ascii_case("sqlite_sequence")
+}
+
+/// Prefixes the table names in the rows inserted into `sqlite_sequence`, and returns them as SQL
+/// string literals. Returns `None` if the insert is not a plain `VALUES` list.
+fn prefix_sequence_names(body: &mut InsertBody, prefix: &str) -> Option<Vec<String>> {
+    let rows = match body {
+        InsertBody::Select(
+            Select {
+                body:
+                    SelectBody {
+                        select: OneSelect::Values(rows),
+                        compounds: None,
+                        ..
+                    },
+                ..
+            },
+            None,
+        ) => rows,
+        _ => return None,
+    };
+    let mut names = Vec::with_capacity(rows.len());
+    for row in rows {
+        let Some(Expr::Literal(Literal::String(name))) = row.first_mut() else { return None };
+        let prefixed = format!("{prefix}{}", unquote(name));
+        *name = format!("'{}'", prefixed.replace('\'', "''"));
+        names.push(name.clone());
+    }
+
+    Some(names)
+}
+
+fn unquote(name: &str) -> String {
+    match name.chars().next() {
+        Some(quote @ ('"' | '`' | '\'')) if name.len() > 1 && name.ends_with(quote) => {
+            name[1..name.len() - 1].replace(&format!("{quote}{quote}"), &quote.to_string())
+        }
+        Some('[') if name.ends_with(']') => name[1..name.len() - 1].to_string(),
+        _ => name.to_string(),
+    }
+}
+
+fn is_create_table(stmt: &str) -> bool {
+    let mut words = stmt.split_whitespace();
+    matches!(
+        (words.next(), words.next()),
+        (Some(create), Some(table)) if create.eq_ignore_ascii_case("create") && table.eq_ignore_ascii_case("table")
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Write;
+
+    use super::*;
+
+    #[test]
+    fn load_summary_matches_dump() {
+        let dump = "\
+PRAGMA foreign_keys=OFF;
+BEGIN TRANSACTION;
+-- a comment
+CREATE TABLE users (id INTEGER PRIMARY KEY, name TEXT);
+INSERT INTO users VALUES(1,'alice');
+INSERT INTO users VALUES(2,
+'bob');
+create table posts (id INTEGER PRIMARY KEY, body TEXT);
+CREATE INDEX posts_body ON posts(body);
+COMMIT;
+";
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        file.write_all(dump.as_bytes()).unwrap();
+
+        let conn = rusqlite::Connection::open_in_memory().unwrap();
+        let summary = perform_load_dump(&conn, file.path().to_path_buf(), None).unwrap();
+
+        assert_eq!(summary.statements_executed, 8);
+        assert_eq!(summary.tables_created, 2);
+        assert_eq!(summary.bytes_read, dump.len());
+
+        let count: usize = conn
+            .query_row("SELECT count(*) FROM users", (), |row| row.get(0))
+            .unwrap();
+        assert_eq!(count, 2);
+    }
+
+    fn dump_file(dump: &str) -> tempfile::NamedTempFile {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        file.write_all(dump.as_bytes()).unwrap();
+        file
+    }
+
+    fn count(conn: &rusqlite::Connection, table: &str) -> rusqlite::Result<usize> {
+        conn.query_row(&format!("SELECT count(*) FROM {table}"), (), |row| {
+            row.get(0)
+        })
+    }
+
+    #[test]
+    fn load_dumps_with_table_prefix() {
+        let dump = "\
+BEGIN TRANSACTION;
+CREATE TABLE users (id INTEGER PRIMARY KEY, name TEXT);
+INSERT INTO users VALUES(1,'alice');
+CREATE INDEX \"users name\" ON users(name);
+COMMIT;
+";
+        let first = dump_file(dump);
+        let second = dump_file(dump);
+
+        let conn = rusqlite::Connection::open_in_memory().unwrap();
+        perform_load_dump(&conn, first.path().to_path_buf(), Some("a_")).unwrap();
+        perform_load_dump(&conn, second.path().to_path_buf(), Some("b_")).unwrap();
+
+        assert_eq!(count(&conn, "a_users").unwrap(), 1);
+        assert_eq!(count(&conn, "b_users").unwrap(), 1);
+        let indexes: usize = conn
+            .query_row(
+                "SELECT count(*) FROM sqlite_schema WHERE type = 'index' AND name IN ('a_users name', 'b_users name')",
+                (),
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(indexes, 2);
+    }
+
+    #[test]
+    fn failed_dump_leaves_previous_dumps_intact() {
+        let first = dump_file(
+            "\
+BEGIN TRANSACTION;
+CREATE TABLE users (id INTEGER PRIMARY KEY);
+INSERT INTO users VALUES(1);
+COMMIT;
+",
+        );
+        let second = dump_file(
+            "\
+BEGIN TRANSACTION;
+CREATE TABLE posts (id INTEGER PRIMARY KEY);
+INSERT INTO posts VALUES(1);
+INSERT INTO posts VALUES(1);
+COMMIT;
+",
+        );
+
+        let conn = rusqlite::Connection::open_in_memory().unwrap();
+        perform_load_dump(&conn, first.path().to_path_buf(), None).unwrap();
+        assert!(perform_load_dump(&conn, second.path().to_path_buf(), None).is_err());
+
+        // the second dump was rolled back as a whole
+        assert!(conn.is_autocommit());
+        assert_eq!(count(&conn, "users").unwrap(), 1);
+        assert!(count(&conn, "posts").is_err());
+    }
+
+    #[test]
+    fn prefix_rejects_unsupported_statements() {
+        assert!(prefix_tables("CREATE VIEW v AS SELECT 1;", "a_").is_err());
+        assert!(prefix_tables("UPDATE users SET id = 2;", "a_").is_err());
+        assert_eq!(
+            prefix_tables("DELETE FROM sqlite_stat1;", "a_").unwrap(),
+            "DELETE FROM sqlite_stat1;"
+        );
+        // the counters of the dump's tables are replaced when they're inserted instead
+        assert_eq!(
+            prefix_tables("DELETE FROM sqlite_sequence;", "a_").unwrap(),
+            ""
+        );
+    }
+
+    #[test]
+    fn prefix_renames_autoincrement_counters() {
+        let dump = "\
+BEGIN TRANSACTION;
+CREATE TABLE users (id INTEGER PRIMARY KEY AUTOINCREMENT, name TEXT);
+INSERT INTO users VALUES(1,'alice');
+INSERT INTO users VALUES(7,'bob');
+DELETE FROM sqlite_sequence;
+INSERT INTO sqlite_sequence VALUES('users',9);
+COMMIT;
+";
+        let first = dump_file(dump);
+        let second = dump_file(dump);
+
+        let conn = rusqlite::Connection::open_in_memory().unwrap();
+        perform_load_dump(&conn, first.path().to_path_buf(), Some("a_")).unwrap();
+        perform_load_dump(&conn, second.path().to_path_buf(), Some("b_")).unwrap();
+
+        // each table has its own counter, and loading the second dump left the first one intact
+        let mut stmt = conn
+            .prepare("SELECT name, seq FROM sqlite_sequence ORDER BY name")
+            .unwrap();
+        let counters: Vec<(String, i64)> = stmt
+            .query_map((), |row| Ok((row.get(0)?, row.get(1)?)))
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(
+            counters,
+            vec![("a_users".to_string(), 9), ("b_users".to_string(), 9)]
+        );
+
+        conn.execute("INSERT INTO a_users (name) VALUES ('carol')", ())
+            .unwrap();
+        let id: i64 = conn
+            .query_row("SELECT id FROM a_users WHERE name = 'carol'", (), |row| {
+                row.get(0)
+            })
+            .unwrap();
+        assert_eq!(id, 10);
+    }
 }
@@ -1,2 +1,4 @@
+pub mod digest;
 pub mod exporter;
 pub mod loader;
+pub mod logical_backup;
@@ -3,7 +3,10 @@ use std::{sync::Arc, time::Duration};
 use futures::Future;
 use tokio::{sync::Semaphore, time::timeout};
 
+use super::policy::{PolicyDbFactory, QueryPolicy};
+use super::result_cache::{CachedDbFactory, QueryResultCache};
 use super::{Database, DescribeResult, Program};
+use crate::soft_limit::SoftLimit;
 use crate::{auth::Authenticated, error::Error, query::QueryResult, query_analysis::State};
 
 #[async_trait::async_trait]
@@ -16,6 +19,20 @@ pub trait DbFactory: Send + Sync {
     {
         ThrottledDbFactory::new(conccurency, self, timeout)
     }
+
+    fn with_policy(self, policy: Option<Arc<dyn QueryPolicy>>) -> PolicyDbFactory<Self>
+    where
+        Self: Sized,
+    {
+        PolicyDbFactory::new(self, policy)
+    }
+
+    fn with_result_cache(self, cache: Option<QueryResultCache>) -> CachedDbFactory<Self>
+    where
+        Self: Sized,
+    {
+        CachedDbFactory::new(self, cache)
+    }
 }
 
 #[async_trait::async_trait]
@@ -34,18 +51,29 @@ where
 #[derive(Clone)]
 pub struct ThrottledDbFactory<F> {
     semaphore: Arc<Semaphore>,
+    conccurency: usize,
     factory: F,
     timeout: Option<Duration>,
+    soft_limit: Option<SoftLimit>,
 }
 
 impl<F> ThrottledDbFactory<F> {
     fn new(conccurency: usize, factory: F, timeout: Option<Duration>) -> Self {
         Self {
             semaphore: Arc::new(Semaphore::new(conccurency)),
+            conccurency,
             factory,
             timeout,
+            soft_limit: None,
         }
     }
+
+    /// Checks the number of open connections against `soft_limit` whenever one is opened or
+    /// closed.
+    pub fn with_soft_limit(mut self, soft_limit: Option<SoftLimit>) -> Self {
+        self.soft_limit = soft_limit;
+        self
+    }
 }
 
 #[async_trait::async_trait]
@@ -57,15 +85,82 @@ impl<F: DbFactory> DbFactory for ThrottledDbFactory<F> {
             None => fut.await,
         }
         .expect("semaphore closed");
+        // if opening the connection fails, dropping the tracked permit updates the soft limit
+        let permit = TrackedPermit {
+            permit: Some(permit),
+            semaphore: self.semaphore.clone(),
+            conccurency: self.conccurency,
+            soft_limit: self.soft_limit.clone(),
+        };
+        permit.update_soft_limit();
         let db = self.factory.create().await?;
         Ok(Arc::new(TrackedDb { permit, db }))
     }
 }
 
+/// A throttle permit, that reports the number of open connections to the soft limit when it's
+/// taken and released.
+struct TrackedPermit {
+    permit: Option<tokio::sync::OwnedSemaphorePermit>,
+    semaphore: Arc<Semaphore>,
+    conccurency: usize,
+    soft_limit: Option<SoftLimit>,
+}
+
+impl TrackedPermit {
+    fn update_soft_limit(&self) {
+        if let Some(soft_limit) = &self.soft_limit {
+            let open = self.conccurency - self.semaphore.available_permits();
+            soft_limit.update(open as u64, self.conccurency as u64);
+        }
+    }
+}
+
+impl Drop for TrackedPermit {
+    fn drop(&mut self) {
+        // release the permit first, so that it's not counted as open anymore
+        drop(self.permit.take());
+        self.update_soft_limit();
+    }
+}
+
+/// A factory that opens a number of connections upfront, and hands them out to the first callers
+/// of `create`, so that they don't pay the cost of opening a fresh connection.
+pub struct WarmedDbFactory<F> {
+    factory: F,
+    warm: parking_lot::Mutex<Vec<Arc<dyn Database>>>,
+}
+
+impl<F: DbFactory> WarmedDbFactory<F> {
+    pub async fn new(factory: F, count: usize) -> Result<Self, Error> {
+        let mut warm = Vec::with_capacity(count);
+        for _ in 0..count {
+            warm.push(factory.create().await?);
+        }
+
+        Ok(Self {
+            factory,
+            warm: parking_lot::Mutex::new(warm),
+        })
+    }
+}
+
+#[async_trait::async_trait]
+impl<F: DbFactory> DbFactory for WarmedDbFactory<F> {
+    async fn create(&self) -> Result<Arc<dyn Database>, Error> {
+        let warm = self.warm.lock().pop();
+        if let Some(db) = warm {
+            return Ok(db);
+        }
+
+        self.factory.create().await
+    }
+}
+
 struct TrackedDb {
     db: Arc<dyn Database>,
     #[allow(dead_code)] // just hold on to it
-    permit: tokio::sync::OwnedSemaphorePermit,
+    permit: TrackedPermit,
 }
 
 #[async_trait::async_trait]
@@ -86,6 +181,7 @@ impl Database for TrackedDb {
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::stats::{Limit, Stats};
 
     struct DummyDb;
 
@@ -123,4 +219,49 @@ mod test {
 
         assert!(factory.create().await.is_ok());
     }
+
+    #[tokio::test]
+    async fn soft_limit_warns_before_throttling() {
+        let tmp = tempfile::tempdir().unwrap();
+        let stats = Stats::new(tmp.path()).unwrap();
+        let factory = (|| async { Ok(DummyDb) })
+            .throttled(10, Some(Duration::from_millis(100)))
+            .with_soft_limit(Some(SoftLimit::new(Limit::Connections, 80, stats.clone())));
+
+        let mut conns = Vec::with_capacity(10);
+        for _ in 0..7 {
+            conns.push(factory.create().await.unwrap());
+        }
+        assert!(stats.near_limits().is_empty());
+
+        // crossing the soft threshold doesn't reject anything
+        for _ in 7..10 {
+            conns.push(factory.create().await.unwrap());
+            assert_eq!(stats.near_limits(), vec![Limit::Connections]);
+        }
+
+        // the hard limit does
+        assert!(factory.create().await.is_err());
+
+        // closing connections clears the warning, without waiting for the next one to open
+        conns.truncate(1);
+        assert!(stats.near_limits().is_empty());
+        factory.create().await.unwrap();
+        assert!(stats.near_limits().is_empty());
+    }
+
+    #[tokio::test]
+    async fn warmed_connections_count_against_throttle() {
+        let factory = (|| async { Ok(DummyDb) }).throttled(2, Some(Duration::from_millis(100)));
+        let factory = WarmedDbFactory::new(factory, 2).await.unwrap();
+
+        // the warmed connections hold all the permits, and are handed out first
+        let conn = factory.create().await.unwrap();
+        let _other = factory.create().await.unwrap();
+        assert!(factory.create().await.is_err());
+
+        drop(conn);
+
+        assert!(factory.create().await.is_ok());
+    }
 }
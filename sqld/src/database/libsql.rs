@@ -10,6 +10,7 @@ use tokio::sync::oneshot;
 use tracing::warn;
 
 use crate::auth::{Authenticated, Authorized};
+use crate::disk_space::DiskSpaceGuard;
 use crate::error::Error;
 use crate::libsql::wal_hook::WalHook;
 use crate::query::{Column, Query, QueryResponse, QueryResult, ResultSet, Row};
@@ -35,12 +36,28 @@ enum Message {
     },
 }
 
+/// Settings applied to every new connection.
+#[derive(Clone, Default)]
+pub struct ConnectionOptions {
+    /// Extensions loaded into the connection
+    pub extensions: Vec<PathBuf>,
+    /// Maximum number of rows a single query is allowed to return
+    pub max_rows_per_query: Option<u64>,
+    /// How long a write waits for a concurrent writer to release its lock before failing with a
+    /// busy error. By default, it fails right away.
+    pub busy_timeout: Option<Duration>,
+    /// Executed in order on the new connection, before it serves any query
+    pub pragmas: Vec<String>,
+    /// Rejects writes while disk space is low
+    pub disk_space: DiskSpaceGuard,
+}
+
 pub struct LibSqlDbFactory<W: WalHook + 'static> {
     db_path: PathBuf,
     hook: &'static WalMethodsHook<W>,
     ctx_builder: Box<dyn Fn() -> W::Context + Sync + Send + 'static>,
     stats: Stats,
-    extensions: Vec<PathBuf>,
+    options: ConnectionOptions,
     /// In wal mode, closing the last database takes time, and causes other databases creation to
     /// return sqlite busy. To mitigate that, we hold on to one connection
     _db: Option<LibSqlDb>,
@@ -56,7 +73,7 @@ where
         hook: &'static WalMethodsHook<W>,
         ctx_builder: F,
         stats: Stats,
-        extensions: Vec<PathBuf>,
+        options: ConnectionOptions,
     ) -> Result<Self>
     where
         F: Fn() -> W::Context + Sync + Send + 'static,
@@ -66,7 +83,7 @@ where
             hook,
             ctx_builder: Box::new(ctx_builder),
             stats,
-            extensions,
+            options,
             _db: None,
         };
 
@@ -108,10 +125,10 @@ where
     async fn create_database(&self) -> Result<LibSqlDb> {
         LibSqlDb::new(
             self.db_path.clone(),
-            self.extensions.clone(),
             self.hook,
             (self.ctx_builder)(),
             self.stats.clone(),
+            self.options.clone(),
         )
         .await
     }
@@ -131,6 +148,7 @@ where
 #[derive(Clone)]
 pub struct LibSqlDb {
     sender: crossbeam::channel::Sender<Message>,
+    disk_space: DiskSpaceGuard,
 }
 
 struct ConnectionState {
@@ -180,6 +198,15 @@ macro_rules! ok_or_exit {
     };
 }
 
+fn is_rollback(stmt: &Statement) -> bool {
+    stmt.kind == StmtKind::TxnEnd
+        && stmt
+            .stmt
+            .trim_start()
+            .get(..8)
+            .map_or(false, |keyword| keyword.eq_ignore_ascii_case("rollback"))
+}
+
 pub fn open_db<'a, W>(
     path: &Path,
     wal_methods: &'static WalMethodsHook<W>,
@@ -202,10 +229,10 @@ where
 impl LibSqlDb {
     pub async fn new<W>(
         path: impl AsRef<Path> + Send + 'static,
-        extensions: Vec<PathBuf>,
         wal_hook: &'static WalMethodsHook<W>,
         hook_ctx: W::Context,
         stats: Stats,
+        options: ConnectionOptions,
     ) -> crate::Result<Self>
     where
         W: WalHook,
@@ -213,11 +240,12 @@ impl LibSqlDb {
     {
         let (sender, receiver) = crossbeam::channel::unbounded::<Message>();
         let (init_sender, init_receiver) = oneshot::channel();
+        let disk_space = options.disk_space.clone();
 
         tokio::task::spawn_blocking(move || {
             let mut ctx = hook_ctx;
             let mut connection =
-                match Connection::new(path.as_ref(), extensions, wal_hook, &mut ctx, stats) {
+                match Connection::new(path.as_ref(), wal_hook, &mut ctx, stats, options) {
                     Ok(conn) => {
                         let Ok(_) = init_sender.send(Ok(())) else { return };
                         conn
@@ -235,6 +263,7 @@ impl LibSqlDb {
                         Err(RecvTimeoutError::Timeout) => {
                             warn!("transaction timed out");
                             connection.rollback();
+                            connection.uncommitted_writes = false;
                             connection.timed_out = true;
                             connection.state.reset();
                             continue;
@@ -271,7 +300,7 @@ impl LibSqlDb {
 
         init_receiver.await??;
 
-        Ok(Self { sender })
+        Ok(Self { sender, disk_space })
     }
 }
 
@@ -279,25 +308,43 @@ struct Connection<'a> {
     state: ConnectionState,
     conn: sqld_libsql_bindings::Connection<'a>,
     timed_out: bool,
+    /// Whether writes were executed since the last commit or rollback
+    uncommitted_writes: bool,
     stats: Stats,
+    /// Maximum number of rows a single query is allowed to return
+    max_rows_per_query: Option<u64>,
 }
 
 impl<'a> Connection<'a> {
     fn new<W: WalHook>(
         path: &Path,
-        extensions: Vec<PathBuf>,
         wal_methods: &'static WalMethodsHook<W>,
         hook_ctx: &'a mut W::Context,
         stats: Stats,
+        options: ConnectionOptions,
     ) -> Result<Self> {
         let this = Self {
             conn: open_db(path, wal_methods, hook_ctx, None)?,
             state: ConnectionState::initial(),
             timed_out: false,
+            uncommitted_writes: false,
             stats,
+            max_rows_per_query: options.max_rows_per_query,
         };
 
-        for ext in extensions {
+        if let Some(busy_timeout) = options.busy_timeout {
+            this.conn.busy_timeout(busy_timeout)?;
+        }
+
+        for pragma in options.pragmas {
+            // some pragmas report the new value as a row, which is not of interest here
+            let mut stmt = this.conn.prepare(&pragma)?;
+            let mut rows = stmt.query(())?;
+            while rows.next()?.is_some() {}
+            tracing::debug!("Applied `{pragma}`");
+        }
+
+        for ext in options.extensions {
             unsafe {
                 let _guard = rusqlite::LoadExtensionGuard::new(&this.conn).unwrap();
                 if let Err(e) = this.conn.load_extension(&ext, None) {
@@ -339,17 +386,36 @@ impl<'a> Connection<'a> {
     }
 
     fn execute_query(&mut self, query: &Query) -> QueryResult {
+        let start = Instant::now();
         let result = self.execute_query_inner(query);
+        self.stats.record_query_latency(start.elapsed());
 
         // We drive the connection state on success. This is how we keep track of whether
         // a transaction timeouts
         if result.is_ok() {
             self.state.step(&query.stmt)
         }
+        self.count_writes(query, result.is_ok());
 
         result
     }
 
+    /// Updates the write and commit counters after `query` was executed.
+    fn count_writes(&mut self, query: &Query, succeeded: bool) {
+        if succeeded && !query.stmt.is_read_only() {
+            self.stats.inc_write_queries();
+            self.uncommitted_writes = true;
+        }
+        // Back in autocommit mode, the writes were either committed or rolled back, explicitly
+        // or because of an error
+        if self.uncommitted_writes && self.conn.is_autocommit() {
+            self.uncommitted_writes = false;
+            if succeeded && !is_rollback(&query.stmt) {
+                self.stats.inc_commits();
+            }
+        }
+    }
+
     fn execute_query_inner(&self, query: &Query) -> QueryResult {
         tracing::trace!("executing query: {}", query.stmt.stmt);
 
@@ -384,6 +450,12 @@ impl<'a> Connection<'a> {
                 continue;
             }
 
+            if let Some(max_rows) = self.max_rows_per_query {
+                if rows.len() as u64 >= max_rows {
+                    return Err(Error::TooManyRows(max_rows));
+                }
+            }
+
             let mut values = vec![];
             for (i, _) in columns.iter().enumerate() {
                 values.push(row.get::<usize, rusqlite::types::Value>(i)?.into());
@@ -485,7 +557,7 @@ fn eval_cond(cond: &Cond, results: &[Option<QueryResult>]) -> Result<bool> {
     })
 }
 
-fn check_program_auth(auth: Authenticated, pgm: &Program) -> Result<()> {
+pub(super) fn check_program_auth(auth: Authenticated, pgm: &Program) -> Result<()> {
     for step in pgm.steps() {
         let query = &step.query;
         match (query.stmt.kind, &auth) {
@@ -508,6 +580,13 @@ fn check_program_auth(auth: Authenticated, pgm: &Program) -> Result<()> {
     Ok(())
 }
 
+fn check_disk_space(disk_space: &DiskSpaceGuard, pgm: &Program) -> Result<()> {
+    if disk_space.is_low() && !pgm.is_read_only() {
+        return Err(Error::DiskFull);
+    }
+    Ok(())
+}
+
 fn check_describe_auth(auth: Authenticated) -> Result<()> {
     match auth {
         Authenticated::Anonymous => {
@@ -525,6 +604,7 @@ impl Database for LibSqlDb {
         auth: Authenticated,
     ) -> Result<(Vec<Option<QueryResult>>, State)> {
         check_program_auth(auth, &pgm)?;
+        check_disk_space(&self.disk_space, &pgm)?;
         let (resp, receiver) = oneshot::channel();
         let msg = Message::Program { pgm, resp };
         let _: Result<_, _> = self.sender.send(msg);
@@ -541,3 +621,241 @@ impl Database for LibSqlDb {
         Ok(receiver.await?)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use sqld_libsql_bindings::wal_hook::TRANSPARENT_METHODS;
+
+    use super::*;
+    use crate::database::test_utils::query;
+    use crate::stats::CountersSnapshot;
+
+    #[tokio::test]
+    async fn query_exceeding_row_limit_fails() {
+        let tmp = tempfile::tempdir().unwrap();
+        let stats = Stats::new(tmp.path()).unwrap();
+        let db = LibSqlDb::new(
+            tmp.path().to_path_buf(),
+            &TRANSPARENT_METHODS,
+            (),
+            stats,
+            ConnectionOptions {
+                max_rows_per_query: Some(2),
+                ..Default::default()
+            },
+        )
+        .await
+        .unwrap();
+        let auth = Authenticated::Authorized(Authorized::FullAccess);
+
+        let (results, _) = db
+            .execute_batch(
+                vec![
+                    query("CREATE TABLE test (x INTEGER)"),
+                    query("INSERT INTO test VALUES (1), (2), (3)"),
+                ],
+                auth,
+            )
+            .await
+            .unwrap();
+        assert!(results.iter().all(|r| matches!(r, Some(Ok(_)))));
+
+        let (result, _) = db
+            .execute_one(query("SELECT * FROM test LIMIT 2"), auth)
+            .await
+            .unwrap();
+        let Ok(QueryResponse::ResultSet(result_set)) = result else { panic!("query failed") };
+        assert_eq!(result_set.rows.len(), 2);
+
+        let (result, _) = db
+            .execute_one(query("SELECT * FROM test"), auth)
+            .await
+            .unwrap();
+        assert!(matches!(result, Err(Error::TooManyRows(2))));
+    }
+
+    #[tokio::test]
+    async fn writes_rejected_while_disk_space_is_low() {
+        let tmp = tempfile::tempdir().unwrap();
+        let stats = Stats::new(tmp.path()).unwrap();
+        let disk_space = DiskSpaceGuard::default();
+        let db = LibSqlDb::new(
+            tmp.path().to_path_buf(),
+            &TRANSPARENT_METHODS,
+            (),
+            stats,
+            ConnectionOptions {
+                disk_space: disk_space.clone(),
+                ..Default::default()
+            },
+        )
+        .await
+        .unwrap();
+        let auth = Authenticated::Authorized(Authorized::FullAccess);
+
+        db.execute_one(query("CREATE TABLE test (x INTEGER)"), auth)
+            .await
+            .unwrap();
+
+        disk_space.update(10, 100);
+        let result = db
+            .execute_one(query("INSERT INTO test VALUES (1)"), auth)
+            .await;
+        assert!(matches!(result, Err(Error::DiskFull)));
+        let (result, _) = db
+            .execute_one(query("SELECT * FROM test"), auth)
+            .await
+            .unwrap();
+        assert!(result.is_ok());
+
+        disk_space.update(100, 100);
+        let (result, _) = db
+            .execute_one(query("INSERT INTO test VALUES (1)"), auth)
+            .await
+            .unwrap();
+        assert!(result.is_ok());
+    }
+
+    #[tokio::test]
+    async fn concurrent_writer_waits_for_busy_timeout() {
+        let tmp = tempfile::tempdir().unwrap();
+        let stats = Stats::new(tmp.path()).unwrap();
+        let open = |busy_timeout| {
+            LibSqlDb::new(
+                tmp.path().to_path_buf(),
+                &TRANSPARENT_METHODS,
+                (),
+                stats.clone(),
+                ConnectionOptions {
+                    busy_timeout,
+                    ..Default::default()
+                },
+            )
+        };
+        let writer = open(None).await.unwrap();
+        let impatient = open(None).await.unwrap();
+        let patient = open(Some(Duration::from_secs(5))).await.unwrap();
+        let auth = Authenticated::Authorized(Authorized::FullAccess);
+
+        writer
+            .execute_one(query("CREATE TABLE test (x INTEGER)"), auth)
+            .await
+            .unwrap();
+        for sql in ["BEGIN IMMEDIATE", "INSERT INTO test VALUES (1)"] {
+            let (result, _) = writer.execute_one(query(sql), auth).await.unwrap();
+            assert!(result.is_ok());
+        }
+
+        // without a busy timeout, the write lock held by the other writer fails the write
+        let (result, _) = impatient
+            .execute_one(query("INSERT INTO test VALUES (2)"), auth)
+            .await
+            .unwrap();
+        assert!(matches!(
+            result,
+            Err(Error::RusqliteError(rusqlite::Error::SqliteFailure(
+                rusqlite::ffi::Error {
+                    code: ErrorCode::DatabaseBusy,
+                    ..
+                },
+                _
+            )))
+        ));
+
+        // with one, the write waits for the lock to be released
+        let start = Instant::now();
+        let waiting = tokio::spawn(async move {
+            patient
+                .execute_one(query("INSERT INTO test VALUES (3)"), auth)
+                .await
+        });
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        let (result, _) = writer.execute_one(query("COMMIT"), auth).await.unwrap();
+        assert!(result.is_ok());
+        let (result, _) = waiting.await.unwrap().unwrap();
+        assert!(result.is_ok());
+        assert!(start.elapsed() >= Duration::from_millis(200));
+
+        let (result, _) = writer
+            .execute_one(query("SELECT * FROM test"), auth)
+            .await
+            .unwrap();
+        let Ok(QueryResponse::ResultSet(result_set)) = result else { panic!("query failed") };
+        assert_eq!(result_set.rows.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn counters_reset_independently_of_gauges() {
+        let tmp = tempfile::tempdir().unwrap();
+        let stats = Stats::new(tmp.path()).unwrap();
+        let db = LibSqlDb::new(
+            tmp.path().to_path_buf(),
+            &TRANSPARENT_METHODS,
+            (),
+            stats.clone(),
+            ConnectionOptions::default(),
+        )
+        .await
+        .unwrap();
+        let auth = Authenticated::Authorized(Authorized::FullAccess);
+        for sql in [
+            "CREATE TABLE test (x INTEGER)",
+            "INSERT INTO test VALUES (1), (2)",
+            "SELECT * FROM test",
+            "BEGIN",
+            "INSERT INTO test VALUES (3)",
+            "UPDATE test SET x = x + 1",
+            "COMMIT",
+            "BEGIN",
+            "DELETE FROM test",
+            "ROLLBACK",
+        ] {
+            let (result, _) = db.execute_one(query(sql), auth).await.unwrap();
+            assert!(result.is_ok());
+        }
+        stats.set_storage_bytes_used(4096);
+
+        let counters = stats.snapshot_counters();
+        assert_eq!(counters.write_queries, 5);
+        assert_eq!(counters.commits, 3);
+        assert_eq!(counters.snapshots_loaded, 0);
+        let rows_written = stats.rows_written();
+        assert!(rows_written > 0);
+
+        stats.reset_counters();
+        assert_eq!(stats.snapshot_counters(), CountersSnapshot::default());
+        assert_eq!(stats.rows_written(), rows_written);
+        assert_eq!(stats.storage_bytes_used(), 4096);
+    }
+
+    #[tokio::test]
+    async fn pragmas_applied_on_open() {
+        let tmp = tempfile::tempdir().unwrap();
+        let stats = Stats::new(tmp.path()).unwrap();
+        let db = LibSqlDb::new(
+            tmp.path().to_path_buf(),
+            &TRANSPARENT_METHODS,
+            (),
+            stats,
+            ConnectionOptions {
+                pragmas: vec![
+                    "PRAGMA cache_size = -4000".to_string(),
+                    "PRAGMA synchronous = NORMAL".to_string(),
+                ],
+                ..Default::default()
+            },
+        )
+        .await
+        .unwrap();
+        let auth = Authenticated::Authorized(Authorized::FullAccess);
+
+        for (sql, expected) in [("PRAGMA cache_size", -4000), ("PRAGMA synchronous", 1)] {
+            let (result, _) = db.execute_one(query(sql), auth).await.unwrap();
+            let Ok(QueryResponse::ResultSet(result_set)) = result else { panic!("query failed") };
+            assert!(
+                matches!(result_set.rows[0].values[0], crate::query::Value::Integer(v) if v == expected),
+                "{sql}"
+            );
+        }
+    }
+}
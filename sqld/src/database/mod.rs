@@ -8,10 +8,26 @@ use crate::Result;
 pub mod dump;
 pub mod factory;
 pub mod libsql;
+pub mod policy;
+pub mod result_cache;
 pub mod write_proxy;
 
 const TXN_TIMEOUT_SECS: u64 = 5;
 
+#[cfg(test)]
+pub(crate) mod test_utils {
+    use super::*;
+
+    /// Builds a query returning rows, out of a single SQL statement.
+    pub fn query(sql: &str) -> Query {
+        Query {
+            stmt: Statement::parse(sql).next().unwrap().unwrap(),
+            params: Params::empty(),
+            want_rows: true,
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct Program {
     pub steps: Arc<Vec<Step>>,
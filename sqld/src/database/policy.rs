@@ -0,0 +1,193 @@
+use std::sync::Arc;
+
+use super::factory::DbFactory;
+use super::{Database, DescribeResult, Program, Step};
+use crate::auth::Authenticated;
+use crate::error::Error;
+use crate::query::{Query, QueryResult};
+use crate::query_analysis::{State, Statement};
+
+/// The outcome of submitting a statement to a `QueryPolicy`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PolicyDecision {
+    /// Execute the statement as is.
+    Allow,
+    /// Reject the whole program with the given reason.
+    Deny(String),
+    /// Execute the given statement instead. It must be a single statement.
+    Rewrite(String),
+}
+
+/// A hook that is consulted for every statement before it is executed, used to audit queries or
+/// enforce access policies.
+pub trait QueryPolicy: Send + Sync {
+    fn authorize(&self, sql: &str, auth: Authenticated) -> PolicyDecision;
+}
+
+/// A factory that applies a `QueryPolicy` to the connections created by the wrapped factory.
+pub struct PolicyDbFactory<F> {
+    factory: F,
+    policy: Option<Arc<dyn QueryPolicy>>,
+}
+
+impl<F> PolicyDbFactory<F> {
+    pub(super) fn new(factory: F, policy: Option<Arc<dyn QueryPolicy>>) -> Self {
+        Self { factory, policy }
+    }
+}
+
+#[async_trait::async_trait]
+impl<F: DbFactory> DbFactory for PolicyDbFactory<F> {
+    async fn create(&self) -> Result<Arc<dyn Database>, Error> {
+        let db = self.factory.create().await?;
+        match self.policy {
+            Some(ref policy) => Ok(Arc::new(PolicyDb {
+                db,
+                policy: policy.clone(),
+            })),
+            None => Ok(db),
+        }
+    }
+}
+
+struct PolicyDb {
+    db: Arc<dyn Database>,
+    policy: Arc<dyn QueryPolicy>,
+}
+
+impl PolicyDb {
+    /// Returns the statement to execute in place of `sql`, if the policy rewrote it.
+    fn authorize(&self, sql: &str, auth: Authenticated) -> crate::Result<Option<Statement>> {
+        match self.policy.authorize(sql, auth) {
+            PolicyDecision::Allow => Ok(None),
+            PolicyDecision::Deny(reason) => Err(Error::NotAuthorized(format!(
+                "denied by query policy: {reason}"
+            ))),
+            PolicyDecision::Rewrite(sql) => {
+                let mut stmts = Statement::parse(&sql);
+                match (stmts.next(), stmts.next()) {
+                    (Some(Ok(stmt)), None) => Ok(Some(stmt)),
+                    (Some(Err(e)), _) => Err(Error::Internal(format!(
+                        "query policy produced an invalid statement: {e}"
+                    ))),
+                    _ => Err(Error::Internal(
+                        "query policy must rewrite a statement into exactly one statement".into(),
+                    )),
+                }
+            }
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl Database for PolicyDb {
+    async fn execute_program(
+        &self,
+        pgm: Program,
+        auth: Authenticated,
+    ) -> crate::Result<(Vec<Option<QueryResult>>, State)> {
+        let mut rewritten = false;
+        let mut steps = Vec::with_capacity(pgm.steps.len());
+        for step in pgm.steps() {
+            let step = match self.authorize(&step.query.stmt.stmt, auth)? {
+                Some(stmt) => {
+                    rewritten = true;
+                    Step {
+                        cond: step.cond.clone(),
+                        query: Query {
+                            stmt,
+                            params: step.query.params.clone(),
+                            want_rows: step.query.want_rows,
+                        },
+                    }
+                }
+                None => step.clone(),
+            };
+            steps.push(step);
+        }
+
+        let pgm = if rewritten { Program::new(steps) } else { pgm };
+        self.db.execute_program(pgm, auth).await
+    }
+
+    async fn describe(&self, sql: String, auth: Authenticated) -> crate::Result<DescribeResult> {
+        let sql = match self.authorize(&sql, auth)? {
+            Some(stmt) => stmt.stmt,
+            None => sql,
+        };
+        self.db.describe(sql, auth).await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use sqld_libsql_bindings::wal_hook::TRANSPARENT_METHODS;
+
+    use super::*;
+    use crate::auth::Authorized;
+    use crate::database::libsql::{ConnectionOptions, LibSqlDb};
+    use crate::database::test_utils::query;
+    use crate::query::{QueryResponse, Value};
+    use crate::stats::Stats;
+
+    struct NoDrop;
+
+    impl QueryPolicy for NoDrop {
+        fn authorize(&self, sql: &str, _auth: Authenticated) -> PolicyDecision {
+            if sql.trim_start().to_uppercase().starts_with("DROP") {
+                PolicyDecision::Deny("DROP statements are not allowed".into())
+            } else if sql == "SELECT 1" {
+                PolicyDecision::Rewrite("SELECT 2".into())
+            } else {
+                PolicyDecision::Allow
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn policy_denies_and_rewrites_statements() {
+        let tmp = tempfile::tempdir().unwrap();
+        let stats = Stats::new(tmp.path()).unwrap();
+        let path = tmp.path().to_path_buf();
+        let factory = PolicyDbFactory::new(
+            move || {
+                LibSqlDb::new(
+                    path.clone(),
+                    &TRANSPARENT_METHODS,
+                    (),
+                    stats.clone(),
+                    ConnectionOptions::default(),
+                )
+            },
+            Some(Arc::new(NoDrop)),
+        );
+        let db = factory.create().await.unwrap();
+        let auth = Authenticated::Authorized(Authorized::FullAccess);
+
+        let (result, _) = db
+            .execute_one(query("CREATE TABLE test (x INTEGER)"), auth)
+            .await
+            .unwrap();
+        assert!(result.is_ok());
+
+        let (result, _) = db
+            .execute_one(query("SELECT * FROM test"), auth)
+            .await
+            .unwrap();
+        assert!(result.is_ok());
+
+        let result = db.execute_one(query("DROP TABLE test"), auth).await;
+        assert!(matches!(result, Err(Error::NotAuthorized(_))));
+
+        // the table must still be there
+        let (result, _) = db
+            .execute_one(query("SELECT * FROM test"), auth)
+            .await
+            .unwrap();
+        assert!(result.is_ok());
+
+        let (result, _) = db.execute_one(query("SELECT 1"), auth).await.unwrap();
+        let Ok(QueryResponse::ResultSet(result_set)) = result else { panic!("query failed") };
+        assert!(matches!(result_set.rows[0].values[0], Value::Integer(2)));
+    }
+}
@@ -0,0 +1,403 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use tokio::sync::watch;
+
+use super::factory::DbFactory;
+use super::libsql::check_program_auth;
+use super::{Database, DescribeResult, Program};
+use crate::auth::Authenticated;
+use crate::error::Error;
+use crate::query::{Params, Query, QueryResponse, QueryResult};
+use crate::query_analysis::{State, StmtKind};
+use crate::replication::FrameNo;
+
+/// A size-bounded LRU cache of read query results, shared by all connections. The whole cache is
+/// invalidated whenever the frame number advances, that is whenever a write is committed.
+#[derive(Clone)]
+pub struct QueryResultCache {
+    inner: Arc<parking_lot::Mutex<CacheInner>>,
+    frame_no: watch::Receiver<FrameNo>,
+}
+
+struct CacheInner {
+    capacity: usize,
+    /// Frame number at which the cached results were read.
+    frame_no: FrameNo,
+    /// Cached responses, along with the tick of their last use.
+    entries: HashMap<Vec<u8>, (u64, QueryResponse)>,
+    /// Keys of the cached responses, by tick of their last use.
+    lru: BTreeMap<u64, Vec<u8>>,
+    tick: u64,
+}
+
+impl CacheInner {
+    fn sync_frame_no(&mut self, frame_no: FrameNo) {
+        if self.frame_no != frame_no {
+            self.entries.clear();
+            self.lru.clear();
+            self.frame_no = frame_no;
+        }
+    }
+
+    fn next_tick(&mut self) -> u64 {
+        self.tick += 1;
+        self.tick
+    }
+
+    fn get(&mut self, key: &[u8]) -> Option<QueryResponse> {
+        let tick = self.next_tick();
+        let (last_use, response) = self.entries.get_mut(key)?;
+        let key = self
+            .lru
+            .remove(last_use)
+            .expect("cache entry missing from the LRU list");
+        self.lru.insert(tick, key);
+        *last_use = tick;
+        Some(response.clone())
+    }
+
+    fn insert(&mut self, key: Vec<u8>, response: QueryResponse) {
+        let tick = self.next_tick();
+        if let Some((last_use, _)) = self.entries.remove(&key) {
+            self.lru.remove(&last_use);
+        }
+        while self.entries.len() >= self.capacity {
+            let Some((_, oldest)) = self.lru.pop_first() else { break };
+            self.entries.remove(&oldest);
+        }
+        self.lru.insert(tick, key.clone());
+        self.entries.insert(key, (tick, response));
+    }
+}
+
+impl QueryResultCache {
+    /// Creates a cache holding the results of up to `capacity` queries, invalidated whenever
+    /// `frame_no` changes.
+    pub fn new(capacity: usize, frame_no: watch::Receiver<FrameNo>) -> Self {
+        let inner = CacheInner {
+            capacity: capacity.max(1),
+            frame_no: *frame_no.borrow(),
+            entries: HashMap::new(),
+            lru: BTreeMap::new(),
+            tick: 0,
+        };
+        Self {
+            inner: Arc::new(parking_lot::Mutex::new(inner)),
+            frame_no,
+        }
+    }
+
+    fn current_frame_no(&self) -> FrameNo {
+        *self.frame_no.borrow()
+    }
+
+    fn get(&self, key: &[u8]) -> Option<QueryResponse> {
+        let frame_no = self.current_frame_no();
+        let mut inner = self.inner.lock();
+        inner.sync_frame_no(frame_no);
+        inner.get(key)
+    }
+
+    /// Caches a response read at `frame_no`, unless a write was committed since.
+    fn insert(&self, key: Vec<u8>, response: QueryResponse, frame_no: FrameNo) {
+        let current_frame_no = self.current_frame_no();
+        if frame_no != current_frame_no {
+            return;
+        }
+        let mut inner = self.inner.lock();
+        inner.sync_frame_no(current_frame_no);
+        inner.insert(key, response);
+    }
+}
+
+/// Functions whose results depend on the current time, the connection or chance, rather than only
+/// on the database contents, so that a cached result would go stale without any write.
+const VOLATILE_FUNCTIONS: &[&str] = &[
+    "changes",
+    "date",
+    "datetime",
+    "julianday",
+    "last_insert_rowid",
+    "random",
+    "randomblob",
+    "strftime",
+    "time",
+    "total_changes",
+    "unixepoch",
+];
+
+/// Keywords evaluating to the current time.
+const TIME_KEYWORDS: &[&str] = &["current_date", "current_time", "current_timestamp"];
+
+fn is_ident_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_'
+}
+
+/// Whether the statement calls any of `VOLATILE_FUNCTIONS` or uses any of `TIME_KEYWORDS`. The SQL
+/// is only scanned for words, so a match inside a string literal counts too, which at worst
+/// prevents caching.
+fn is_volatile(sql: &str) -> bool {
+    let mut rest = sql;
+    while let Some(start) = rest.find(is_ident_char) {
+        let word_and_rest = &rest[start..];
+        let end = word_and_rest
+            .find(|c: char| !is_ident_char(c))
+            .unwrap_or(word_and_rest.len());
+        let (word, after) = word_and_rest.split_at(end);
+        let is_call = after.trim_start().starts_with('(');
+        if (is_call
+            && VOLATILE_FUNCTIONS
+                .iter()
+                .any(|f| word.eq_ignore_ascii_case(f)))
+            || TIME_KEYWORDS.iter().any(|k| word.eq_ignore_ascii_case(k))
+        {
+            return true;
+        }
+        rest = after;
+    }
+    false
+}
+
+/// Returns the cache key of a program, if its result can be cached: a single, unconditional,
+/// read statement, which doesn't use any volatile function.
+fn cache_key(pgm: &Program) -> Option<Vec<u8>> {
+    let [step] = pgm.steps() else { return None };
+    if step.cond.is_some()
+        || step.query.stmt.kind != StmtKind::Read
+        || is_volatile(&step.query.stmt.stmt)
+    {
+        return None;
+    }
+    let Query {
+        stmt,
+        params,
+        want_rows,
+    } = &step.query;
+    // named parameters are sorted, so that the key doesn't depend on the map order
+    let params = match params {
+        Params::Named(params) => {
+            let mut params: Vec<_> = params.iter().collect();
+            params.sort_by(|(a, _), (b, _)| a.cmp(b));
+            (0u8, bincode::serialize(&params).ok()?)
+        }
+        Params::Positional(params) => (1u8, bincode::serialize(params).ok()?),
+    };
+    bincode::serialize(&(&stmt.stmt, want_rows, params)).ok()
+}
+
+/// A factory whose connections serve repeated read queries from a `QueryResultCache`.
+pub struct CachedDbFactory<F> {
+    factory: F,
+    cache: Option<QueryResultCache>,
+}
+
+impl<F> CachedDbFactory<F> {
+    pub(super) fn new(factory: F, cache: Option<QueryResultCache>) -> Self {
+        Self { factory, cache }
+    }
+}
+
+#[async_trait::async_trait]
+impl<F: DbFactory> DbFactory for CachedDbFactory<F> {
+    async fn create(&self) -> Result<Arc<dyn Database>, Error> {
+        let db = self.factory.create().await?;
+        match self.cache {
+            Some(ref cache) => Ok(Arc::new(CachedDb {
+                db,
+                cache: cache.clone(),
+                in_txn: AtomicBool::new(false),
+                pending_write: parking_lot::Mutex::new(None),
+            })),
+            None => Ok(db),
+        }
+    }
+}
+
+struct CachedDb {
+    db: Arc<dyn Database>,
+    cache: QueryResultCache,
+    /// Whether the connection is in a transaction, whose uncommitted writes are not reflected in
+    /// the frame number, and so must not be read from or written to the cache.
+    in_txn: AtomicBool,
+    /// Frame number before the last write of this connection, until the write shows up in the
+    /// frame number. On replicas, writes are only applied once replicated from the primary.
+    pending_write: parking_lot::Mutex<Option<FrameNo>>,
+}
+
+impl CachedDb {
+    fn bypass_cache(&self, frame_no: FrameNo) -> bool {
+        self.in_txn.load(Ordering::Relaxed) || *self.pending_write.lock() == Some(frame_no)
+    }
+}
+
+#[async_trait::async_trait]
+impl Database for CachedDb {
+    async fn execute_program(
+        &self,
+        pgm: Program,
+        auth: Authenticated,
+    ) -> crate::Result<(Vec<Option<QueryResult>>, State)> {
+        // read before executing, so that a write committed meanwhile prevents caching
+        let frame_no = self.cache.current_frame_no();
+        let key = if self.bypass_cache(frame_no) {
+            None
+        } else {
+            cache_key(&pgm)
+        };
+        if let Some(ref key) = key {
+            if let Some(response) = self.cache.get(key) {
+                check_program_auth(auth, &pgm)?;
+                return Ok((vec![Some(Ok(response))], State::Init));
+            }
+        }
+
+        let is_read_only = pgm.is_read_only();
+        let (results, state) = self.db.execute_program(pgm, auth).await?;
+        if let (Some(key), [Some(Ok(response))]) = (key, results.as_slice()) {
+            self.cache.insert(key, response.clone(), frame_no);
+        }
+        if !is_read_only {
+            *self.pending_write.lock() = Some(frame_no);
+        }
+        self.in_txn.store(state == State::Txn, Ordering::Relaxed);
+        Ok((results, state))
+    }
+
+    async fn describe(&self, sql: String, auth: Authenticated) -> crate::Result<DescribeResult> {
+        self.db.describe(sql, auth).await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use sqld_libsql_bindings::wal_hook::TRANSPARENT_METHODS;
+
+    use super::*;
+    use crate::auth::Authorized;
+    use crate::database::libsql::{ConnectionOptions, LibSqlDb};
+    use crate::database::test_utils::query;
+    use crate::query::Value;
+    use crate::stats::Stats;
+
+    async fn count(db: &Arc<dyn Database>, auth: Authenticated) -> i64 {
+        let (result, _) = db
+            .execute_one(query("SELECT count(*) FROM test"), auth)
+            .await
+            .unwrap();
+        let Ok(QueryResponse::ResultSet(result_set)) = result else { panic!("query failed") };
+        match result_set.rows[0].values[0] {
+            Value::Integer(count) => count,
+            _ => panic!("unexpected count"),
+        }
+    }
+
+    #[tokio::test]
+    async fn results_cached_until_next_write() {
+        let tmp = tempfile::tempdir().unwrap();
+        let stats = Stats::new(tmp.path()).unwrap();
+        let path = tmp.path().to_path_buf();
+        let (frame_no_sender, frame_no) = watch::channel(0);
+        let factory = CachedDbFactory::new(
+            move || {
+                LibSqlDb::new(
+                    path.clone(),
+                    &TRANSPARENT_METHODS,
+                    (),
+                    stats.clone(),
+                    ConnectionOptions::default(),
+                )
+            },
+            Some(QueryResultCache::new(16, frame_no)),
+        );
+        let db = factory.create().await.unwrap();
+        // writes through another connection are only noticed once the frame number advances
+        let writer = factory.create().await.unwrap();
+        let auth = Authenticated::Authorized(Authorized::FullAccess);
+
+        db.execute_one(query("CREATE TABLE test (x INTEGER)"), auth)
+            .await
+            .unwrap();
+        frame_no_sender.send_replace(1);
+        assert_eq!(count(&db, auth).await, 0);
+
+        writer
+            .execute_one(query("INSERT INTO test VALUES (1)"), auth)
+            .await
+            .unwrap();
+        // served from the cache
+        assert_eq!(count(&db, auth).await, 0);
+        // but not to anonymous users
+        let result = db
+            .execute_one(query("SELECT count(*) FROM test"), Authenticated::Anonymous)
+            .await;
+        assert!(matches!(result, Err(Error::NotAuthorized(_))));
+
+        // the write is committed
+        frame_no_sender.send_replace(2);
+        assert_eq!(count(&db, auth).await, 1);
+
+        // a transaction sees its own writes
+        db.execute_one(query("BEGIN"), auth).await.unwrap();
+        db.execute_one(query("INSERT INTO test VALUES (2)"), auth)
+            .await
+            .unwrap();
+        assert_eq!(count(&db, auth).await, 2);
+        db.execute_one(query("ROLLBACK"), auth).await.unwrap();
+        assert_eq!(count(&db, auth).await, 1);
+
+        // a connection sees its own writes before they show up in the frame number, like on
+        // replicas, where writes are applied once replicated from the primary
+        db.execute_one(query("INSERT INTO test VALUES (3)"), auth)
+            .await
+            .unwrap();
+        assert_eq!(count(&db, auth).await, 2);
+    }
+
+    #[test]
+    fn volatile_statements_not_cached() {
+        let program = |sql: &str| {
+            Program::new(vec![crate::database::Step {
+                cond: None,
+                query: query(sql),
+            }])
+        };
+        assert!(cache_key(&program("SELECT x FROM test")).is_some());
+        // columns named like volatile functions are fine
+        assert!(cache_key(&program("SELECT date, time FROM test")).is_some());
+
+        for sql in [
+            "SELECT random()",
+            "SELECT x FROM test WHERE x > RANDOM ()",
+            "SELECT datetime('now')",
+            "SELECT date('now', '-1 day')",
+            "SELECT strftime('%s', 'now')",
+            "SELECT julianday()",
+            "SELECT CURRENT_TIMESTAMP",
+            "SELECT changes()",
+            "SELECT total_changes()",
+            "SELECT last_insert_rowid()",
+        ] {
+            assert!(cache_key(&program(sql)).is_none(), "{sql} cached");
+        }
+    }
+
+    #[test]
+    fn least_recently_used_results_evicted() {
+        let (_frame_no_sender, frame_no) = watch::channel(0);
+        let cache = QueryResultCache::new(2, frame_no);
+        let response = || QueryResponse::ResultSet(crate::query::ResultSet::empty(false));
+        cache.insert(b"a".to_vec(), response(), 0);
+        cache.insert(b"b".to_vec(), response(), 0);
+        assert!(cache.get(b"a").is_some());
+        cache.insert(b"c".to_vec(), response(), 0);
+        assert!(cache.get(b"a").is_some());
+        assert!(cache.get(b"b").is_none());
+        assert!(cache.get(b"c").is_some());
+
+        // read before a write that was committed meanwhile
+        cache.insert(b"d".to_vec(), response(), 1);
+        assert!(cache.get(b"d").is_none());
+    }
+}
@@ -18,34 +18,36 @@ use crate::rpc::proxy::rpc::DisconnectMessage;
 use crate::stats::Stats;
 use crate::Result;
 
+use super::libsql::{ConnectionOptions, LibSqlDb};
 use super::Program;
-use super::{factory::DbFactory, libsql::LibSqlDb, Database, DescribeResult};
+use super::{factory::DbFactory, Database, DescribeResult};
 
 #[derive(Clone)]
 pub struct WriteProxyDbFactory {
     client: ProxyClient<Channel>,
     db_path: PathBuf,
-    extensions: Vec<PathBuf>,
     stats: Stats,
     applied_frame_no_receiver: watch::Receiver<FrameNo>,
+    /// Options of the local connections that serve reads
+    options: ConnectionOptions,
 }
 
 impl WriteProxyDbFactory {
     pub fn new(
         db_path: PathBuf,
-        extensions: Vec<PathBuf>,
         channel: Channel,
         uri: tonic::transport::Uri,
         stats: Stats,
         applied_frame_no_receiver: watch::Receiver<FrameNo>,
+        options: ConnectionOptions,
     ) -> Self {
         let client = ProxyClient::with_origin(channel, uri);
         Self {
             client,
             db_path,
-            extensions,
             stats,
             applied_frame_no_receiver,
+            options,
         }
     }
 }
@@ -56,9 +58,9 @@ impl DbFactory for WriteProxyDbFactory {
         let db = WriteProxyDatabase::new(
             self.client.clone(),
             self.db_path.clone(),
-            self.extensions.clone(),
             self.stats.clone(),
             self.applied_frame_no_receiver.clone(),
+            self.options.clone(),
         )
         .await?;
         Ok(Arc::new(db))
@@ -82,11 +84,11 @@ impl WriteProxyDatabase {
     async fn new(
         write_proxy: ProxyClient<Channel>,
         path: PathBuf,
-        extensions: Vec<PathBuf>,
         stats: Stats,
         applied_frame_no_receiver: watch::Receiver<FrameNo>,
+        options: ConnectionOptions,
     ) -> Result<Self> {
-        let read_db = LibSqlDb::new(path, extensions, &TRANSPARENT_METHODS, (), stats).await?;
+        let read_db = LibSqlDb::new(path, &TRANSPARENT_METHODS, (), stats, options).await?;
         Ok(Self {
             read_db,
             write_proxy,
@@ -0,0 +1,114 @@
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Raised while the volume holding the database is low on free space. Writes are rejected while
+/// the guard is raised, so that SQLite doesn't run out of disk in the middle of a write.
+#[derive(Clone, Default, Debug)]
+pub struct DiskSpaceGuard {
+    low: Arc<AtomicBool>,
+}
+
+impl DiskSpaceGuard {
+    /// returns true if writes should be rejected
+    pub fn is_low(&self) -> bool {
+        self.low.load(Ordering::Relaxed)
+    }
+
+    /// raises or lowers the guard, depending on how many bytes are still `available` on disk
+    pub fn update(&self, available: u64, min_free: u64) {
+        let low = available < min_free;
+        match (self.low.swap(low, Ordering::Relaxed), low) {
+            (false, true) => tracing::error!(
+                "only {available} bytes of disk space left, below the threshold of {min_free} bytes: rejecting writes"
+            ),
+            (true, false) => {
+                tracing::info!("{available} bytes of disk space available again: accepting writes")
+            }
+            _ => (),
+        }
+    }
+}
+
+/// Returns the number of bytes available to the server on the volume holding `path`.
+pub fn available_space(path: &Path) -> anyhow::Result<u64> {
+    let stat = nix::sys::statvfs::statvfs(path)?;
+    Ok(stat.blocks_available() as u64 * stat.fragment_size() as u64)
+}
+
+/// Periodically checks the space available on the volume holding the database with `probe`, and
+/// raises `guard` while it's below `min_free` bytes.
+pub async fn run_disk_space_monitor<F>(
+    db_path: PathBuf,
+    min_free: u64,
+    guard: DiskSpaceGuard,
+    probe: F,
+    period: Duration,
+) -> anyhow::Result<()>
+where
+    F: Fn(&Path) -> anyhow::Result<u64>,
+{
+    let mut interval = tokio::time::interval(period);
+    loop {
+        interval.tick().await;
+        match probe(&db_path) {
+            Ok(available) => guard.update(available, min_free),
+            // keep the previous state, the next check may succeed
+            Err(e) => tracing::warn!("failed to check available disk space: {e}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::atomic::AtomicU64;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn guard_follows_available_space() {
+        let available = Arc::new(AtomicU64::new(100));
+        let guard = DiskSpaceGuard::default();
+        let monitor = tokio::spawn(run_disk_space_monitor(
+            PathBuf::new(),
+            50,
+            guard.clone(),
+            {
+                let available = available.clone();
+                move |_: &Path| Ok(available.load(Ordering::Relaxed))
+            },
+            Duration::from_millis(10),
+        ));
+
+        let wait_for = |low: bool| {
+            let guard = guard.clone();
+            async move {
+                while guard.is_low() != low {
+                    tokio::time::sleep(Duration::from_millis(5)).await;
+                }
+            }
+        };
+
+        tokio::time::timeout(Duration::from_secs(5), wait_for(false))
+            .await
+            .unwrap();
+        available.store(10, Ordering::Relaxed);
+        tokio::time::timeout(Duration::from_secs(5), wait_for(true))
+            .await
+            .unwrap();
+        available.store(50, Ordering::Relaxed);
+        tokio::time::timeout(Duration::from_secs(5), wait_for(false))
+            .await
+            .unwrap();
+
+        monitor.abort();
+    }
+
+    #[test]
+    fn available_space_of_existing_dir() {
+        let tmp = tempfile::tempdir().unwrap();
+        assert!(available_space(tmp.path()).unwrap() > 0);
+        assert!(available_space(&tmp.path().join("missing")).is_err());
+    }
+}
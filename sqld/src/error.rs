@@ -29,6 +29,10 @@ pub enum Error {
     ReplicatorExited,
     #[error("Timed out while openning database connection")]
     DbCreateTimeout,
+    #[error("Query returned more than `{0}` rows")]
+    TooManyRows(u64),
+    #[error("Not enough disk space left to accept writes")]
+    DiskFull,
 }
 
 impl From<tokio::sync::oneshot::error::RecvError> for Error {
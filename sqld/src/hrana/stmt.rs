@@ -39,6 +39,10 @@ pub enum StmtError {
         message: String,
         offset: i32,
     },
+    #[error("Query returned more than {limit} rows")]
+    TooManyRows { limit: u64 },
+    #[error("Not enough disk space left to accept writes")]
+    DiskFull,
 }
 
 pub async fn execute_stmt(
@@ -210,6 +214,8 @@ pub fn stmt_error_from_sqld_error(sqld_error: SqldError) -> Result<StmtError, Sq
         SqldError::LibSqlInvalidQueryParams(source) => StmtError::ArgsInvalid { source },
         SqldError::LibSqlTxTimeout(_) => StmtError::TransactionTimeout,
         SqldError::LibSqlTxBusy => StmtError::TransactionBusy,
+        SqldError::TooManyRows(limit) => StmtError::TooManyRows { limit },
+        SqldError::DiskFull => StmtError::DiskFull,
         SqldError::RusqliteError(rusqlite_error) => match rusqlite_error {
             rusqlite::Error::SqliteFailure(sqlite_error, Some(message)) => StmtError::SqliteError {
                 source: sqlite_error,
@@ -254,6 +260,8 @@ impl StmtError {
             Self::TransactionBusy => "TRANSACTION_BUSY",
             Self::SqliteError { source, .. } => sqlite_error_code(source.code),
             Self::SqlInputError { .. } => "SQL_INPUT_ERROR",
+            Self::TooManyRows { .. } => "TOO_MANY_ROWS",
+            Self::DiskFull => "DISK_FULL",
         }
     }
 }
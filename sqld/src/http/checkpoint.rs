@@ -0,0 +1,223 @@
+use std::str::FromStr;
+
+use hyper::{Body, Request, Response, StatusCode};
+use serde::Serialize;
+
+use crate::auth::Authenticated;
+use crate::database::factory::DbFactory;
+use crate::database::Database;
+use crate::error::Error;
+use crate::query::{Params, Query, QueryResponse, Value};
+use crate::query_analysis::{Statement, StmtKind};
+
+/// The checkpoint modes supported by `PRAGMA wal_checkpoint`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CheckpointMode {
+    Passive,
+    Full,
+    Restart,
+    Truncate,
+}
+
+impl CheckpointMode {
+    fn as_str(&self) -> &'static str {
+        match self {
+            CheckpointMode::Passive => "PASSIVE",
+            CheckpointMode::Full => "FULL",
+            CheckpointMode::Restart => "RESTART",
+            CheckpointMode::Truncate => "TRUNCATE",
+        }
+    }
+}
+
+impl FromStr for CheckpointMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "passive" => Ok(CheckpointMode::Passive),
+            "full" => Ok(CheckpointMode::Full),
+            "restart" => Ok(CheckpointMode::Restart),
+            "truncate" => Ok(CheckpointMode::Truncate),
+            _ => anyhow::bail!("invalid checkpoint mode `{s}`"),
+        }
+    }
+}
+
+/// The outcome of a checkpoint, as reported by `PRAGMA wal_checkpoint`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct CheckpointResult {
+    /// The checkpoint could not complete because of concurrent readers or writers
+    pub busy: bool,
+    /// Number of frames in the WAL
+    pub log_frames: u32,
+    /// Number of frames of the WAL copied back into the database file
+    pub checkpointed_frames: u32,
+}
+
+/// Runs a checkpoint of the WAL in the given mode.
+pub async fn checkpoint(
+    db: &dyn Database,
+    mode: CheckpointMode,
+    auth: Authenticated,
+) -> crate::Result<CheckpointResult> {
+    // `wal_checkpoint` is rejected when parsing user queries, so the statement is built by hand.
+    // It is classified as a write, so that it requires full access.
+    let query = Query {
+        stmt: Statement {
+            stmt: format!("PRAGMA wal_checkpoint({})", mode.as_str()),
+            kind: StmtKind::Write,
+            is_iud: false,
+            is_insert: false,
+        },
+        params: Params::empty(),
+        want_rows: true,
+    };
+    let (result, _) = db.execute_one(query, auth).await?;
+    let QueryResponse::ResultSet(result_set) = result?;
+    let values = result_set
+        .rows
+        .first()
+        .map(|row| row.values.as_slice())
+        .unwrap_or_default();
+    match values {
+        [Value::Integer(busy), Value::Integer(log), Value::Integer(checkpointed)] => {
+            Ok(CheckpointResult {
+                busy: *busy != 0,
+                // -1 is reported when the database is not in WAL mode
+                log_frames: (*log).max(0) as u32,
+                checkpointed_frames: (*checkpointed).max(0) as u32,
+            })
+        }
+        _ => Err(Error::Internal(format!(
+            "unexpected wal_checkpoint result: {values:?}"
+        ))),
+    }
+}
+
+/// Handles `POST /v1/checkpoint`. Replicas don't own their WAL, which is written by replication,
+/// so checkpoints are only supported on the primary.
+pub async fn handle_checkpoint(
+    req: Request<Body>,
+    auth: Authenticated,
+    db_factory: &dyn DbFactory,
+    is_replica: bool,
+) -> anyhow::Result<Response<Body>> {
+    if is_replica {
+        return Ok(super::error(
+            "checkpoints can only be run on the primary",
+            StatusCode::BAD_REQUEST,
+        ));
+    }
+
+    let mode = req
+        .uri()
+        .query()
+        .into_iter()
+        .flat_map(|query| query.split('&'))
+        .find_map(|param| param.strip_prefix("mode="))
+        .map(CheckpointMode::from_str)
+        .transpose();
+    let mode = match mode {
+        Ok(mode) => mode.unwrap_or(CheckpointMode::Passive),
+        Err(e) => return Ok(super::error(&e.to_string(), StatusCode::BAD_REQUEST)),
+    };
+
+    let db = db_factory.create().await?;
+    match checkpoint(&*db, mode, auth).await {
+        Ok(result) => Ok(Response::builder()
+            .header("Content-Type", "application/json")
+            .body(Body::from(serde_json::to_vec(&result)?))?),
+        Err(e @ Error::NotAuthorized(_)) => Ok(super::error(&e.to_string(), StatusCode::FORBIDDEN)),
+        Err(e) => Ok(super::error(
+            &format!("internal error: {e}"),
+            StatusCode::INTERNAL_SERVER_ERROR,
+        )),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use sqld_libsql_bindings::wal_hook::TRANSPARENT_METHODS;
+
+    use super::*;
+    use crate::auth::Authorized;
+    use crate::database::libsql::{ConnectionOptions, LibSqlDb};
+    use crate::database::test_utils::query;
+    use crate::stats::Stats;
+
+    #[test]
+    fn parse_checkpoint_mode() {
+        assert_eq!(
+            "truncate".parse::<CheckpointMode>().unwrap(),
+            CheckpointMode::Truncate
+        );
+        assert_eq!(
+            "PASSIVE".parse::<CheckpointMode>().unwrap(),
+            CheckpointMode::Passive
+        );
+        assert!("everything".parse::<CheckpointMode>().is_err());
+    }
+
+    #[tokio::test]
+    async fn checkpoint_reports_frames_and_truncates_wal() {
+        let tmp = tempfile::tempdir().unwrap();
+        let stats = Stats::new(tmp.path()).unwrap();
+        let db = LibSqlDb::new(
+            tmp.path().to_path_buf(),
+            &TRANSPARENT_METHODS,
+            (),
+            stats,
+            ConnectionOptions::default(),
+        )
+        .await
+        .unwrap();
+        let auth = Authenticated::Authorized(Authorized::FullAccess);
+
+        db.execute_batch(
+            vec![
+                query("CREATE TABLE test (x INTEGER)"),
+                query("INSERT INTO test VALUES (1), (2), (3)"),
+            ],
+            auth,
+        )
+        .await
+        .unwrap();
+
+        let result = checkpoint(&db, CheckpointMode::Passive, auth)
+            .await
+            .unwrap();
+        assert!(!result.busy);
+        assert!(result.log_frames > 0);
+        assert_eq!(result.checkpointed_frames, result.log_frames);
+
+        let result = checkpoint(&db, CheckpointMode::Truncate, auth)
+            .await
+            .unwrap();
+        assert!(!result.busy);
+        let wal_len = std::fs::metadata(tmp.path().join("data-wal"))
+            .unwrap()
+            .len();
+        assert_eq!(wal_len, 0);
+
+        let read_only = Authenticated::Authorized(Authorized::ReadOnly);
+        assert!(matches!(
+            checkpoint(&db, CheckpointMode::Passive, read_only).await,
+            Err(Error::NotAuthorized(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn checkpoint_rejected_on_replica() {
+        let db_factory = || async { Err::<LibSqlDb, _>(Error::Internal("unreachable".into())) };
+        let auth = Authenticated::Authorized(Authorized::FullAccess);
+        let req = Request::post("/v1/checkpoint?mode=truncate")
+            .body(Body::empty())
+            .unwrap();
+
+        let resp = handle_checkpoint(req, auth, &db_factory, true)
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    }
+}
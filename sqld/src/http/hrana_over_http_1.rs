@@ -133,6 +133,8 @@ fn response_error_response(err: ResponseError) -> hyper::Response<hyper::Body> {
                 hyper::StatusCode::SERVICE_UNAVAILABLE
             }
             StmtError::SqliteError { .. } => hyper::StatusCode::INTERNAL_SERVER_ERROR,
+            StmtError::TooManyRows { .. } => hyper::StatusCode::PAYLOAD_TOO_LARGE,
+            StmtError::DiskFull => hyper::StatusCode::INSUFFICIENT_STORAGE,
         },
     };
 
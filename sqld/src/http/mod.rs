@@ -1,3 +1,4 @@
+mod checkpoint;
 mod hrana_over_http_1;
 pub mod stats;
 mod types;
@@ -193,7 +194,27 @@ async fn show_console() -> anyhow::Result<Response<Body>> {
     Ok(Response::new(Body::from(std::include_str!("console.html"))))
 }
 
-fn handle_health() -> Response<Body> {
+fn handle_health(stats: &Stats) -> Response<Body> {
+    if stats.replication_halted() {
+        // reads are still served, but the replica needs manual intervention
+        return Response::builder()
+            .status(StatusCode::SERVICE_UNAVAILABLE)
+            .body(Body::from("replication halted"))
+            .unwrap();
+    }
+    if stats.backup_frame_gaps() {
+        // the backup can't be restored up to its last consistent frame
+        return Response::builder()
+            .status(StatusCode::SERVICE_UNAVAILABLE)
+            .body(Body::from("backup has missing frames"))
+            .unwrap();
+    }
+    let near_limits = stats.near_limits();
+    if !near_limits.is_empty() {
+        // nothing is rejected yet, but alerts should fire
+        let names: Vec<_> = near_limits.into_iter().map(|limit| limit.name()).collect();
+        return Response::new(Body::from(format!("near limit: {}", names.join(", "))));
+    }
     // return empty OK
     Response::new(Body::empty())
 }
@@ -227,6 +248,7 @@ async fn handle_request(
     db_factory: Arc<dyn DbFactory>,
     enable_console: bool,
     stats: Stats,
+    is_replica: bool,
 ) -> anyhow::Result<Response<Body>> {
     if hyper_tungstenite::is_upgrade_request(&req) {
         return Ok(handle_upgrade(&upgrade_tx, req).await);
@@ -247,8 +269,14 @@ async fn handle_request(
         (&Method::POST, "/") => handle_query(req, auth, db_factory.clone()).await,
         (&Method::GET, "/version") => Ok(handle_version()),
         (&Method::GET, "/console") if enable_console => show_console().await,
-        (&Method::GET, "/health") => Ok(handle_health()),
+        (&Method::GET, "/health") => Ok(handle_health(&stats)),
         (&Method::GET, "/v1/stats") => Ok(stats::handle_stats(&stats)),
+        (&Method::POST, "/v1/stats/reset_counters") => {
+            Ok(stats::handle_reset_counters(auth, &stats))
+        }
+        (&Method::POST, "/v1/checkpoint") => {
+            checkpoint::handle_checkpoint(req, auth, &*db_factory, is_replica).await
+        }
 
         (&Method::GET, "/v1") => hrana_over_http_1::handle_index(req).await,
         (&Method::POST, "/v1/execute") => {
@@ -289,6 +317,7 @@ pub async fn run_http(
     enable_console: bool,
     idle_shutdown_layer: Option<IdleShutdownLayer>,
     stats: Stats,
+    is_replica: bool,
 ) -> anyhow::Result<()> {
     tracing::info!("listening for HTTP requests on {addr}");
 
@@ -322,6 +351,7 @@ pub async fn run_http(
                 db_factory.clone(),
                 enable_console,
                 stats.clone(),
+                is_replica,
             )
         });
 
@@ -1,13 +1,26 @@
-use hyper::{Body, Response};
+use hyper::{Body, Response, StatusCode};
+use std::time::UNIX_EPOCH;
+
 use serde::Serialize;
 
-use crate::stats::Stats;
+use crate::auth::{Authenticated, Authorized};
+use crate::stats::{CountersSnapshot, Limit, Stats};
 
 #[derive(Serialize)]
 pub struct StatsResponse {
     pub rows_read_count: u64,
     pub rows_written_count: u64,
     pub storage_bytes_used: u64,
+    pub last_write_time_ms: Option<u64>,
+    pub backup_check_passed: Option<bool>,
+    pub backup_check_duration_ms: Option<u64>,
+    pub replication_halted: bool,
+    pub backup_frame_gaps: bool,
+    pub near_limits: Vec<&'static str>,
+    pub counters: CountersSnapshot,
+    pub query_latency_p50_us: Option<u64>,
+    pub query_latency_p95_us: Option<u64>,
+    pub query_latency_p99_us: Option<u64>,
 }
 
 impl From<&Stats> for StatsResponse {
@@ -16,10 +29,32 @@ impl From<&Stats> for StatsResponse {
             rows_read_count: stats.rows_read(),
             rows_written_count: stats.rows_written(),
             storage_bytes_used: stats.storage_bytes_used(),
+            last_write_time_ms: stats.last_write_time().and_then(|time| {
+                time.duration_since(UNIX_EPOCH)
+                    .ok()
+                    .map(|d| d.as_millis() as u64)
+            }),
+            backup_check_passed: stats.last_backup_check().map(|(passed, _)| passed),
+            backup_check_duration_ms: stats
+                .last_backup_check()
+                .map(|(_, duration)| duration.as_millis() as u64),
+            replication_halted: stats.replication_halted(),
+            backup_frame_gaps: stats.backup_frame_gaps(),
+            near_limits: stats.near_limits().into_iter().map(Limit::name).collect(),
+            counters: stats.snapshot_counters(),
+            query_latency_p50_us: latency_micros(stats, 0.50),
+            query_latency_p95_us: latency_micros(stats, 0.95),
+            query_latency_p99_us: latency_micros(stats, 0.99),
         }
     }
 }
 
+fn latency_micros(stats: &Stats, p: f64) -> Option<u64> {
+    stats
+        .query_latency_percentile(p)
+        .map(|d| d.as_micros() as u64)
+}
+
 impl From<Stats> for StatsResponse {
     fn from(stats: Stats) -> Self {
         (&stats).into()
@@ -35,3 +70,11 @@ pub fn handle_stats(stats: &Stats) -> Response<Body> {
         .body(Body::from(payload))
         .unwrap()
 }
+
+pub fn handle_reset_counters(auth: Authenticated, stats: &Stats) -> Response<Body> {
+    if !matches!(auth, Authenticated::Authorized(Authorized::FullAccess)) {
+        return super::error("not authorized", StatusCode::FORBIDDEN);
+    }
+    stats.reset_counters();
+    Response::new(Body::empty())
+}
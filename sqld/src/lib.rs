@@ -2,35 +2,46 @@ use std::net::SocketAddr;
 use std::path::{Path, PathBuf};
 use std::sync::mpsc::RecvTimeoutError;
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, SystemTime};
 
 use anyhow::Context as AnyhowContext;
 use database::dump::loader::DumpLoader;
-use database::factory::DbFactory;
-use database::libsql::{open_db, LibSqlDbFactory};
+use database::factory::{DbFactory, WarmedDbFactory};
+use database::libsql::{open_db, ConnectionOptions, LibSqlDbFactory};
+use database::policy::QueryPolicy;
+use database::result_cache::QueryResultCache;
 use database::write_proxy::WriteProxyDbFactory;
+use database::Database;
 use futures::never::Never;
 use libsql::wal_hook::TRANSPARENT_METHODS;
 use once_cell::sync::Lazy;
 use replication::primary::logger::{ReplicationLoggerHookCtx, REPLICATION_METHODS};
 use replication::ReplicationLogger;
 use rpc::run_rpc_server;
-use tokio::sync::{mpsc, Notify};
+use tokio::sync::{mpsc, watch, Notify};
 use tokio::task::JoinSet;
 use tonic::transport::Channel;
-use utils::services::idle_shutdown::IdleShutdownLayer;
+use utils::services::idle_shutdown::{IdleKicker, IdleShutdownLayer};
 
 use crate::auth::Auth;
+use crate::disk_space::{run_disk_space_monitor, DiskSpaceGuard};
 use crate::error::Error;
 use crate::replication::replica::Replicator;
-use crate::stats::Stats;
+use crate::replication::FrameNo;
+use crate::soft_limit::SoftLimit;
+use crate::stats::{Limit, Stats};
 
 use sha256::try_digest;
 
+pub use auth::{Authenticated, Authorized};
+pub use replication::replica::ApplyErrorPolicy;
 pub use sqld_libsql_bindings as libsql;
 
 mod auth;
+#[cfg(feature = "bottomless")]
+mod backup_check;
 pub mod database;
+mod disk_space;
 mod error;
 mod heartbeat;
 mod hrana;
@@ -40,11 +51,15 @@ mod query;
 mod query_analysis;
 mod replication;
 pub mod rpc;
+mod soft_limit;
 mod stats;
 mod utils;
 
 const MAX_CONCCURENT_DBS: usize = 128;
 const DB_CREATE_TIMEOUT: Duration = Duration::from_secs(1);
+const DISK_SPACE_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+const KEEP_WARM_PERIOD: Duration = Duration::from_secs(30);
+const ANALYZE_BUSY_TIMEOUT: Duration = Duration::from_secs(30);
 
 #[derive(clap::ValueEnum, Clone, Debug, PartialEq)]
 pub enum Backend {
@@ -83,14 +98,31 @@ pub struct Config {
     pub rpc_server_ca_cert: Option<PathBuf>,
     #[cfg(feature = "bottomless")]
     pub enable_bottomless_replication: bool,
+    #[cfg(feature = "bottomless")]
+    pub backup_check_interval: Option<Duration>,
+    #[cfg(feature = "bottomless")]
+    pub frame_gap_check_interval: Option<Duration>,
     pub idle_shutdown_timeout: Option<Duration>,
-    pub load_from_dump: Option<PathBuf>,
+    pub load_from_dump: Vec<PathBuf>,
+    pub prefix_dump_tables: bool,
     pub max_log_size: u64,
     pub heartbeat_url: Option<String>,
     pub heartbeat_auth: Option<String>,
     pub heartbeat_period: Duration,
     pub soft_heap_limit_mb: Option<usize>,
     pub hard_heap_limit_mb: Option<usize>,
+    pub warmup_connections: usize,
+    pub analyze_interval: Option<Duration>,
+    pub max_rows_per_query: Option<u64>,
+    pub replica_reconnect_grace: Duration,
+    pub replica_apply_error_policy: ApplyErrorPolicy,
+    pub min_free_disk_mb: Option<u64>,
+    pub soft_limit_percent: Option<u8>,
+    pub busy_timeout: Option<Duration>,
+    pub query_policy: Option<Arc<dyn QueryPolicy>>,
+    pub keep_warm: bool,
+    pub pragmas: Vec<String>,
+    pub query_cache_size: Option<usize>,
 }
 
 async fn run_service(
@@ -102,6 +134,16 @@ async fn run_service(
 ) -> anyhow::Result<()> {
     let auth = get_auth(config)?;
 
+    if config.keep_warm {
+        // touch the database often enough for the idle shutdown to never trigger
+        let period = match config.idle_shutdown_timeout {
+            Some(idle_timeout) => KEEP_WARM_PERIOD.min(idle_timeout / 2),
+            None => KEEP_WARM_PERIOD,
+        };
+        let idle_kicker = idle_shutdown_layer.clone().map(|isl| isl.into_kicker());
+        join_set.spawn(run_keep_warm(db_factory.clone(), idle_kicker, period));
+    }
+
     if let Some(addr) = config.tcp_addr {
         join_set.spawn(postgres::server::run(addr, db_factory.clone()));
     }
@@ -140,6 +182,7 @@ async fn run_service(
             config.enable_http_console,
             idle_shutdown_layer,
             stats.clone(),
+            config.writer_rpc_addr.is_some(),
         ));
         join_set.spawn(async move {
             hrana_http_srv.run_expire().await;
@@ -248,6 +291,12 @@ fn configure_rpc(config: &Config) -> anyhow::Result<(Channel, tonic::transport::
     Ok((channel, uri))
 }
 
+fn connections_soft_limit(config: &Config, stats: &Stats) -> Option<SoftLimit> {
+    config
+        .soft_limit_percent
+        .map(|percent| SoftLimit::new(Limit::Connections, percent, stats.clone()))
+}
+
 async fn start_replica(
     config: &Config,
     join_set: &mut JoinSet<anyhow::Result<()>>,
@@ -255,22 +304,47 @@ async fn start_replica(
     stats: Stats,
 ) -> anyhow::Result<()> {
     let (channel, uri) = configure_rpc(config)?;
-    let replicator = Replicator::new(config.db_path.clone(), channel.clone(), uri.clone());
+    let replicator = Replicator::new(
+        config.db_path.clone(),
+        channel.clone(),
+        uri.clone(),
+        config.replica_reconnect_grace,
+        config.replica_apply_error_policy,
+        stats.clone(),
+    );
     let applied_frame_no_receiver = replicator.current_frame_no_notifier.subscribe();
+    join_set.spawn(run_last_write_monitor(
+        applied_frame_no_receiver.clone(),
+        stats.clone(),
+    ));
+    let result_cache = config
+        .query_cache_size
+        .map(|size| QueryResultCache::new(size, applied_frame_no_receiver.clone()));
 
     join_set.spawn(replicator.run());
 
     let valid_extensions = validate_extensions(config.extensions_path.clone())?;
+    let pragmas = validate_pragmas(&config.pragmas)?;
 
     let factory = WriteProxyDbFactory::new(
         config.db_path.clone(),
-        valid_extensions,
         channel,
         uri,
         stats.clone(),
         applied_frame_no_receiver,
+        ConnectionOptions {
+            extensions: valid_extensions,
+            max_rows_per_query: config.max_rows_per_query,
+            pragmas,
+            // writes are executed by the primary
+            ..Default::default()
+        },
     )
-    .throttled(MAX_CONCCURENT_DBS, Some(DB_CREATE_TIMEOUT));
+    .with_result_cache(result_cache)
+    .with_policy(config.query_policy.clone())
+    .throttled(MAX_CONCCURENT_DBS, Some(DB_CREATE_TIMEOUT))
+    .with_soft_limit(connections_soft_limit(config, &stats));
+    let factory = WarmedDbFactory::new(factory, config.warmup_connections).await?;
 
     run_service(
         Arc::new(factory),
@@ -333,6 +407,26 @@ fn validate_extensions(extensions_path: Option<PathBuf>) -> anyhow::Result<Vec<P
     Ok(valid_extensions)
 }
 
+/// With `prefix_dump_tables`, the tables of each dump are named after the dump file, e.g.
+/// `users.sql` creates `users_<table>` tables.
+fn dump_table_prefix(config: &Config, path: &Path) -> anyhow::Result<Option<String>> {
+    if !config.prefix_dump_tables {
+        return Ok(None);
+    }
+    let stem = path
+        .file_stem()
+        .and_then(|stem| stem.to_str())
+        .ok_or_else(|| anyhow::anyhow!("invalid dump path: {}", path.display()))?;
+    Ok(Some(format!("{stem}_")))
+}
+
+fn validate_pragmas(pragmas: &[String]) -> anyhow::Result<Vec<String>> {
+    for pragma in pragmas {
+        query_analysis::validate_pragma(pragma).context("invalid connection pragma")?;
+    }
+    Ok(pragmas.to_vec())
+}
+
 async fn start_primary(
     config: &Config,
     join_set: &mut JoinSet<anyhow::Result<()>>,
@@ -347,16 +441,76 @@ async fn start_primary(
 
     // load dump is necessary
     let dump_loader = DumpLoader::new(config.db_path.clone(), logger.clone()).await?;
-    if let Some(ref path) = config.load_from_dump {
-        if !is_fresh_db {
-            anyhow::bail!("cannot load from a dump if a database already exists.\nIf you're sure you want to load from a dump, delete your database folder at `{}`", config.db_path.display());
-        }
-        dump_loader.load_dump(path.into()).await?;
+    if !config.load_from_dump.is_empty() && !is_fresh_db {
+        anyhow::bail!("cannot load from a dump if a database already exists.\nIf you're sure you want to load from a dump, delete your database folder at `{}`", config.db_path.display());
+    }
+    // a dump that fails to load is rolled back, the ones loaded before it are kept
+    for path in &config.load_from_dump {
+        dump_loader
+            .load_dump(path.clone(), dump_table_prefix(config, path)?)
+            .await?;
     }
 
     let valid_extensions = validate_extensions(config.extensions_path.clone())?;
+    let pragmas = validate_pragmas(&config.pragmas)?;
+
+    join_set.spawn(run_last_write_monitor(
+        logger.new_frame_notifier.subscribe(),
+        stats.clone(),
+    ));
+
+    #[cfg(feature = "bottomless")]
+    if let Some(interval) = config.backup_check_interval {
+        if config.enable_bottomless_replication {
+            join_set.spawn(backup_check::run_backup_check(
+                config.db_path.clone(),
+                stats.clone(),
+                interval,
+                backup_check::restore_bottomless_backup,
+            ));
+        } else {
+            tracing::warn!("backup checks are enabled, but bottomless replication is not");
+        }
+    }
+
+    #[cfg(feature = "bottomless")]
+    if let Some(interval) = config.frame_gap_check_interval {
+        if config.enable_bottomless_replication {
+            let db_path = config.db_path.clone();
+            join_set.spawn(backup_check::run_frame_gap_check(
+                stats.clone(),
+                interval,
+                move || backup_check::find_bottomless_frame_gaps(db_path.clone()),
+            ));
+        } else {
+            tracing::warn!("frame gap checks are enabled, but bottomless replication is not");
+        }
+    }
+
+    if let Some(interval) = config.analyze_interval {
+        join_set.spawn(run_periodic_analyze(
+            config.db_path.clone(),
+            logger.clone(),
+            interval,
+        ));
+    }
 
-    let db_factory: Arc<_> = LibSqlDbFactory::new(
+    let result_cache = config
+        .query_cache_size
+        .map(|size| QueryResultCache::new(size, logger.new_frame_notifier.subscribe()));
+
+    let disk_space = DiskSpaceGuard::default();
+    if let Some(min_free_mb) = config.min_free_disk_mb {
+        join_set.spawn(run_disk_space_monitor(
+            config.db_path.clone(),
+            min_free_mb * 1024 * 1024,
+            disk_space.clone(),
+            disk_space::available_space,
+            DISK_SPACE_CHECK_INTERVAL,
+        ));
+    }
+
+    let db_factory = LibSqlDbFactory::new(
         config.db_path.clone(),
         &REPLICATION_METHODS,
         {
@@ -364,11 +518,22 @@ async fn start_primary(
             move || ReplicationLoggerHookCtx::new(logger.clone())
         },
         stats.clone(),
-        valid_extensions,
+        ConnectionOptions {
+            extensions: valid_extensions,
+            max_rows_per_query: config.max_rows_per_query,
+            busy_timeout: config.busy_timeout,
+            pragmas,
+            disk_space,
+        },
     )
     .await?
+    .with_result_cache(result_cache)
+    .with_policy(config.query_policy.clone())
     .throttled(MAX_CONCCURENT_DBS, Some(DB_CREATE_TIMEOUT))
-    .into();
+    .with_soft_limit(connections_soft_limit(config, &stats));
+    let db_factory: Arc<_> = WarmedDbFactory::new(db_factory, config.warmup_connections)
+        .await?
+        .into();
 
     if let Some(ref addr) = config.rpc_server_addr {
         join_set.spawn(run_rpc_server(
@@ -388,6 +553,78 @@ async fn start_primary(
     Ok(())
 }
 
+// Records the time of the last write in the Stats structure, whenever the frame number advances: a
+// new transaction was committed on the primary, or applied on a replica. A replica starts at
+// `FrameNo::MAX` until the handshake with the primary tells it where it's at, which isn't a write.
+async fn run_last_write_monitor(
+    mut frame_no: watch::Receiver<FrameNo>,
+    stats: Stats,
+) -> anyhow::Result<()> {
+    let mut last_frame_no = *frame_no.borrow();
+    while frame_no.changed().await.is_ok() {
+        let current_frame_no = *frame_no.borrow();
+        if current_frame_no != last_frame_no {
+            if last_frame_no != FrameNo::MAX {
+                stats.set_last_write_time(SystemTime::now());
+            }
+            last_frame_no = current_frame_no;
+        }
+    }
+
+    Ok(())
+}
+
+// Holds on to a connection and periodically runs a no-op query on it, so that the connection and
+// the SQLite page cache stay warm, and the idle shutdown doesn't trigger in the absence of client
+// traffic. If the query fails, the connection is dropped, and a new one is opened on the next run.
+async fn run_keep_warm(
+    db_factory: Arc<dyn DbFactory>,
+    idle_kicker: Option<IdleKicker>,
+    period: Duration,
+) -> anyhow::Result<()> {
+    let auth = auth::Authenticated::Authorized(auth::Authorized::FullAccess);
+    let mut db = None;
+    let mut interval = tokio::time::interval(period);
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    loop {
+        interval.tick().await;
+        if let Some(ref kicker) = idle_kicker {
+            kicker.kick();
+        }
+
+        let conn = match db {
+            Some(ref conn) => conn.clone(),
+            None => match db_factory.create().await {
+                Ok(conn) => {
+                    db = Some(conn.clone());
+                    conn
+                }
+                Err(e) => {
+                    tracing::warn!("failed to open keep-warm connection: {e}");
+                    continue;
+                }
+            },
+        };
+
+        let query = query::Query {
+            stmt: query_analysis::Statement::parse("SELECT 1")
+                .next()
+                .unwrap()
+                .unwrap(),
+            params: query::Params::empty(),
+            want_rows: false,
+        };
+        let result = conn.execute_one(query, auth).await;
+        match result {
+            Ok((Ok(_), _)) => (),
+            Ok((Err(e), _)) | Err(e) => {
+                tracing::warn!("keep-warm query failed: {e}");
+                db = None;
+            }
+        }
+    }
+}
+
 // Periodically check the storage used by the database and save it in the Stats structure.
 // TODO: Once we have a separate fiber that does WAL checkpoints, running this routine
 // right after checkpointing is exactly where it should be done.
@@ -436,9 +673,69 @@ async fn run_storage_monitor(db_path: PathBuf, stats: Stats) -> anyhow::Result<(
     Ok(())
 }
 
+// Periodically run ANALYZE, so that the query planner statistics keep up with the data. The
+// connection goes through the replication logger, so that replicas get the statistics too.
+//
+// ANALYZE is a write: it waits up to `ANALYZE_BUSY_TIMEOUT` for an open write transaction to
+// finish, and while it runs, client writes wait for it as long as their own busy timeout allows.
+// Its frames stay in the WAL until the next checkpoint, like any other write, and a RESTART or
+// TRUNCATE checkpoint issued while it runs reports `busy` and should be retried.
+async fn run_periodic_analyze(
+    db_path: PathBuf,
+    logger: Arc<ReplicationLogger>,
+    interval: Duration,
+) -> anyhow::Result<()> {
+    let (_drop_guard, exit_notify) = std::sync::mpsc::channel::<Never>();
+    tokio::task::spawn_blocking(move || -> anyhow::Result<()> {
+        let mut ctx = ReplicationLoggerHookCtx::new(logger);
+        let conn = open_db(&db_path, &REPLICATION_METHODS, &mut ctx, None)?;
+        conn.busy_timeout(ANALYZE_BUSY_TIMEOUT)?;
+        let mut last_data_version = None;
+        loop {
+            match exit_notify.recv_timeout(interval) {
+                Ok(_) => unreachable!(),
+                Err(RecvTimeoutError::Disconnected) => return Ok(()),
+                Err(RecvTimeoutError::Timeout) => (),
+            }
+
+            if let Err(e) = maybe_analyze(&conn, &mut last_data_version) {
+                tracing::warn!("periodic ANALYZE failed: {e}");
+            }
+        }
+    })
+    .await?
+}
+
+/// Runs ANALYZE, unless the database was not written to by other connections since the data
+/// version recorded in `last_data_version`. Returns whether ANALYZE was run.
+fn maybe_analyze(
+    conn: &rusqlite::Connection,
+    last_data_version: &mut Option<i64>,
+) -> rusqlite::Result<bool> {
+    let data_version = conn.query_row("PRAGMA data_version", (), |row| row.get(0))?;
+    if *last_data_version == Some(data_version) {
+        tracing::trace!("no writes since the last ANALYZE, skipping");
+        return Ok(false);
+    }
+
+    conn.execute_batch("ANALYZE")?;
+    tracing::debug!("ANALYZE complete");
+    *last_data_version = Some(data_version);
+
+    Ok(true)
+}
+
 pub async fn run_server(config: Config) -> anyhow::Result<()> {
     tracing::trace!("Backend: {:?}", config.backend);
 
+    // warmed connections hold a permit each, so there must be enough of them to go around
+    anyhow::ensure!(
+        config.warmup_connections <= MAX_CONCCURENT_DBS,
+        "cannot warm up {} connections, at most {} connections can be open at once",
+        config.warmup_connections,
+        MAX_CONCCURENT_DBS
+    );
+
     #[cfg(feature = "bottomless")]
     if config.enable_bottomless_replication {
         bottomless::static_init::register_bottomless_methods();
@@ -498,3 +795,190 @@ pub async fn run_server(config: Config) -> anyhow::Result<()> {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn last_write_time_follows_commits() {
+        use crate::database::libsql::LibSqlDb;
+        use crate::database::Database;
+        use crate::query::{Params, Query};
+        use crate::query_analysis::Statement;
+
+        let tmp = tempfile::tempdir().unwrap();
+        let stats = Stats::new(tmp.path()).unwrap();
+        let logger = Arc::new(ReplicationLogger::open(tmp.path(), 0).unwrap());
+        let monitor = tokio::spawn(run_last_write_monitor(
+            logger.new_frame_notifier.subscribe(),
+            stats.clone(),
+        ));
+        let db = LibSqlDb::new(
+            tmp.path().to_path_buf(),
+            &REPLICATION_METHODS,
+            ReplicationLoggerHookCtx::new(logger.clone()),
+            stats.clone(),
+            ConnectionOptions::default(),
+        )
+        .await
+        .unwrap();
+        let auth = auth::Authenticated::Authorized(auth::Authorized::FullAccess);
+        let write = |sql: &str| {
+            let query = Query {
+                stmt: Statement::parse(sql).next().unwrap().unwrap(),
+                params: Params::empty(),
+                want_rows: false,
+            };
+            db.execute_one(query, auth)
+        };
+        let wait_for_write_after = |time: SystemTime| {
+            let stats = stats.clone();
+            async move {
+                loop {
+                    if matches!(stats.last_write_time(), Some(t) if t >= time) {
+                        return stats.last_write_time().unwrap();
+                    }
+                    tokio::time::sleep(Duration::from_millis(5)).await;
+                }
+            }
+        };
+
+        assert_eq!(stats.last_write_time(), None);
+
+        let before = SystemTime::now() - Duration::from_millis(1);
+        let (result, _) = write("CREATE TABLE test (x INTEGER)").await.unwrap();
+        assert!(result.is_ok());
+        let first = tokio::time::timeout(Duration::from_secs(5), wait_for_write_after(before))
+            .await
+            .unwrap();
+        assert!(first.elapsed().unwrap() < Duration::from_secs(5));
+
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        let (result, _) = write("INSERT INTO test VALUES (1)").await.unwrap();
+        assert!(result.is_ok());
+        let second = tokio::time::timeout(
+            Duration::from_secs(5),
+            wait_for_write_after(first + Duration::from_millis(1)),
+        )
+        .await
+        .unwrap();
+        assert!(second > first);
+
+        monitor.abort();
+    }
+
+    #[tokio::test]
+    async fn replica_handshake_is_not_a_write() {
+        let tmp = tempfile::tempdir().unwrap();
+        let stats = Stats::new(tmp.path()).unwrap();
+        let (sender, receiver) = watch::channel(FrameNo::MAX);
+        let monitor = tokio::spawn(run_last_write_monitor(receiver, stats.clone()));
+
+        // the handshake moves the replica out of `FrameNo::MAX`
+        sender.send(42).unwrap();
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert_eq!(stats.last_write_time(), None);
+
+        // frames replicated afterwards are writes
+        sender.send(43).unwrap();
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while stats.last_write_time().is_none() {
+                tokio::time::sleep(Duration::from_millis(5)).await;
+            }
+        })
+        .await
+        .unwrap();
+
+        monitor.abort();
+    }
+
+    #[test]
+    fn analyze_is_skipped_without_writes() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("data");
+        let writer = rusqlite::Connection::open(&path).unwrap();
+        writer
+            .execute_batch(
+                "CREATE TABLE test (x INTEGER); CREATE INDEX test_x ON test(x); INSERT INTO test VALUES (1);",
+            )
+            .unwrap();
+
+        let conn = rusqlite::Connection::open(&path).unwrap();
+        let stat = |conn: &rusqlite::Connection| -> String {
+            conn.query_row(
+                "SELECT stat FROM sqlite_stat1 WHERE idx = 'test_x'",
+                (),
+                |row| row.get(0),
+            )
+            .unwrap()
+        };
+
+        let mut last_data_version = None;
+        assert!(maybe_analyze(&conn, &mut last_data_version).unwrap());
+        assert_eq!(stat(&conn), "1 1");
+        assert!(!maybe_analyze(&conn, &mut last_data_version).unwrap());
+
+        writer
+            .execute_batch("INSERT INTO test VALUES (2); INSERT INTO test VALUES (3);")
+            .unwrap();
+        assert!(maybe_analyze(&conn, &mut last_data_version).unwrap());
+        assert_eq!(stat(&conn), "3 1");
+    }
+
+    #[tokio::test]
+    async fn keep_warm_prevents_idle_shutdown() {
+        use crate::database::libsql::LibSqlDb;
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let tmp = tempfile::tempdir().unwrap();
+        let stats = Stats::new(tmp.path()).unwrap();
+        let opened = Arc::new(AtomicUsize::new(0));
+        let db_factory: Arc<dyn DbFactory> = Arc::new({
+            let path = tmp.path().to_path_buf();
+            let opened = opened.clone();
+            move || {
+                opened.fetch_add(1, Ordering::Relaxed);
+                LibSqlDb::new(
+                    path.clone(),
+                    &TRANSPARENT_METHODS,
+                    (),
+                    stats.clone(),
+                    ConnectionOptions::default(),
+                )
+            }
+        });
+        let shutdown = Arc::new(Notify::new());
+        let idle_shutdown_layer =
+            IdleShutdownLayer::new(Duration::from_millis(100), shutdown.clone());
+
+        let keep_warm = tokio::spawn(run_keep_warm(
+            db_factory,
+            Some(idle_shutdown_layer.into_kicker()),
+            Duration::from_millis(20),
+        ));
+
+        // no client traffic for several idle timeouts
+        let idle = tokio::time::timeout(Duration::from_millis(500), shutdown.notified()).await;
+        assert!(idle.is_err(), "idle shutdown triggered");
+        // the same connection was used all along
+        assert_eq!(opened.load(Ordering::Relaxed), 1);
+        assert!(!keep_warm.is_finished());
+
+        keep_warm.abort();
+    }
+
+    #[test]
+    fn validate_pragmas_rejects_other_statements() {
+        let pragmas = vec!["PRAGMA cache_size = -4000".to_string()];
+        assert_eq!(validate_pragmas(&pragmas).unwrap(), pragmas);
+
+        for sql in [
+            "SELECT 1",
+            "PRAGMA cache_size = -4000; DROP TABLE test",
+            "PRAGMA",
+        ] {
+            assert!(validate_pragmas(&[sql.to_string()]).is_err(), "{sql}");
+        }
+    }
+}
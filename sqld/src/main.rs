@@ -10,7 +10,8 @@ use std::{
 use anyhow::{bail, Context as _, Result};
 use clap::Parser;
 use mimalloc::MiMalloc;
-use sqld::{database::dump::exporter::export_dump, Config};
+use sqld::database::dump::{digest::content_digest, exporter::export_dump};
+use sqld::Config;
 use tracing_subscriber::{
     filter::LevelFilter, prelude::__tracing_subscriber_SubscriberExt, util::SubscriberInitExt,
     Layer,
@@ -36,6 +37,12 @@ struct Cli {
     #[clap(long, short)]
     extensions_path: Option<PathBuf>,
 
+    /// A PRAGMA statement executed on every new database connection, before it serves any query,
+    /// e.g. `--pragma "PRAGMA cache_size = -16000"`. Can be repeated, in which case the statements
+    /// are executed in order.
+    #[clap(long = "pragma")]
+    pragmas: Vec<String>,
+
     /// The address and port the PostgreSQL server listens to.
     #[clap(long, short, env = "SQLD_PG_LISTEN_ADDR")]
     pg_listen_addr: Option<SocketAddr>,
@@ -118,6 +125,18 @@ struct Cli {
     #[cfg(feature = "bottomless")]
     #[clap(long, env = "SQLD_ENABLE_BOTTOMLESS_REPLICATION")]
     enable_bottomless_replication: bool,
+    /// Interval in seconds at which the newest bottomless backup is restored into a temporary
+    /// location and checked with `PRAGMA quick_check`, to make sure it can actually be restored.
+    /// The outcome is reported in the stats. By default, backups are not checked.
+    #[cfg(feature = "bottomless")]
+    #[clap(long, env = "SQLD_BACKUP_CHECK_INTERVAL_S")]
+    backup_check_interval_s: Option<u64>,
+    /// Interval in seconds at which the newest bottomless generation is listed, to check that
+    /// no frame up to its last consistent frame is missing. Missing frames are logged and make
+    /// `/health` fail. By default, the backup is not checked for missing frames.
+    #[cfg(feature = "bottomless")]
+    #[clap(long, env = "SQLD_FRAME_GAP_CHECK_INTERVAL_S")]
+    frame_gap_check_interval_s: Option<u64>,
     /// The duration, in second, after which to shutdown the server if no request have been
     /// received.
     /// By default, the server doesn't shutdown when idle.
@@ -125,9 +144,17 @@ struct Cli {
     idle_shutdown_timeout_s: Option<u64>,
 
     /// Load the dump at the provided path.
-    /// Requires that the node is not in replica mode
+    /// Requires that the node is not in replica mode.
+    /// Can be repeated to load several dumps in order. A dump that fails to load is rolled back,
+    /// and the dumps loaded before it are kept.
     #[clap(long, env = "SQLD_LOAD_DUMP_PATH", conflicts_with = "primary_grpc_url")]
-    load_from_dump: Option<PathBuf>,
+    load_from_dump: Vec<PathBuf>,
+
+    /// Prefix the tables and indexes created by each dump with the name of the dump file, e.g.
+    /// `--load-from-dump users.sql` creates `users_<table>`, so that dumps using the same table
+    /// names can be loaded into the same database.
+    #[clap(long, requires = "load_from_dump")]
+    prefix_dump_tables: bool,
 
     /// Maximum size the replication log is allowed to grow (in MB).
     /// defaults to 200MB.
@@ -161,6 +188,74 @@ struct Cli {
     /// if it goes over this limit with memory usage.
     #[clap(long, env = "SQLD_HARD_HEAP_LIMIT_MB")]
     hard_heap_limit_mb: Option<usize>,
+
+    /// Number of database connections to open eagerly on startup, so that the first queries don't
+    /// pay the cost of opening a connection. Warmed connections count against the concurrent
+    /// connections limit, so at most 128 can be warmed up.
+    #[clap(long, env = "SQLD_WARMUP_CONNECTIONS", default_value = "0")]
+    warmup_connections: usize,
+
+    /// Keep a connection open and periodically run a no-op query on it, so that the database
+    /// stays warm without client traffic. This also prevents the idle shutdown from triggering.
+    #[clap(long, env = "SQLD_KEEP_WARM")]
+    keep_warm: bool,
+
+    /// Interval in seconds at which ANALYZE is run on the database to keep the query planner
+    /// statistics up to date. Runs are skipped if nothing was written since the previous one.
+    /// ANALYZE holds the write lock while it runs, so concurrent writes wait for it up to
+    /// `--busy-timeout-ms`. By default, ANALYZE is never run automatically.
+    #[clap(long, env = "SQLD_ANALYZE_INTERVAL_S")]
+    analyze_interval_s: Option<u64>,
+
+    /// Maximum number of rows a single query may return. Queries returning more rows fail with
+    /// an error instead of being buffered in memory. By default, there is no limit.
+    #[clap(long, env = "SQLD_MAX_ROWS_PER_QUERY")]
+    max_rows_per_query: Option<u64>,
+
+    /// Cache the results of up to this many distinct read queries, and serve repeated queries
+    /// from the cache until the next write. Queries calling functions that depend on the time,
+    /// the connection or chance, i.e. `random()`, `randomblob()`, `changes()`, `total_changes()`,
+    /// `last_insert_rowid()`, `date()`, `time()`, `datetime()`, `julianday()`, `unixepoch()`,
+    /// `strftime()` and `CURRENT_DATE`/`CURRENT_TIME`/`CURRENT_TIMESTAMP`, are never cached. Other
+    /// non-deterministic functions, e.g. from extensions, are cached as is. By default, nothing is
+    /// cached.
+    #[clap(long, env = "SQLD_QUERY_CACHE_SIZE")]
+    query_cache_size: Option<usize>,
+
+    /// Time in seconds during which a replica that lost its connection to the primary keeps
+    /// serving reads from its current state and retries streaming from where it stopped, before
+    /// tearing down its replication state and performing a new handshake.
+    /// By default, the replica performs a new handshake right away.
+    #[clap(long, env = "SQLD_REPLICA_RECONNECT_GRACE_S", default_value = "0")]
+    replica_reconnect_grace_s: u64,
+
+    /// What a replica does when frames received from the primary fail to be applied: `reset`
+    /// performs a new handshake with the primary, `halt` stops replicating and keeps serving
+    /// reads from the last applied frame, with `/health` reporting the failure, and `retry`
+    /// retries the transaction a bounded number of times before halting.
+    #[clap(
+        long,
+        value_enum,
+        default_value = "reset",
+        env = "SQLD_REPLICA_APPLY_ERROR_POLICY"
+    )]
+    replica_apply_error_policy: sqld::ApplyErrorPolicy,
+
+    /// Minimum free disk space, in MB, on the volume holding the database. While less space is
+    /// available, write queries are rejected with a disk full error; reads are still served.
+    #[clap(long, env = "SQLD_MIN_FREE_DISK_MB")]
+    min_free_disk_mb: Option<u64>,
+
+    /// Percentage of a hard limit, such as the maximum number of concurrent connections, above
+    /// which a warning is logged and the limit is reported as near in `/health` and `/v1/stats`.
+    /// Nothing is rejected until the hard limit itself is reached.
+    #[clap(long, env = "SQLD_SOFT_LIMIT_PERCENT", value_parser = clap::value_parser!(u8).range(1..=100))]
+    soft_limit_percent: Option<u8>,
+
+    /// Time in milliseconds a write waits for a concurrent writer to release the write lock
+    /// before failing with a busy error. By default, it fails right away.
+    #[clap(long, env = "SQLD_BUSY_TIMEOUT_MS")]
+    busy_timeout_ms: Option<u64>,
 }
 
 #[derive(clap::Subcommand, Debug)]
@@ -169,6 +264,19 @@ enum UtilsSubcommands {
         #[clap(long)]
         /// Path at which to write the dump
         path: Option<PathBuf>,
+        #[clap(long)]
+        /// Compress the dump with gzip
+        gzip: bool,
+    },
+    /// Print a digest of the database schema and contents, which is equal for databases with
+    /// identical contents
+    Digest,
+    /// Upload a gzip-compressed SQL dump of the database, along with a manifest, to the bottomless
+    /// bucket. Logical backups are independent of generations and are never used to restore.
+    #[cfg(feature = "bottomless")]
+    LogicalBackup {
+        /// Name under which the backup is stored
+        key: String,
     },
 }
 
@@ -255,18 +363,36 @@ fn config_from_args(args: Cli) -> Result<Config> {
         rpc_server_ca_cert: args.grpc_ca_cert_file,
         #[cfg(feature = "bottomless")]
         enable_bottomless_replication: args.enable_bottomless_replication,
+        #[cfg(feature = "bottomless")]
+        backup_check_interval: args.backup_check_interval_s.map(Duration::from_secs),
+        #[cfg(feature = "bottomless")]
+        frame_gap_check_interval: args.frame_gap_check_interval_s.map(Duration::from_secs),
         idle_shutdown_timeout: args.idle_shutdown_timeout_s.map(Duration::from_secs),
         load_from_dump: args.load_from_dump,
+        prefix_dump_tables: args.prefix_dump_tables,
         max_log_size: args.max_log_size,
         heartbeat_url: args.heartbeat_url,
         heartbeat_auth: args.heartbeat_auth,
         heartbeat_period: Duration::from_secs(args.heartbeat_period_s),
         soft_heap_limit_mb: args.soft_heap_limit_mb,
         hard_heap_limit_mb: args.hard_heap_limit_mb,
+        warmup_connections: args.warmup_connections,
+        analyze_interval: args.analyze_interval_s.map(Duration::from_secs),
+        max_rows_per_query: args.max_rows_per_query,
+        replica_reconnect_grace: Duration::from_secs(args.replica_reconnect_grace_s),
+        replica_apply_error_policy: args.replica_apply_error_policy,
+        min_free_disk_mb: args.min_free_disk_mb,
+        soft_limit_percent: args.soft_limit_percent,
+        busy_timeout: args.busy_timeout_ms.map(Duration::from_millis),
+        // only set by programs embedding the library
+        query_policy: None,
+        keep_warm: args.keep_warm,
+        query_cache_size: args.query_cache_size,
+        pragmas: args.pragmas,
     })
 }
 
-fn perform_dump(dump_path: Option<&Path>, db_path: &Path) -> anyhow::Result<()> {
+fn perform_dump(dump_path: Option<&Path>, db_path: &Path, gzip: bool) -> anyhow::Result<()> {
     let out: Box<dyn Write> = match dump_path {
         Some(path) => {
             let f = OpenOptions::new()
@@ -280,7 +406,38 @@ fn perform_dump(dump_path: Option<&Path>, db_path: &Path) -> anyhow::Result<()>
     };
     let conn = rusqlite::Connection::open(db_path.join("data"))?;
 
-    export_dump(conn, out)?;
+    if gzip {
+        let mut encoder = flate2::write::GzEncoder::new(out, flate2::Compression::default());
+        export_dump(conn, &mut encoder)?;
+        encoder.finish()?;
+    } else {
+        export_dump(conn, out)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(feature = "bottomless")]
+async fn upload_logical_backup(db_path: &Path, key: &str) -> anyhow::Result<()> {
+    use sqld::database::dump::logical_backup::write_logical_backup;
+
+    let data_path = db_path.join("data");
+    let mut replicator = bottomless::replicator::Replicator::new().await?;
+    replicator.register_db(data_path.to_str().context("invalid database path")?);
+
+    let conn = rusqlite::Connection::open_with_flags(
+        &data_path,
+        rusqlite::OpenFlags::SQLITE_OPEN_READ_ONLY,
+    )?;
+    let backup = tempfile::NamedTempFile::new_in(db_path)?;
+    let manifest = write_logical_backup(conn, &replicator.db_name, backup.as_file())?;
+    replicator
+        .upload_logical_backup(key, backup.path(), serde_json::to_vec(&manifest)?.into())
+        .await?;
+    eprintln!(
+        "Uploaded logical backup {key} of database {}: {} tables, {} rows",
+        manifest.db_name, manifest.tables, manifest.rows
+    );
 
     Ok(())
 }
@@ -325,7 +482,7 @@ async fn main() -> Result<()> {
     let args = Cli::parse();
 
     match args.utils {
-        Some(UtilsSubcommands::Dump { path }) => {
+        Some(UtilsSubcommands::Dump { path, gzip }) => {
             if let Some(ref path) = path {
                 eprintln!(
                     "Dumping database {} to {}",
@@ -333,7 +490,19 @@ async fn main() -> Result<()> {
                     path.display()
                 );
             }
-            perform_dump(path.as_deref(), &args.db_path)
+            perform_dump(path.as_deref(), &args.db_path, gzip)
+        }
+        Some(UtilsSubcommands::Digest) => {
+            let conn = rusqlite::Connection::open_with_flags(
+                args.db_path.join("data"),
+                rusqlite::OpenFlags::SQLITE_OPEN_READ_ONLY,
+            )?;
+            println!("{}", hex::encode(content_digest(&conn)?));
+            Ok(())
+        }
+        #[cfg(feature = "bottomless")]
+        Some(UtilsSubcommands::LogicalBackup { key }) => {
+            upload_logical_backup(&args.db_path, &key).await
         }
         None => {
             args.print_welcome_message();
@@ -344,3 +513,40 @@ async fn main() -> Result<()> {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::io::Read;
+
+    use super::*;
+
+    #[test]
+    fn gzip_dump_matches_plain_dump() {
+        let tmp = tempfile::tempdir().unwrap();
+        let conn = rusqlite::Connection::open(tmp.path().join("data")).unwrap();
+        conn.execute_batch(
+            "CREATE TABLE test (x INTEGER, y TEXT); INSERT INTO test VALUES (1, 'one'), (2, 'two');",
+        )
+        .unwrap();
+        drop(conn);
+
+        let plain_path = tmp.path().join("dump.sql");
+        let gzip_path = tmp.path().join("dump.sql.gz");
+        perform_dump(Some(&plain_path), tmp.path(), false).unwrap();
+        perform_dump(Some(&gzip_path), tmp.path(), true).unwrap();
+
+        let plain = fs::read_to_string(&plain_path).unwrap();
+        let mut decompressed = String::new();
+        flate2::read::GzDecoder::new(fs::File::open(&gzip_path).unwrap())
+            .read_to_string(&mut decompressed)
+            .unwrap();
+        assert_eq!(decompressed, plain);
+
+        let conn = rusqlite::Connection::open_in_memory().unwrap();
+        conn.execute_batch(&decompressed).unwrap();
+        let count: usize = conn
+            .query_row("SELECT count(*) FROM test", (), |row| row.get(0))
+            .unwrap();
+        assert_eq!(count, 2);
+    }
+}
@@ -18,9 +18,13 @@ impl From<Error> for PgWireError {
             Error::LibSqlTxBusy => {
                 PgWireError::IoError(io::Error::new(io::ErrorKind::WouldBlock, other.to_string()))
             }
-            Error::LibSqlTxTimeout(_) => PgWireError::UserError(Box::new(ErrorInfo::new(
+            Error::LibSqlTxTimeout(_) | Error::TooManyRows(_) => PgWireError::UserError(Box::new(
+                ErrorInfo::new("ERROR".to_owned(), "XX000".to_owned(), other.to_string()),
+            )),
+            // disk_full
+            Error::DiskFull => PgWireError::UserError(Box::new(ErrorInfo::new(
                 "ERROR".to_owned(),
-                "XX000".to_owned(),
+                "53100".to_owned(),
                 other.to_string(),
             ))),
             _ => PgWireError::IoError(io::Error::new(io::ErrorKind::Other, other.to_string())),
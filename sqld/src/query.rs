@@ -117,7 +117,7 @@ impl FromStr for Type {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Row {
     pub values: Vec<Value>,
 }
@@ -146,7 +146,7 @@ impl From<rusqlite::types::Value> for Value {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct ResultSet {
     pub columns: Vec<Column>,
     pub rows: Vec<Row>,
@@ -269,7 +269,7 @@ impl From<ResultRows> for ResultSet {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum QueryResponse {
     ResultSet(ResultSet),
 }
@@ -284,6 +284,20 @@ impl Statement {
     }
 }
 
+/// Checks that `sql` consists of a single PRAGMA statement.
+pub fn validate_pragma(sql: &str) -> Result<()> {
+    let mut parser = Box::new(Parser::new(sql.as_bytes()));
+    match parser.next()? {
+        Some(Cmd::Stmt(Stmt::Pragma(..))) => (),
+        _ => anyhow::bail!("`{sql}` is not a PRAGMA statement"),
+    }
+    anyhow::ensure!(
+        parser.next()?.is_none(),
+        "`{sql}` must contain a single PRAGMA statement"
+    );
+    Ok(())
+}
+
 /// Given a an initial state and an array of queries, attempts to predict what the final state will
 /// be
 pub fn predict_final_state<'a>(
@@ -186,6 +186,14 @@ pub struct LogFile {
     commited_checksum: u64,
 }
 
+#[derive(thiserror::Error, Debug)]
+pub enum LogHeaderError {
+    #[error("replication log has no valid header: it was not written by sqld, or is corrupted. Remove the `wallog` file and the snapshots next to it to start a new log")]
+    InvalidMagic,
+    #[error("incompatible replication log version: found {found}, supported {supported}")]
+    IncompatibleLogVersion { found: u32, supported: u32 },
+}
+
 #[derive(thiserror::Error, Debug)]
 pub enum LogReadError {
     #[error("could not fetch log entry, snapshot required")]
@@ -199,6 +207,8 @@ pub enum LogReadError {
 impl LogFile {
     /// size of a single frame
     pub const FRAME_SIZE: usize = size_of::<FrameHeader>() + WAL_PAGE_SIZE as usize;
+    /// version of the log file format written by this version of sqld
+    pub const VERSION: u32 = 1;
 
     pub fn new(file: File, max_log_frame_count: u64) -> anyhow::Result<Self> {
         // FIXME: we should probably take a lock on this file, to prevent anybody else to write to
@@ -208,7 +218,7 @@ impl LogFile {
         if file_end == 0 {
             let db_id = Uuid::new_v4();
             let header = LogFileHeader {
-                version: 1,
+                version: Self::VERSION,
                 start_frame_no: 0,
                 magic: WAL_MAGIC,
                 page_size: WAL_PAGE_SIZE,
@@ -261,10 +271,22 @@ impl LogFile {
         file.read_exact_at(&mut buf, 0)?;
         let header: LogFileHeader = pod_read_unaligned(&buf);
         if header.magic != WAL_MAGIC {
-            bail!("invalid replication log header");
+            bail!(LogHeaderError::InvalidMagic);
         }
 
-        Ok(header)
+        Self::migrate_header(header)
+    }
+
+    /// Brings a header read from disk up to the current log format version. This is where log
+    /// files written by older versions of sqld should be migrated when the format changes.
+    fn migrate_header(header: LogFileHeader) -> anyhow::Result<LogFileHeader> {
+        match header.version {
+            Self::VERSION => Ok(header),
+            found => bail!(LogHeaderError::IncompatibleLogVersion {
+                found,
+                supported: Self::VERSION,
+            }),
+        }
     }
 
     pub fn header(&self) -> &LogFileHeader {
@@ -675,6 +697,46 @@ mod test {
         logger.commit().unwrap();
     }
 
+    #[test]
+    fn open_current_version_log() {
+        let dir = tempfile::tempdir().unwrap();
+        let db_id = ReplicationLogger::open(dir.path(), 0)
+            .unwrap()
+            .database_id()
+            .unwrap();
+
+        let logger = ReplicationLogger::open(dir.path(), 0).unwrap();
+        assert_eq!(logger.database_id().unwrap(), db_id);
+        assert_eq!(logger.log_file.read().header().version, LogFile::VERSION);
+    }
+
+    #[test]
+    fn reject_future_version_log() {
+        let f = tempfile::tempfile().unwrap();
+        let mut log_file = LogFile::new(f.try_clone().unwrap(), 100).unwrap();
+        log_file.header.version = LogFile::VERSION + 1;
+        log_file.write_header().unwrap();
+
+        let err = LogFile::new(f, 100).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<LogHeaderError>(),
+            Some(LogHeaderError::IncompatibleLogVersion { found, supported })
+                if *found == LogFile::VERSION + 1 && *supported == LogFile::VERSION
+        ));
+    }
+
+    #[test]
+    fn reject_headerless_log() {
+        let mut f = tempfile::tempfile().unwrap();
+        f.write_all(&[1; LogFile::FRAME_SIZE]).unwrap();
+
+        let err = LogFile::new(f, 100).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<LogHeaderError>(),
+            Some(LogHeaderError::InvalidMagic)
+        ));
+    }
+
     #[test]
     fn log_file_test_rollback() {
         let f = tempfile::tempfile().unwrap();
@@ -4,6 +4,8 @@ pub enum ReplicationError {
     Lagging,
     #[error("Trying to replicate incompatible databases")]
     DbIncompatible,
+    #[error("Failed to apply frames: {0}")]
+    Apply(anyhow::Error),
     #[error("{0}")]
     Other(#[from] anyhow::Error),
 }
@@ -5,4 +5,4 @@ mod meta;
 mod replicator;
 mod snapshot;
 
-pub use replicator::Replicator;
+pub use replicator::{ApplyErrorPolicy, Replicator};
@@ -1,5 +1,5 @@
 use std::path::PathBuf;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use anyhow::bail;
 use futures::StreamExt;
@@ -13,14 +13,49 @@ use crate::rpc::replication_log::rpc::{
     replication_log_client::ReplicationLogClient, HelloRequest, LogOffset,
 };
 use crate::rpc::replication_log::NEED_SNAPSHOT_ERROR_MSG;
+use crate::stats::Stats;
 
+use super::error::ReplicationError;
 use super::hook::Frames;
 use super::injector::FrameInjectorHandle;
 
 const HANDSHAKE_MAX_RETRIES: usize = 100;
+/// Number of times a transaction that failed to apply is retried under the `Retry` policy.
+const APPLY_MAX_RETRIES: usize = 10;
 
 type Client = ReplicationLogClient<Channel>;
 
+/// What the replica does when frames received from the primary fail to be applied.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ApplyErrorPolicy {
+    /// Tear down the replication state and perform a new handshake with the primary.
+    Reset,
+    /// Stop replicating, and keep serving reads from the last successfully applied frame until
+    /// an operator intervenes.
+    Halt,
+    /// Retry applying the transaction a bounded number of times, then halt.
+    Retry,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ApplyErrorAction {
+    Retry,
+    Reset,
+    Halt,
+}
+
+impl ApplyErrorPolicy {
+    /// Returns what to do after the same transaction failed to apply `failures` times in a row.
+    fn action(self, failures: usize) -> ApplyErrorAction {
+        match self {
+            ApplyErrorPolicy::Reset => ApplyErrorAction::Reset,
+            ApplyErrorPolicy::Halt => ApplyErrorAction::Halt,
+            ApplyErrorPolicy::Retry if failures <= APPLY_MAX_RETRIES => ApplyErrorAction::Retry,
+            ApplyErrorPolicy::Retry => ApplyErrorAction::Halt,
+        }
+    }
+}
+
 /// The `Replicator` duty is to download frames from the primary, and pass them to the injector at
 /// transaction boundaries.
 pub struct Replicator {
@@ -29,10 +64,22 @@ pub struct Replicator {
     injector: Option<FrameInjectorHandle>,
     current_frame_no: FrameNo,
     pub current_frame_no_notifier: watch::Sender<FrameNo>,
+    /// How long replication errors are retried before the injector is torn down and a new
+    /// handshake is performed.
+    reconnect_grace: Duration,
+    apply_error_policy: ApplyErrorPolicy,
+    stats: Stats,
 }
 
 impl Replicator {
-    pub fn new(db_path: PathBuf, channel: Channel, uri: tonic::transport::Uri) -> Self {
+    pub fn new(
+        db_path: PathBuf,
+        channel: Channel,
+        uri: tonic::transport::Uri,
+        reconnect_grace: Duration,
+        apply_error_policy: ApplyErrorPolicy,
+        stats: Stats,
+    ) -> Self {
         let client = Client::with_origin(channel, uri);
         let (applied_frame_notifier, _) = watch::channel(FrameNo::MAX);
         Self {
@@ -41,29 +88,114 @@ impl Replicator {
             injector: None,
             current_frame_no: FrameNo::MAX,
             current_frame_no_notifier: applied_frame_notifier,
+            reconnect_grace,
+            apply_error_policy,
+            stats,
         }
     }
 
     pub async fn run(mut self) -> anyhow::Result<()> {
+        // When replication started failing, reset whenever new frames are applied
+        let mut failing_since: Option<Instant> = None;
+        // Number of times in a row the current transaction failed to apply
+        let mut apply_failures = 0;
         loop {
             if self.injector.is_none() {
                 self.try_perform_handshake().await?;
             }
 
+            let frame_no_before = self.current_frame_no();
             if let Err(e) = self.replicate().await {
-                // Replication encountered an error. We log the error, and then shut down the
-                // injector and propagate a potential panic from there.
-                tracing::warn!("replication error: {e}");
-                if let Some(injector) = self.injector.take() {
-                    if let Err(e) = injector.shutdown().await {
-                        tracing::warn!("error shutting down frame injector: {e}");
+                if self.current_frame_no() != frame_no_before {
+                    failing_since = None;
+                    apply_failures = 0;
+                }
+                if let Some(e @ ReplicationError::Apply(_)) = e.downcast_ref::<ReplicationError>() {
+                    apply_failures += 1;
+                    match self.on_apply_error(e, apply_failures).await {
+                        ApplyErrorAction::Retry => (),
+                        ApplyErrorAction::Reset => apply_failures = 0,
+                        // keep the replicator alive, so that readers waiting on the current
+                        // frame number are not notified of its shutdown
+                        ApplyErrorAction::Halt => return std::future::pending().await,
                     }
+                    tokio::time::sleep(Duration::from_secs(1)).await;
+                    continue;
                 }
+                self.on_replication_error(&e, &mut failing_since).await;
+            } else {
+                failing_since = None;
+                apply_failures = 0;
             }
             tokio::time::sleep(Duration::from_secs(1)).await;
         }
     }
 
+    /// Handles an error streaming frames from the primary, with replication failing since
+    /// `failing_since`. Within the reconnect grace period, the injector is kept, so that the
+    /// replica keeps serving reads and resumes streaming from its current frame.
+    async fn on_replication_error(
+        &mut self,
+        e: &anyhow::Error,
+        failing_since: &mut Option<Instant>,
+    ) {
+        let failing_for = failing_since.get_or_insert_with(Instant::now).elapsed();
+        if failing_for < self.reconnect_grace {
+            // The primary may only be briefly unavailable: keep the injector, and with it
+            // the replica state, and resume streaming from the current frame.
+            tracing::warn!(
+                "replication error: {e}. retrying, failing for {failing_for:?} out of {:?} grace period",
+                self.reconnect_grace
+            );
+        } else {
+            // Replication encountered an error. We log the error, and then shut down the
+            // injector and propagate a potential panic from there.
+            tracing::warn!("replication error: {e}");
+            *failing_since = None;
+            self.shutdown_injector().await;
+        }
+    }
+
+    /// Handles the `failures`th error in a row applying the current transaction, according to the
+    /// apply error policy.
+    async fn on_apply_error(&mut self, e: &ReplicationError, failures: usize) -> ApplyErrorAction {
+        let action = self.apply_error_policy.action(failures);
+        match action {
+            ApplyErrorAction::Retry => {
+                tracing::warn!("{e}. retrying ({failures}/{APPLY_MAX_RETRIES})");
+            }
+            ApplyErrorAction::Reset => {
+                tracing::warn!("{e}. resetting replication state");
+                self.shutdown_injector().await;
+            }
+            ApplyErrorAction::Halt => {
+                self.halt().await;
+                tracing::error!(
+                    "{e}. replication halted, serving reads at frame {:?} until manual intervention",
+                    self.current_frame_no()
+                );
+            }
+        }
+
+        action
+    }
+
+    /// Shuts down the injector and propagates a potential panic from there. A new handshake is
+    /// performed on the next replication attempt.
+    async fn shutdown_injector(&mut self) {
+        if let Some(injector) = self.injector.take() {
+            if let Err(e) = injector.shutdown().await {
+                tracing::warn!("error shutting down frame injector: {e}");
+            }
+        }
+    }
+
+    /// Stops applying frames and raises the replication halted alarm.
+    async fn halt(&mut self) {
+        self.shutdown_injector().await;
+        self.stats.set_replication_halted(true);
+    }
+
     async fn try_perform_handshake(&mut self) -> anyhow::Result<()> {
         let mut error_printed = false;
         for _ in 0..HANDSHAKE_MAX_RETRIES {
@@ -145,7 +277,9 @@ impl Replicator {
             .as_mut()
             .unwrap()
             .apply_frames(Frames::Snapshot(snap))
-            .await?;
+            .await
+            .map_err(ReplicationError::Apply)?;
+        self.stats.inc_snapshots_loaded();
 
         Ok(())
     }
@@ -156,7 +290,8 @@ impl Replicator {
             .as_mut()
             .expect("invalid state")
             .apply_frames(Frames::Vec(frames))
-            .await?;
+            .await
+            .map_err(ReplicationError::Apply)?;
 
         self.update_current_frame_no(new_frame_no);
 
@@ -167,3 +302,115 @@ impl Replicator {
         (self.current_frame_no != FrameNo::MAX).then_some(self.current_frame_no)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::rpc::replication_log::rpc::HelloResponse;
+
+    fn replicator(db_path: &std::path::Path, policy: ApplyErrorPolicy, stats: Stats) -> Replicator {
+        let channel = Channel::from_static("http://127.0.0.1:5001").connect_lazy();
+        let uri = tonic::transport::Uri::from_static("http://127.0.0.1:5001");
+        Replicator::new(
+            db_path.to_path_buf(),
+            channel,
+            uri,
+            Duration::ZERO,
+            policy,
+            stats,
+        )
+    }
+
+    #[tokio::test]
+    async fn primary_unavailable_within_reconnect_grace() {
+        // a primary that is down: nothing listens on the port anymore
+        let addr = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let uri: tonic::transport::Uri = format!("http://{addr}").parse().unwrap();
+        let channel = Channel::builder(uri.clone()).connect_lazy();
+
+        let tmp = tempfile::tempdir().unwrap();
+        let stats = Stats::new(tmp.path()).unwrap();
+        let mut replicator = Replicator::new(
+            tmp.path().to_path_buf(),
+            channel,
+            uri,
+            Duration::from_secs(60),
+            ApplyErrorPolicy::Reset,
+            stats,
+        );
+        let hello = HelloResponse {
+            generation_id: uuid::Uuid::new_v4().to_string(),
+            generation_start_index: 0,
+            database_id: uuid::Uuid::new_v4().to_string(),
+        };
+        let (injector, frame_no) = FrameInjectorHandle::new(tmp.path().to_path_buf(), hello)
+            .await
+            .unwrap();
+        replicator.update_current_frame_no(frame_no);
+        replicator.injector = Some(injector);
+
+        // within the grace period, the replica state is kept, and reads are served from it
+        let mut failing_since = None;
+        for _ in 0..3 {
+            let e = replicator.replicate().await.unwrap_err();
+            replicator
+                .on_replication_error(&e, &mut failing_since)
+                .await;
+            assert!(replicator.injector.is_some());
+            assert_eq!(replicator.current_frame_no, frame_no);
+        }
+
+        // once it has elapsed, the injector is shut down, and a new handshake is needed
+        replicator.reconnect_grace = Duration::ZERO;
+        let e = replicator.replicate().await.unwrap_err();
+        replicator
+            .on_replication_error(&e, &mut failing_since)
+            .await;
+        assert!(replicator.injector.is_none());
+        assert!(failing_since.is_none());
+    }
+
+    #[tokio::test]
+    async fn apply_error_policies() {
+        let injected = || ReplicationError::Apply(anyhow::anyhow!("injected error"));
+
+        let tmp = tempfile::tempdir().unwrap();
+        let stats = Stats::new(tmp.path()).unwrap();
+        let mut reset = replicator(tmp.path(), ApplyErrorPolicy::Reset, stats.clone());
+        assert_eq!(
+            reset.on_apply_error(&injected(), 1).await,
+            ApplyErrorAction::Reset
+        );
+        assert!(!stats.replication_halted());
+
+        let tmp = tempfile::tempdir().unwrap();
+        let stats = Stats::new(tmp.path()).unwrap();
+        let mut halt = replicator(tmp.path(), ApplyErrorPolicy::Halt, stats.clone());
+        assert_eq!(
+            halt.on_apply_error(&injected(), 1).await,
+            ApplyErrorAction::Halt
+        );
+        assert!(stats.replication_halted());
+
+        let tmp = tempfile::tempdir().unwrap();
+        let stats = Stats::new(tmp.path()).unwrap();
+        let mut retry = replicator(tmp.path(), ApplyErrorPolicy::Retry, stats.clone());
+        for failures in 1..=APPLY_MAX_RETRIES {
+            assert_eq!(
+                retry.on_apply_error(&injected(), failures).await,
+                ApplyErrorAction::Retry
+            );
+            assert!(!stats.replication_halted());
+        }
+        assert_eq!(
+            retry
+                .on_apply_error(&injected(), APPLY_MAX_RETRIES + 1)
+                .await,
+            ApplyErrorAction::Halt
+        );
+        assert!(stats.replication_halted());
+    }
+}
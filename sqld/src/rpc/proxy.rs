@@ -50,7 +50,9 @@ pub mod rpc {
     impl From<SqldError> for ErrorCode {
         fn from(other: SqldError) -> Self {
             match other {
-                SqldError::LibSqlInvalidQueryParams(_) => ErrorCode::SqlError,
+                SqldError::LibSqlInvalidQueryParams(_)
+                | SqldError::TooManyRows(_)
+                | SqldError::DiskFull => ErrorCode::SqlError,
                 SqldError::LibSqlTxTimeout(_) => ErrorCode::TxTimeout,
                 SqldError::LibSqlTxBusy => ErrorCode::TxBusy,
                 _ => ErrorCode::Internal,
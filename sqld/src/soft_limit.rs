@@ -0,0 +1,60 @@
+use crate::stats::{Limit, Stats};
+
+/// Soft threshold on a hard limit: while usage is above `threshold_percent` of the limit, a
+/// warning is logged and the limit is reported as near in the stats and health check, so that
+/// alerts can fire before requests start being rejected. Nothing is rejected by a soft limit.
+#[derive(Clone)]
+pub struct SoftLimit {
+    limit: Limit,
+    threshold_percent: u8,
+    stats: Stats,
+}
+
+impl SoftLimit {
+    pub fn new(limit: Limit, threshold_percent: u8, stats: Stats) -> Self {
+        Self {
+            limit,
+            threshold_percent,
+            stats,
+        }
+    }
+
+    /// records that `used` out of `hard_limit` is currently in use
+    pub fn update(&self, used: u64, hard_limit: u64) {
+        let near = used * 100 >= hard_limit * self.threshold_percent as u64;
+        let was_near = self.stats.set_near_limit(self.limit, near);
+        match (was_near, near) {
+            (false, true) => tracing::warn!(
+                "{} limit nearly reached: {used} out of {hard_limit} in use, above the soft threshold of {}%",
+                self.limit.name(),
+                self.threshold_percent
+            ),
+            (true, false) => tracing::info!(
+                "{} usage back below the soft threshold: {used} out of {hard_limit} in use",
+                self.limit.name()
+            ),
+            _ => (),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn near_limit_follows_usage() {
+        let tmp = tempfile::tempdir().unwrap();
+        let stats = Stats::new(tmp.path()).unwrap();
+        let soft_limit = SoftLimit::new(Limit::Connections, 80, stats.clone());
+
+        soft_limit.update(7, 10);
+        assert!(stats.near_limits().is_empty());
+        soft_limit.update(8, 10);
+        assert_eq!(stats.near_limits(), vec![Limit::Connections]);
+        soft_limit.update(10, 10);
+        assert_eq!(stats.near_limits(), vec![Limit::Connections]);
+        soft_limit.update(3, 10);
+        assert!(stats.near_limits().is_empty());
+    }
+}
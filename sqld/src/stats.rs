@@ -1,9 +1,9 @@
 use std::fs::{File, OpenOptions};
 use std::io::Seek;
 use std::path::Path;
-use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicU8, Ordering};
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use serde::{Deserialize, Serialize};
 
@@ -17,6 +17,118 @@ struct StatsInner {
     rows_written: AtomicU64,
     rows_read: AtomicU64,
     storage_bytes_used: AtomicU64,
+    /// milliseconds since the unix epoch, 0 if nothing was ever written
+    #[serde(default)]
+    last_write_time_ms: AtomicU64,
+    /// outcome of the last backup check since the server started: 0 if none ran yet, 1 if it
+    /// passed, 2 if it failed
+    #[serde(skip)]
+    backup_check_status: AtomicU8,
+    #[serde(skip)]
+    backup_check_duration_ms: AtomicU64,
+    /// set when a replica stopped replicating and needs manual intervention
+    #[serde(skip)]
+    replication_halted: AtomicBool,
+    /// set when the last frame gap check found frames missing from the backup
+    #[serde(skip)]
+    backup_frame_gaps: AtomicBool,
+    /// bitmask of the limits whose usage is above their soft threshold
+    #[serde(skip)]
+    near_limits: AtomicU8,
+    #[serde(skip)]
+    query_latencies: LatencyHistogram,
+    #[serde(skip)]
+    counters: Counters,
+}
+
+/// Monotonic counters, kept in memory since the server started or they were last reset.
+#[derive(Default)]
+struct Counters {
+    write_queries: AtomicU64,
+    commits: AtomicU64,
+    snapshots_loaded: AtomicU64,
+}
+
+/// Values of the cumulative counters at a point in time.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct CountersSnapshot {
+    /// statements that could modify the database
+    pub write_queries: u64,
+    /// committed transactions that wrote to the database
+    pub commits: u64,
+    /// snapshots of the primary loaded by a replica
+    pub snapshots_loaded: u64,
+}
+
+/// Hard limits that can be given a soft threshold, see `SoftLimit`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Limit {
+    /// concurrently open connections
+    Connections,
+}
+
+impl Limit {
+    const ALL: [Limit; 1] = [Limit::Connections];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Limit::Connections => "connections",
+        }
+    }
+
+    fn mask(self) -> u8 {
+        1 << self as u8
+    }
+}
+
+/// Number of buckets in the latency histogram: bucket `i` counts durations shorter than 2^i
+/// microseconds, the last one counts everything above.
+const LATENCY_BUCKETS: usize = 32;
+
+/// Bounded-memory histogram of query durations, with power-of-two buckets.
+#[derive(Default)]
+struct LatencyHistogram {
+    buckets: [AtomicU64; LATENCY_BUCKETS],
+}
+
+impl LatencyHistogram {
+    fn record(&self, duration: Duration) {
+        let micros = duration.as_micros().min(u64::MAX as u128) as u64;
+        // index of the smallest power of two strictly greater than `micros`
+        let bucket = (u64::BITS - micros.leading_zeros()) as usize;
+        self.buckets[bucket.min(LATENCY_BUCKETS - 1)].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Returns the upper bound of the bucket containing the `p`th percentile (0.0..=1.0) of the
+    /// recorded durations, or `None` if nothing was recorded.
+    fn percentile(&self, p: f64) -> Option<Duration> {
+        let counts: Vec<u64> = self
+            .buckets
+            .iter()
+            .map(|b| b.load(Ordering::Relaxed))
+            .collect();
+        let total: u64 = counts.iter().sum();
+        if total == 0 {
+            return None;
+        }
+
+        let rank = ((total as f64 * p).ceil() as u64).max(1);
+        let mut seen = 0;
+        for (i, count) in counts.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                return Some(Duration::from_micros(1 << i));
+            }
+        }
+
+        None
+    }
+
+    fn reset(&self) {
+        for bucket in self.buckets.iter() {
+            bucket.store(0, Ordering::Relaxed);
+        }
+    }
 }
 
 impl Stats {
@@ -65,6 +177,143 @@ impl Stats {
     pub fn storage_bytes_used(&self) -> u64 {
         self.inner.storage_bytes_used.load(Ordering::Relaxed)
     }
+
+    /// records `time` as the time of the most recent committed write
+    pub fn set_last_write_time(&self, time: SystemTime) {
+        let millis = time
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis() as u64)
+            .unwrap_or_default();
+        self.inner
+            .last_write_time_ms
+            .fetch_max(millis, Ordering::Relaxed);
+    }
+
+    /// returns the time of the most recent committed write, or `None` if nothing was written
+    /// since this database was created
+    pub fn last_write_time(&self) -> Option<SystemTime> {
+        match self.inner.last_write_time_ms.load(Ordering::Relaxed) {
+            0 => None,
+            millis => Some(UNIX_EPOCH + Duration::from_millis(millis)),
+        }
+    }
+
+    /// records the outcome of a check that the backup can be restored
+    pub fn record_backup_check(&self, passed: bool, duration: Duration) {
+        self.inner
+            .backup_check_duration_ms
+            .store(duration.as_millis() as u64, Ordering::Relaxed);
+        self.inner
+            .backup_check_status
+            .store(if passed { 1 } else { 2 }, Ordering::Relaxed);
+    }
+
+    /// returns whether the last backup check passed and how long it took, or `None` if no check
+    /// ran since the server started
+    pub fn last_backup_check(&self) -> Option<(bool, Duration)> {
+        let passed = match self.inner.backup_check_status.load(Ordering::Relaxed) {
+            0 => return None,
+            status => status == 1,
+        };
+        let duration =
+            Duration::from_millis(self.inner.backup_check_duration_ms.load(Ordering::Relaxed));
+        Some((passed, duration))
+    }
+
+    pub fn set_replication_halted(&self, halted: bool) {
+        self.inner
+            .replication_halted
+            .store(halted, Ordering::Relaxed);
+    }
+
+    /// returns whether this replica stopped applying frames from the primary
+    pub fn replication_halted(&self) -> bool {
+        self.inner.replication_halted.load(Ordering::Relaxed)
+    }
+
+    pub fn set_backup_frame_gaps(&self, found: bool) {
+        self.inner.backup_frame_gaps.store(found, Ordering::Relaxed);
+    }
+
+    /// returns whether frames were found missing from the backup by the last frame gap check
+    pub fn backup_frame_gaps(&self) -> bool {
+        self.inner.backup_frame_gaps.load(Ordering::Relaxed)
+    }
+
+    /// flags whether the usage of `limit` is above its soft threshold, returns the previous flag
+    pub fn set_near_limit(&self, limit: Limit, near: bool) -> bool {
+        let previous = if near {
+            self.inner
+                .near_limits
+                .fetch_or(limit.mask(), Ordering::Relaxed)
+        } else {
+            self.inner
+                .near_limits
+                .fetch_and(!limit.mask(), Ordering::Relaxed)
+        };
+        previous & limit.mask() != 0
+    }
+
+    /// returns the limits whose usage is above their soft threshold
+    pub fn near_limits(&self) -> Vec<Limit> {
+        let near_limits = self.inner.near_limits.load(Ordering::Relaxed);
+        Limit::ALL
+            .into_iter()
+            .filter(|limit| near_limits & limit.mask() != 0)
+            .collect()
+    }
+
+    pub fn inc_write_queries(&self) {
+        self.inner
+            .counters
+            .write_queries
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn inc_commits(&self) {
+        self.inner.counters.commits.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn inc_snapshots_loaded(&self) {
+        self.inner
+            .counters
+            .snapshots_loaded
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// returns the current values of the cumulative counters
+    pub fn snapshot_counters(&self) -> CountersSnapshot {
+        let counters = &self.inner.counters;
+        CountersSnapshot {
+            write_queries: counters.write_queries.load(Ordering::Relaxed),
+            commits: counters.commits.load(Ordering::Relaxed),
+            snapshots_loaded: counters.snapshots_loaded.load(Ordering::Relaxed),
+        }
+    }
+
+    /// sets all the cumulative counters back to zero, e.g. before a benchmark
+    pub fn reset_counters(&self) {
+        let counters = &self.inner.counters;
+        counters.write_queries.store(0, Ordering::Relaxed);
+        counters.commits.store(0, Ordering::Relaxed);
+        counters.snapshots_loaded.store(0, Ordering::Relaxed);
+    }
+
+    /// records the time it took to execute a query
+    pub fn record_query_latency(&self, duration: Duration) {
+        self.inner.query_latencies.record(duration);
+    }
+
+    /// returns an upper bound of the `p`th percentile (0.0..=1.0) of query latencies since the
+    /// server started or the latencies were last reset, or `None` if no query ran since then.
+    pub fn query_latency_percentile(&self, p: f64) -> Option<Duration> {
+        self.inner.query_latencies.percentile(p)
+    }
+
+    /// forgets all the recorded query latencies
+    pub fn reset_query_latencies(&self) {
+        self.inner.query_latencies.reset();
+    }
 }
 
 fn spawn_stats_persist_thread(stats: Arc<StatsInner>, mut file: File) {
@@ -75,3 +324,76 @@ fn spawn_stats_persist_thread(stats: Arc<StatsInner>, mut file: File) {
         std::thread::sleep(Duration::from_secs(5));
     });
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn latency_percentiles() {
+        let histogram = LatencyHistogram::default();
+        assert_eq!(histogram.percentile(0.5), None);
+
+        // 90 fast queries, 9 slower ones and a single very slow one
+        for _ in 0..90 {
+            histogram.record(Duration::from_micros(100));
+        }
+        for _ in 0..9 {
+            histogram.record(Duration::from_millis(10));
+        }
+        histogram.record(Duration::from_secs(1));
+
+        let p50 = histogram.percentile(0.5).unwrap();
+        assert!(p50 > Duration::from_micros(100) && p50 <= Duration::from_micros(200));
+        let p95 = histogram.percentile(0.95).unwrap();
+        assert!(p95 > Duration::from_millis(10) && p95 <= Duration::from_millis(20));
+        let p99 = histogram.percentile(0.99).unwrap();
+        assert!(p99 > Duration::from_millis(10) && p99 <= Duration::from_millis(20));
+        let max = histogram.percentile(1.0).unwrap();
+        assert!(max > Duration::from_secs(1) && max <= Duration::from_secs(2));
+
+        histogram.reset();
+        assert_eq!(histogram.percentile(0.5), None);
+    }
+
+    #[test]
+    fn last_write_time_is_persisted() {
+        let tmp = tempfile::tempdir().unwrap();
+        let stats = Stats::new(tmp.path()).unwrap();
+        assert_eq!(stats.last_write_time(), None);
+
+        let time = UNIX_EPOCH + Duration::from_millis(1_690_000_000_000);
+        stats.set_last_write_time(time);
+        assert_eq!(stats.last_write_time(), Some(time));
+        // a stale notification doesn't move the time backward
+        stats.set_last_write_time(time - Duration::from_secs(1));
+        assert_eq!(stats.last_write_time(), Some(time));
+
+        let serialized = serde_json::to_string(&*stats.inner).unwrap();
+        let inner: StatsInner = serde_json::from_str(&serialized).unwrap();
+        assert_eq!(
+            inner.last_write_time_ms.load(Ordering::Relaxed),
+            1_690_000_000_000
+        );
+
+        // stats persisted before the last write time was recorded are still loaded
+        let inner: StatsInner =
+            serde_json::from_str(r#"{"rows_written":1,"rows_read":2,"storage_bytes_used":3}"#)
+                .unwrap();
+        assert_eq!(inner.rows_read.load(Ordering::Relaxed), 2);
+        assert_eq!(inner.last_write_time_ms.load(Ordering::Relaxed), 0);
+    }
+
+    #[test]
+    fn latency_outliers_are_bounded() {
+        let histogram = LatencyHistogram::default();
+        histogram.record(Duration::ZERO);
+        histogram.record(Duration::MAX);
+
+        assert_eq!(histogram.percentile(0.5), Some(Duration::from_micros(1)));
+        assert_eq!(
+            histogram.percentile(1.0),
+            Some(Duration::from_micros(1 << (LATENCY_BUCKETS - 1)))
+        );
+    }
+}